@@ -4,40 +4,21 @@ use bencher::Bencher;
 
 use std::fs::File;
 use std::io::Cursor;
-use std::io::{Read, ErrorKind};
+use std::io::Read;
 
 
 
-use varnishslog::stream_buf::{ReadStreamBuf, StreamBuf, FillApplyError, FillError};
 use varnishslog::vsl::record::VslRecord;
-use varnishslog::vsl::record::parser::{binary_vsl_tag, vsl_record_v4};
+use varnishslog::vsl::reader::VslReader;
 use varnishslog::access_log::session_state::SessionState;
 use varnishslog::access_log::record_state::RecordState;
 use varnishslog::serialization::{log_client_record, Format, Config};
 
-fn parse_each_vsl_record<R: Read, C>(mut rfb: ReadStreamBuf<R>, mut block: C) where C: FnMut(&VslRecord<'_>) {
-    rfb.fill_apply(binary_vsl_tag).unwrap();
+fn parse_each_vsl_record<R: Read, C>(input: R, mut block: C) where C: FnMut(&VslRecord<'_>) {
+    let mut reader = VslReader::new(input);
 
-    loop {
-        match rfb.fill_apply(binary_vsl_tag) {
-            Err(err) => panic!("VSL tag error: {}", err),
-            Ok(None) => continue,
-            Ok(Some(_)) => break,
-        }
-    }
-
-    loop {
-        match rfb.fill_apply(vsl_record_v4) {
-            Err(FillApplyError::FillError(FillError::Io(err))) => {
-                if err.kind() == ErrorKind::UnexpectedEof {
-                    break
-                }
-                panic!("I/O error: {}", err);
-            },
-            Err(err) => panic!("error: {}", err),
-            Ok(None) => continue,
-            Ok(Some(record)) => block(&record),
-        }
+    while let Some(record) = reader.read_record().expect("VSL reader error") {
+        block(&record);
     }
 }
 
@@ -57,7 +38,7 @@ fn record_state(bench: &mut Bencher) {
     bench.iter(|| {
         let mut rs = RecordState::new();
         {
-            parse_each_vsl_record(ReadStreamBuf::new(&mut cursor), |vsl_record| {
+            parse_each_vsl_record(&mut cursor, |vsl_record| {
                 bencher::black_box(rs.apply(vsl_record));
             });
         }
@@ -70,7 +51,7 @@ fn session_state(bench: &mut Bencher) {
     bench.iter(|| {
         let mut ss = SessionState::new();
         {
-            parse_each_vsl_record(ReadStreamBuf::new(&mut cursor), |vsl_record| {
+            parse_each_vsl_record(&mut cursor, |vsl_record| {
                 bencher::black_box(ss.apply(vsl_record));
             });
         }
@@ -85,6 +66,11 @@ fn log_session_record_ncsa_json(bench: &mut Bencher) {
         keep_raw_log: false,
         no_header_indexing: false,
         keep_raw_headers: false,
+        partial_records: false,
+        flush_on_eof: false,
+        stats_records: false,
+        stats_log_time: false,
+        metrics: None,
     };
     let format = Format::NcsaJson;
 
@@ -92,7 +78,7 @@ fn log_session_record_ncsa_json(bench: &mut Bencher) {
         let mut out = Cursor::new(Vec::new());
         let mut ss = SessionState::new();
         {
-            parse_each_vsl_record(ReadStreamBuf::new(&mut cursor), |vsl_record| {
+            parse_each_vsl_record(&mut cursor, |vsl_record| {
                 if let Some(session) = ss.apply(vsl_record) {
                     log_client_record(&session, &format, &mut out, &config).unwrap()
                 }
@@ -110,6 +96,11 @@ fn log_session_record_json(bench: &mut Bencher) {
         keep_raw_log: false,
         no_header_indexing: false,
         keep_raw_headers: false,
+        partial_records: false,
+        flush_on_eof: false,
+        stats_records: false,
+        stats_log_time: false,
+        metrics: None,
     };
     let format = Format::NcsaJson;
 
@@ -117,7 +108,7 @@ fn log_session_record_json(bench: &mut Bencher) {
         let mut out = Cursor::new(Vec::new());
         let mut ss = SessionState::new();
         {
-            parse_each_vsl_record(ReadStreamBuf::new(&mut cursor), |vsl_record| {
+            parse_each_vsl_record(&mut cursor, |vsl_record| {
                 if let Some(session) = ss.apply(vsl_record) {
                     log_client_record(&session, &format, &mut out, &config).unwrap()
                 }
@@ -134,6 +125,11 @@ fn log_session_record_json_raw(bench: &mut Bencher) {
         keep_raw_log: true,
         no_header_indexing: true,
         keep_raw_headers: true,
+        partial_records: false,
+        flush_on_eof: false,
+        stats_records: false,
+        stats_log_time: false,
+        metrics: None,
     };
     let format = Format::NcsaJson;
 
@@ -141,7 +137,7 @@ fn log_session_record_json_raw(bench: &mut Bencher) {
         let mut out = Cursor::new(Vec::new());
         let mut ss = SessionState::new();
         {
-            parse_each_vsl_record(ReadStreamBuf::new(&mut cursor), |vsl_record| {
+            parse_each_vsl_record(&mut cursor, |vsl_record| {
                 if let Some(session) = ss.apply(vsl_record) {
                     log_client_record(&session, &format, &mut out, &config).unwrap()
                 }
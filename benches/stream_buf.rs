@@ -9,10 +9,10 @@ use std::io::{Read, ErrorKind};
 
 
 
-use varnishslog::stream_buf::{ReadStreamBuf, StreamBuf, FillApplyError, FillError};
+use varnishslog::stream_buf::{ReadStreamBuf, MmapStreamBuf, StreamBuf, FillApplyError, FillError};
 use varnishslog::vsl::record::parser::{binary_vsl_tag, vsl_record_v4};
 
-fn parse_vsl<R: Read>(mut rfb: ReadStreamBuf<R>) {
+fn parse_vsl<S: StreamBuf<u8>>(mut rfb: S) {
     rfb.fill_apply(binary_vsl_tag).unwrap();
 
     loop {
@@ -98,10 +98,17 @@ fn default_buffer_no_prefetch(bench: &mut Bencher) {
     })
 }
 
+fn mmap_file(bench: &mut Bencher) {
+    bench.iter(|| {
+        parse_vsl(MmapStreamBuf::from_file(&open_data()).unwrap());
+    })
+}
+
 benchmark_group!(benches,
                  default_buffer,
                  default_buffer_from_file,
                  custom_buffer_from_file_303b,
                  custom_buffer_from_file_1mib,
-                 default_buffer_no_prefetch);
+                 default_buffer_no_prefetch,
+                 mmap_file);
 benchmark_main!(benches);
@@ -1,6 +1,6 @@
 #[cfg(test)]
 #[macro_use]
-mod test_helpers {
+pub(crate) mod test_helpers {
     //TODO move to lib.rs?
     use crate::vsl::record::{VslRecord, VslRecordTag, VslIdent};
     use std::sync::{Once};
@@ -16,6 +16,7 @@ mod test_helpers {
         pub fn from_str(tag: VslRecordTag, ident: VslIdent, message: &str) -> VslRecord<'_> {
             VslRecord {
                 tag: tag,
+                raw_tag: tag.number(),
                 marker: Marker::VSL_CLIENTMARKER,
                 ident: ident,
                 data: message.as_ref()
@@ -73,5 +74,6 @@ mod test_helpers {
 }
 
 pub mod record;
+pub mod reader;
 pub mod session_state;
 pub mod record_state;
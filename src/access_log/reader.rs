@@ -0,0 +1,118 @@
+// Reads VSL stream and yields fully linked access records.
+//
+// This combines VslReader with SessionState so that embedding applications can get
+// ClientAccessRecords (and optionally SessionRecords) out of any Read.
+// Store configuration including epoch source is passed via StoreConfig.
+use std::io::Read;
+
+use crate::store::Config as StoreConfig;
+use crate::stream_buf::{StreamBuf, ReadStreamBuf};
+use crate::vsl::reader::{VslReader, VslReaderError};
+use super::session_state::SessionState;
+use super::record::AccessRecord;
+
+pub struct AccessLogReader<S: StreamBuf<u8>> {
+    reader: VslReader<S>,
+    state: SessionState,
+    emit_sessions: bool,
+}
+
+impl<R: Read> AccessLogReader<ReadStreamBuf<R>> {
+    pub fn new(input: R) -> AccessLogReader<ReadStreamBuf<R>> {
+        AccessLogReader::with_config(input, &Default::default())
+    }
+
+    pub fn with_config(input: R, store_config: &StoreConfig) -> AccessLogReader<ReadStreamBuf<R>> {
+        AccessLogReader::from_vsl_reader(VslReader::new(input), store_config)
+    }
+}
+
+impl<S: StreamBuf<u8>> AccessLogReader<S> {
+    /// Use preconfigured VslReader, e.g. with custom buffer capacity or memory mapped file
+    pub fn from_vsl_reader(reader: VslReader<S>, store_config: &StoreConfig) -> AccessLogReader<S> {
+        AccessLogReader {
+            reader,
+            state: SessionState::with_config(store_config),
+            emit_sessions: false,
+        }
+    }
+
+    /// Yield `AccessRecord::Session` records in addition to client access records
+    pub fn emit_sessions(&mut self) {
+        self.emit_sessions = true;
+    }
+
+    /// State holding records that are still being built or linked; use it after end of
+    /// stream to inspect unresolved records
+    pub fn session_state(&self) -> &SessionState {
+        &self.state
+    }
+
+    pub fn into_session_state(self) -> SessionState {
+        self.state
+    }
+
+    /// Reads VSL records until next access record is complete; returns `None` at the end of the stream
+    pub fn read_access_record(&mut self) -> Result<Option<AccessRecord>, VslReaderError> {
+        while let Some(vsl) = self.reader.read_record()? {
+            match self.state.apply_access_record(&vsl) {
+                Some(record @ AccessRecord::ClientAccess(_)) => return Ok(Some(record)),
+                Some(record @ AccessRecord::Session(_)) => if self.emit_sessions {
+                    return Ok(Some(record))
+                },
+                Some(AccessRecord::BackendAccess(_)) | None => (),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<S: StreamBuf<u8>> Iterator for AccessLogReader<S> {
+    type Item = Result<AccessRecord, VslReaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_access_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn subject() -> AccessLogReader<ReadStreamBuf<File>> {
+        AccessLogReader::new(File::open("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap())
+    }
+
+    #[test]
+    fn read_client_access_records() {
+        let records = subject().collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(records.len(), 8);
+        assert!(records.iter().all(|record| record.is_client_access()));
+    }
+
+    #[test]
+    fn read_session_records() {
+        let mut reader = subject();
+        reader.emit_sessions();
+
+        let records = reader.collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(records.iter().filter(|record| record.is_client_access()).count(), 8);
+        assert!(records.iter().any(|record| record.is_session()));
+    }
+
+    #[test]
+    fn unresolved_records_at_end_of_stream() {
+        let mut reader = subject();
+        for record in &mut reader {
+            record.unwrap();
+        }
+
+        let state = reader.session_state();
+        assert!(state.unresolved_root_client_access_records().is_empty());
+        assert!(state.unresolved_client_access_records().is_empty());
+        assert!(state.unresolved_backend_access_records().is_empty());
+    }
+}
@@ -1,6 +1,8 @@
 use crate::vsl::record::{
     VslIdent,
 };
+use crate::store::MemSize;
+use serde::{Serialize, Deserialize};
 pub use crate::vsl::record::message::{
     TimeStamp,
     Duration,
@@ -16,7 +18,7 @@ pub use crate::vsl::record::message::{
 
 pub type Address = (String, Port);
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LogEntry {
     /// VCL std.log logged messages
     Vcl(String),
@@ -34,7 +36,7 @@ pub enum LogEntry {
     Acl(AclResult, String, Option<String>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Accounting {
     pub recv_header: ByteCount,
     pub recv_body: ByteCount,
@@ -44,13 +46,13 @@ pub struct Accounting {
     pub sent_total: ByteCount,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PipeAccounting {
     pub recv_total: ByteCount,
     pub sent_total: ByteCount,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Handling {
     /// Cache hit and served from cache
     Hit(VslIdent),
@@ -68,27 +70,27 @@ pub enum Handling {
     Pipe,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Compression {
     pub operation: CompressionOperation,
     pub bytes_in: ByteCount,
     pub bytes_out: ByteCount,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Link<T> {
     Unresolved(VslIdent, String),
     Resolved(Box<T>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Proxy {
     pub version: String,
     pub client: Address,
     pub server: Address,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SessionInfo {
     pub ident: VslIdent,
     pub open: TimeStamp,
@@ -98,7 +100,7 @@ pub struct SessionInfo {
 }
 
 /// All Duration fields are in seconds (floating point values rounded to micro second precision)
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ClientAccessRecord {
     pub root: bool,
     pub session: Option<SessionInfo>,
@@ -116,7 +118,7 @@ pub struct ClientAccessRecord {
     pub log: Vec<LogEntry>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ClientAccessTransaction {
     Full {
         request: HttpRequest,
@@ -167,7 +169,7 @@ pub enum ClientAccessTransaction {
     },
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CacheObject {
     /// Type ("malloc", "file", "persistent" etc.)
     pub storage_type: String,
@@ -189,7 +191,7 @@ pub struct CacheObject {
     pub response: Option<HttpResponse>
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BackendConnection {
     pub fd: FileDescriptor,
     pub name: String,
@@ -198,7 +200,7 @@ pub struct BackendConnection {
 }
 
 /// All Duration fields are in seconds (floating point values rounded to micro second precision)
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BackendAccessRecord {
     pub ident: VslIdent,
     pub parent: VslIdent,
@@ -214,7 +216,7 @@ pub struct BackendAccessRecord {
     pub lru_nuked: u32,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BackendAccessTransaction {
     Full {
         request: HttpRequest,
@@ -271,7 +273,7 @@ pub enum BackendAccessTransaction {
 }
 
 /// Complete sessoin
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SessionRecord {
     pub ident: VslIdent,
     pub open: TimeStamp,
@@ -283,7 +285,7 @@ pub struct SessionRecord {
     pub close_reason: String,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HttpRequest {
     pub protocol: String,
     pub method: String,
@@ -291,7 +293,7 @@ pub struct HttpRequest {
     pub headers: Vec<(String, String)>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HttpResponse {
     pub status: Status,
     pub reason: String,
@@ -300,7 +302,7 @@ pub struct HttpResponse {
 }
 
 /// Access records to be fully connected and logged
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AccessRecord {
     ClientAccess(ClientAccessRecord),
     BackendAccess(BackendAccessRecord),
@@ -387,3 +389,174 @@ impl AccessRecord {
         }
     }
 }
+
+impl ClientAccessRecord {
+    /// Vxids of linked transactions that were not resolved, including links of the linked records
+    pub fn unresolved_links(&self) -> Vec<VslIdent> {
+        let mut links = Vec::new();
+        self.collect_unresolved_links(&mut links);
+        links
+    }
+}
+
+trait UnresolvedLinks {
+    fn collect_unresolved_links(&self, links: &mut Vec<VslIdent>);
+}
+
+impl<T: UnresolvedLinks> UnresolvedLinks for Link<T> {
+    fn collect_unresolved_links(&self, links: &mut Vec<VslIdent>) {
+        match *self {
+            Link::Unresolved(ident, _) => links.push(ident),
+            Link::Resolved(ref record) => record.collect_unresolved_links(links),
+        }
+    }
+}
+
+impl<T: UnresolvedLinks> UnresolvedLinks for Option<T> {
+    fn collect_unresolved_links(&self, links: &mut Vec<VslIdent>) {
+        if let Some(ref link) = *self {
+            link.collect_unresolved_links(links)
+        }
+    }
+}
+
+impl UnresolvedLinks for ClientAccessRecord {
+    fn collect_unresolved_links(&self, links: &mut Vec<VslIdent>) {
+        match self.transaction {
+            ClientAccessTransaction::Full { ref esi_records, ref backend_record, .. } => {
+                for esi_record in esi_records {
+                    esi_record.collect_unresolved_links(links);
+                }
+                backend_record.collect_unresolved_links(links);
+            }
+            ClientAccessTransaction::RestartedEarly { ref restart_record, .. } =>
+                restart_record.collect_unresolved_links(links),
+            ClientAccessTransaction::RestartedLate { ref backend_record, ref restart_record, .. } => {
+                backend_record.collect_unresolved_links(links);
+                restart_record.collect_unresolved_links(links);
+            }
+            ClientAccessTransaction::Bad { .. } => (),
+            ClientAccessTransaction::Piped { ref backend_record, .. } =>
+                backend_record.collect_unresolved_links(links),
+        }
+    }
+}
+
+impl UnresolvedLinks for BackendAccessRecord {
+    fn collect_unresolved_links(&self, links: &mut Vec<VslIdent>) {
+        match self.transaction {
+            BackendAccessTransaction::Failed { ref retry_record, .. } |
+            BackendAccessTransaction::Abandoned { ref retry_record, .. } =>
+                retry_record.collect_unresolved_links(links),
+            BackendAccessTransaction::Full { .. } |
+            BackendAccessTransaction::Aborted { .. } |
+            BackendAccessTransaction::Piped { .. } => (),
+        }
+    }
+}
+
+impl MemSize for LogEntry {
+    fn mem_size(&self) -> usize {
+        match *self {
+            LogEntry::Vcl(ref message) |
+            LogEntry::VclError(ref message) |
+            LogEntry::Debug(ref message) |
+            LogEntry::Error(ref message) |
+            LogEntry::FetchError(ref message) |
+            LogEntry::Warning(ref message) => message.mem_size(),
+            LogEntry::Acl(_, ref name, ref value) => name.mem_size() + value.mem_size(),
+        }
+    }
+}
+
+impl<T: MemSize> MemSize for Link<T> {
+    fn mem_size(&self) -> usize {
+        match *self {
+            Link::Unresolved(_, ref reason) => reason.mem_size(),
+            Link::Resolved(ref record) => record.mem_size(),
+        }
+    }
+}
+
+impl MemSize for Proxy {
+    fn mem_size(&self) -> usize {
+        self.version.mem_size() + self.client.mem_size() + self.server.mem_size()
+    }
+}
+
+impl MemSize for SessionInfo {
+    fn mem_size(&self) -> usize {
+        self.local.mem_size() + self.remote.mem_size() + self.proxy.mem_size()
+    }
+}
+
+impl MemSize for HttpRequest {
+    fn mem_size(&self) -> usize {
+        self.protocol.mem_size() + self.method.mem_size() + self.url.mem_size() + self.headers.mem_size()
+    }
+}
+
+impl MemSize for HttpResponse {
+    fn mem_size(&self) -> usize {
+        self.reason.mem_size() + self.protocol.mem_size() + self.headers.mem_size()
+    }
+}
+
+impl MemSize for ClientAccessRecord {
+    fn mem_size(&self) -> usize {
+        self.session.mem_size() + self.reason.mem_size() + self.remote.mem_size() +
+            self.transaction.mem_size() + self.log.mem_size()
+    }
+}
+
+impl MemSize for ClientAccessTransaction {
+    fn mem_size(&self) -> usize {
+        match *self {
+            ClientAccessTransaction::Full { ref request, ref response, ref esi_records, ref backend_record, .. } =>
+                request.mem_size() + response.mem_size() + esi_records.mem_size() + backend_record.mem_size(),
+            ClientAccessTransaction::RestartedEarly { ref request, ref restart_record, .. } =>
+                request.mem_size() + restart_record.mem_size(),
+            ClientAccessTransaction::RestartedLate { ref request, ref response, ref backend_record, ref restart_record, .. } =>
+                request.mem_size() + response.mem_size() + backend_record.mem_size() + restart_record.mem_size(),
+            ClientAccessTransaction::Bad { ref request, ref response, .. } =>
+                request.mem_size() + response.mem_size(),
+            ClientAccessTransaction::Piped { ref request, ref backend_record, .. } =>
+                request.mem_size() + backend_record.mem_size(),
+        }
+    }
+}
+
+impl MemSize for CacheObject {
+    fn mem_size(&self) -> usize {
+        self.storage_type.mem_size() + self.storage_name.mem_size() + self.fetch_mode.mem_size() + self.response.mem_size()
+    }
+}
+
+impl MemSize for BackendConnection {
+    fn mem_size(&self) -> usize {
+        self.name.mem_size() + self.remote.mem_size() + self.local.mem_size()
+    }
+}
+
+impl MemSize for BackendAccessRecord {
+    fn mem_size(&self) -> usize {
+        self.reason.mem_size() + self.transaction.mem_size() + self.log.mem_size()
+    }
+}
+
+impl MemSize for BackendAccessTransaction {
+    fn mem_size(&self) -> usize {
+        match *self {
+            BackendAccessTransaction::Full { ref request, ref response, ref backend_connection, ref cache_object, .. } =>
+                request.mem_size() + response.mem_size() + backend_connection.mem_size() + cache_object.mem_size(),
+            BackendAccessTransaction::Failed { ref request, ref synth_response, ref retry_record, .. } =>
+                request.mem_size() + synth_response.mem_size() + retry_record.mem_size(),
+            BackendAccessTransaction::Aborted { ref request } =>
+                request.mem_size(),
+            BackendAccessTransaction::Abandoned { ref request, ref response, ref backend_connection, ref retry_record, .. } =>
+                request.mem_size() + response.mem_size() + backend_connection.mem_size() + retry_record.mem_size(),
+            BackendAccessTransaction::Piped { ref request, ref backend_connection } =>
+                request.mem_size() + backend_connection.mem_size(),
+        }
+    }
+}
@@ -1,14 +1,20 @@
 pub mod record_builder;
 use log::{error, warn, debug};
 use self::record_builder::{RecordBuilder, RecordBuilderError, SessionHead, Record};
-use crate::store::VslStore;
+use crate::store::{VslStore, StoreEntry, MemSize, observe_log_time};
 use crate::store::Config as StoreConfig;
-use crate::vsl::record::VslRecord;
+use crate::store::Stats as StoreStats;
+use crate::metrics::Metrics;
+use crate::vsl::record::AsVslRecord;
+use crate::vsl::record::VslRecordTag::SLT_SessError;
+use crate::vsl::record::message::parser::slt_sess_error;
 use crate::access_log::record::AccessRecord;
 use std::num::Wrapping;
 use crate::vsl::record::VslIdent;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::cell::RefCell;
+use serde::{Serialize, Deserialize};
 
 #[derive(Debug)]
 enum Slot {
@@ -18,6 +24,30 @@ enum Slot {
 }
 use self::Slot::*;
 
+impl MemSize for Slot {
+    fn mem_size(&self) -> usize {
+        match *self {
+            // builders grow by size of VSL records applied to them
+            Builder(_) => 0,
+            Session(ref session) => std::mem::size_of::<RefCell<SessionHead>>() + session.borrow().mem_size(),
+            Tombstone(_) => 0,
+        }
+    }
+}
+
+// Tombstones are not kept in snapshots
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SlotSnapshot {
+    Builder(Box<RecordBuilder>),
+    Session(Box<SessionHead>),
+}
+
+/// Records being built taken out of RecordState
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordStateSnapshot {
+    builders: Vec<StoreEntry<SlotSnapshot>>,
+}
+
 #[derive(Debug)]
 enum SlotAction {
     New(RecordBuilder),
@@ -36,7 +66,8 @@ use self::SlotAction::*;
 //
 #[derive(Debug)]
 pub struct RecordState {
-    builders: VslStore<Slot>
+    builders: VslStore<Slot>,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl Default for RecordState {
@@ -67,6 +98,7 @@ impl RecordState {
     pub fn with_config(store_config: &StoreConfig) -> RecordState {
         RecordState {
             builders: VslStore::with_config("builders", Some(Self::on_expire), None, store_config),
+            metrics: store_config.metrics().cloned(),
         }
     }
 
@@ -77,9 +109,20 @@ impl RecordState {
         VslStore::log_expire(store_name, current_epoch, record_epoch, record_ident, record);
     }
 
-    pub fn apply(&mut self, vsl: &VslRecord<'_>) -> Option<AccessRecord> {
+    pub fn apply<V: AsVslRecord + ?Sized>(&mut self, vsl: &V) -> Option<AccessRecord> {
+        let vsl = &vsl.as_vsl_record();
+        observe_log_time(vsl);
+
         // Do not store 0 SLT_CLI Rd ping etc.
         if ! (vsl.is_client() || vsl.is_backend()) {
+            // Client connections that failed to be accepted have no session
+            if vsl.tag == SLT_SessError {
+                match vsl.parse_data(slt_sess_error) {
+                    Ok((socket, (addr, port), fd, errno, message)) =>
+                        warn!("Failed to accept client connection on {} ({}:{}) fd {}: errno {}: {}", socket, addr, port, fd, errno, message),
+                    Err(err) => warn!("Failed to accept client connection: {}", err),
+                }
+            }
             debug!("Skipping non-client/backend record: {}", vsl);
             return None
         }
@@ -118,6 +161,7 @@ impl RecordState {
                     builder.set_session(session.clone())
                 }
                 self.builders.insert(vsl.ident, Builder(builder));
+                self.builders.grow(&vsl.ident, vsl.data.len());
                 None
             }
             Finalize => {
@@ -161,11 +205,60 @@ impl RecordState {
                     _ =>
                         error!("Error while building record with ident {} while applying {}: {}", &vsl.ident, &vsl, &err)
                 }
+                if let Some(ref metrics) = self.metrics {
+                    metrics.tombstone(err.kind());
+                }
                 // catch all following records
                 self.builders.insert(vsl.ident, Tombstone(err));
                 return None
             }
-            Continue => return None
+            Continue => {
+                self.builders.grow(&vsl.ident, vsl.data.len());
+                return None
+            }
+        }
+    }
+
+    /// Takes all records being built out of the state
+    pub fn take_snapshot(&mut self) -> RecordStateSnapshot {
+        let builders = self.builders.drain().into_iter().filter_map(|StoreEntry { ident, age, record }| {
+            let record = match record {
+                Builder(builder) => SlotSnapshot::Builder(Box::new(builder)),
+                Session(session) => SlotSnapshot::Session(Box::new(session.borrow().clone())),
+                Tombstone(_) => return None,
+            };
+            Some(StoreEntry { ident, age, record })
+        }).collect();
+
+        RecordStateSnapshot { builders }
+    }
+
+    /// Puts back records taken with `take_snapshot`
+    pub fn restore_snapshot(&mut self, snapshot: RecordStateSnapshot) {
+        let mut linked = Vec::new();
+
+        for StoreEntry { ident, age, record } in snapshot.builders {
+            let record = match record {
+                SlotSnapshot::Builder(builder) => {
+                    if builder.session_ident().is_some() {
+                        linked.push(ident);
+                    }
+                    Builder(*builder)
+                }
+                SlotSnapshot::Session(session) => Session(Rc::new(RefCell::new(*session))),
+            };
+            self.builders.restore(StoreEntry { ident, age, record });
+        }
+
+        // sessions are shared with client requests
+        for ident in linked {
+            let session = match self.builders.get(&ident) {
+                Some(Builder(builder)) => builder.session_ident().and_then(|ident| self.lookup_session(&ident)),
+                _ => None,
+            };
+            if let (Some(session), Some(Builder(builder))) = (session, self.builders.get_mut(&ident)) {
+                builder.set_session(session)
+            }
         }
     }
 
@@ -187,6 +280,15 @@ impl RecordState {
     pub fn tombstone_count(&self) -> usize {
         self.builders.values().filter(|&v| if let Tombstone(_) = *v { true } else { false }).count()
     }
+
+    pub fn store_stats(&self) -> &StoreStats {
+        self.builders.stats()
+    }
+
+    /// Epoch of the last transaction that started
+    pub fn epoch(&self) -> Wrapping<u64> {
+        self.builders.epoch()
+    }
 }
 
 #[cfg(test)]
@@ -112,10 +112,12 @@
 //
 
 use quick_error::quick_error;
+use serde::{Serialize, Deserialize};
 use log::{warn, debug};
 use std::rc::Rc;
 use std::cell::RefCell;
 use crate::maybe_string::{MaybeStr, MaybeString};
+use crate::store::MemSize;
 use crate::vsl::record::{
     VslRecordTag,
     VslIdent,
@@ -174,8 +176,22 @@ quick_error! {
     }
 }
 
+impl RecordBuilderError {
+    /// Name of the error variant
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            RecordBuilderError::SpuriousBegin(_) => "SpuriousBegin",
+            RecordBuilderError::UnimplementedTransactionType(_) => "UnimplementedTransactionType",
+            RecordBuilderError::UnexpectedTransition(_) => "UnexpectedTransition",
+            RecordBuilderError::InvalidMessageFormat(_) => "InvalidMessageFormat",
+            RecordBuilderError::DetailIncomplete(_) => "DetailIncomplete",
+            RecordBuilderError::RecordIncomplete(_) => "RecordIncomplete",
+        }
+    }
+}
+
 /// Head of session data
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SessionHead {
     pub ident: VslIdent,
     pub open: TimeStamp,
@@ -259,6 +275,13 @@ impl SessionHead {
     }
 }
 
+impl MemSize for SessionHead {
+    fn mem_size(&self) -> usize {
+        self.local.mem_size() + self.remote.mem_size() + self.proxy.mem_size() +
+            self.client_records.mem_size() + self.close_reason.mem_size()
+    }
+}
+
 /// Records that can be produced from VSL
 #[derive(Debug, Clone, PartialEq)]
 pub enum Record {
@@ -278,7 +301,7 @@ trait MutBuilder {
     fn build(self) -> Result<Self::C, Self::E>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct MutBuilderState<B> {
     inner: B,
     complete: bool,
@@ -323,7 +346,7 @@ trait DetailBuilder<C>: MutBuilder<C=C, E=RecordBuilderError> + Sized {
 }
 
 // Note: we need to use bytes here since we need to be 1 to 1 comparable with original byte value
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct HeadersBuilder {
     headers: Vec<(MaybeString, MaybeString)>,
 }
@@ -351,7 +374,7 @@ impl HeadersBuilder {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct HttpRequestBuilder {
     protocol: Option<String>,
     method: Option<String>,
@@ -422,7 +445,7 @@ impl MutBuilder for HttpRequestBuilder {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct HttpResponseBuilder {
     protocol: Option<String>,
     status: Option<Status>,
@@ -497,7 +520,7 @@ impl MutBuilder for HttpResponseBuilder {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum ClientAccessTransactionType {
     Full,
     RestartedEarly,
@@ -506,7 +529,7 @@ enum ClientAccessTransactionType {
     Piped,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum BackendAccessTransactionType {
     Full,
     Failed,
@@ -515,7 +538,7 @@ enum BackendAccessTransactionType {
     Piped,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum RecordType {
     ClientAccess {
         reason: String,
@@ -530,13 +553,13 @@ enum RecordType {
     Session
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct ObjStorage {
     stype: String,
     name: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct ObjTtl {
     ttl: Option<Duration>,
     grace: Option<Duration>,
@@ -545,16 +568,18 @@ struct ObjTtl {
     origin: Option<TimeStamp>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FetchBody {
     pub mode: String,
     pub streamed: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RecordBuilder {
     ident: VslIdent,
     record_type: RecordType,
+    // linked again with session restored from snapshot
+    #[serde(skip)]
     session: Option<Rc<RefCell<SessionHead>>>,
     req_start: Option<TimeStamp>,
     pipe_start: Option<TimeStamp>,
@@ -701,7 +726,15 @@ impl RecordBuilder {
                         self.resp_ttfb = None;
                         self.resp_fetch = None;
                     }
+                    // Request was reset by the client (HTTP/2 RST_STREAM)
+                    "Reset" => {
+                        self.req_took = Some(since_work_start);
+                        self.resp_end = Some(timestamp);
+                        self.log.push(LogEntry::Warning("Request was reset by the client".to_string()));
+                    }
                     "Restart" => self.resp_end = Some(timestamp),
+                    // Backend connection established; logged before BackendOpen
+                    "Connected" => (),
                     _ => debug!("Ignoring unmatched SLT_Timestamp label variant: {}", label)
                 };
             }
@@ -749,9 +782,18 @@ impl RecordBuilder {
                 self.log.push(LogEntry::Debug(log_entry.to_lossy_string()));
             }
             SLT_Notice => {
-                let log_entry = vsl.parse_data(slt_vcl_log)?;
+                // Notices are prefixed with an identifier but modules may log anything
+                let log_entry = match vsl.parse_data(slt_notice) {
+                    Ok((id, description)) => format!("{}: {}", id, description.to_lossy_string()),
+                    Err(_) => vsl.parse_data(slt_vcl_log)?.to_lossy_string(),
+                };
+
+                self.log.push(LogEntry::Warning(log_entry));
+            }
+            SLT_SessError => {
+                let (socket, _local, _fd, errno, message) = vsl.parse_data(slt_sess_error)?;
 
-                self.log.push(LogEntry::Warning(log_entry.to_lossy_string()));
+                self.log.push(LogEntry::Error(format!("Accept failed on {} (errno {}): {}", socket, errno, message.to_lossy_string())));
             }
             SLT_Error => {
                 let log_entry = vsl.parse_data(slt_vcl_log)?;
@@ -903,13 +945,21 @@ impl RecordBuilder {
                     local: (local_addr.to_string(), local_port),
                 });
             }
-            SLT_Backend | SLT_BackendStart | SLT_BackendReuse | SLT_BackendClose => {
+            SLT_Backend | SLT_BackendStart | SLT_BackendReuse => {
                 // SLT_Backend: Logged when a connection is selected for handling a backend; Not in use in 6.6.1
                 // SLT_BackendStart: Start of backend processing. Logs the backend IP address and port; Not in use in 6.6.1
                 // SLT_BackendReuse: Logged when a backend connection is put up for reuse by a later; Not used in 6.6.1
-                // SLT_BackendClose: Logged when a backend connection is closed
                 // Not much more than in SLT_BackendOpen
             }
+            SLT_BackendClose => {
+                // Logged when a backend connection is closed or recycled; it is not part of the
+                // record so format we don't know does not fail the transaction
+                match vsl.parse_data(slt_backend_close) {
+                    Ok((fd, name, action, reason)) => debug!("Backend connection {} to {} {}: {}", fd, name, action,
+                        reason.map(|reason| reason.to_lossy_string()).unwrap_or_else(|| "-".to_string())),
+                    Err(err) => warn!("Ignoring SLT_BackendClose record: {}", err),
+                }
+            }
 
             // Request
             SLT_BereqProtocol | SLT_ReqProtocol |
@@ -1120,7 +1170,12 @@ impl RecordBuilder {
                     streamed: streamed,
                 });
             }
-            SLT_VCL_use => (), // TODO: Records the name of the VCL being used
+            SLT_VCL_use => {
+                // Name of the VCL being used and label it was switched to with
+                let (vcl, label) = vsl.parse_data(slt_vcl_use)?;
+
+                debug!("Using VCL {}{}", vcl, label.map(|label| format!(" via {}", label)).unwrap_or_default());
+            }
             SLT_End => return Ok(true),
             SLT__Bogus | SLT__Reserved | SLT__Batch => warn!("Ignoring bogus tag: {:?}", vsl.tag),
             _ => debug!("Ignoring unmatched VSL tag: {:?}", vsl.tag)
@@ -1756,6 +1811,7 @@ mod tests {
 
         builder.apply(&VslRecord {
             tag: SLT_BereqURL,
+            raw_tag: SLT_BereqURL.number(),
             marker: Marker::VSL_BACKENDMARKER,
             ident: 123,
             data: &[0, 159, 146, 150]
@@ -1767,6 +1823,7 @@ mod tests {
 
         builder.apply(&VslRecord {
             tag: SLT_BereqHeader,
+            raw_tag: SLT_BereqHeader.number(),
             marker: Marker::VSL_BACKENDMARKER,
             ident: 123,
             data: &[72, 111, 115, 116, 58, 32, 0, 159, 146, 150]
@@ -2436,6 +2493,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn apply_backend_access_record_connected_backend_close() {
+        let mut builder = apply_new!(
+            32769, SLT_Begin,            "bereq 8 fetch";
+            32769, SLT_VCL_use,          "boot";
+            32769, SLT_Timestamp,        "Start: 1470403414.669375 0.004452 0.000000";
+            32769, SLT_BereqMethod,      "GET";
+            32769, SLT_BereqURL,         "/";
+            32769, SLT_BereqProtocol,    "HTTP/1.1";
+            32769, SLT_VCL_return,       "fetch";
+            32769, SLT_Timestamp,        "Fetch: 1470403414.669400 0.004477 0.000025";
+            32769, SLT_Timestamp,        "Connected: 1470403414.669450 0.004527 0.000050";
+            32769, SLT_BackendOpen,      "26 default 127.0.0.1 42000 127.0.0.1 51058 connect";
+            32769, SLT_Timestamp,        "Bereq: 1470403414.669471 0.004549 0.000021";
+            32769, SLT_Timestamp,        "Beresp: 1470403414.672184 0.007262 0.002713";
+            32769, SLT_BerespProtocol,   "HTTP/1.1";
+            32769, SLT_BerespStatus,     "200";
+            32769, SLT_BerespReason,     "OK";
+            32769, SLT_TTL,              "RFC 120 10 0 1470403414 1470403414 1470403414 0 0 cacheable";
+            32769, SLT_VCL_call,         "BACKEND_RESPONSE";
+            32769, SLT_VCL_return,       "deliver";
+            32769, SLT_Filters,          "";
+            32769, SLT_Storage,          "malloc s0";
+            32769, SLT_Fetch_Body,       "3 length stream";
+            32769, SLT_BackendClose,     "26 default recycle";
+            32769, SLT_Timestamp,        "BerespBody: 1470403414.672290 0.007367 0.000105";
+            32769, SLT_Length,           "6962";
+            32769, SLT_BereqAcct,        "1021 0 1021 608 6962 7570";
+        );
+
+        let record = apply_last!(builder, 32769, SLT_End, "")
+            .unwrap_backend_access();
+
+        assert_eq!(record.start, Some(parse!("1470403414.669375")));
+        assert_eq!(record.end, Some(parse!("1470403414.672290")));
+
+        assert_matches!(record.transaction, BackendAccessTransaction::Full {
+                send,
+                ttfb,
+                wait,
+                fetch,
+                backend_connection,
+                ..
+            } => {
+                assert_eq!(send, parse!("0.004549"));
+                assert_eq!(ttfb, parse!("0.007262"));
+                assert_eq!(wait, parse!("0.002713"));
+                assert_eq!(fetch, parse!("0.007367"));
+                assert_eq!(backend_connection.remote, Some(("127.0.0.1".to_string(), 42000)));
+            }
+        );
+    }
+
+    #[test]
+    fn apply_backend_access_record_bad_backend_close() {
+        let mut builder = apply_new!(
+            32769, SLT_Begin,            "bereq 8 fetch";
+            32769, SLT_Timestamp,        "Start: 1470403414.669375 0.004452 0.000000";
+            32769, SLT_BereqMethod,      "GET";
+            32769, SLT_BereqURL,         "/";
+            32769, SLT_BereqProtocol,    "HTTP/1.1";
+            32769, SLT_VCL_return,       "fetch";
+            32769, SLT_Timestamp,        "Fetch: 1470403414.669400 0.004477 0.000025";
+            32769, SLT_BackendOpen,      "26 default 127.0.0.1 42000 127.0.0.1 51058 connect";
+            32769, SLT_Timestamp,        "Bereq: 1470403414.669471 0.004549 0.000021";
+            32769, SLT_Timestamp,        "Beresp: 1470403414.672184 0.007262 0.002713";
+            32769, SLT_BerespProtocol,   "HTTP/1.1";
+            32769, SLT_BerespStatus,     "200";
+            32769, SLT_BerespReason,     "OK";
+            32769, SLT_TTL,              "RFC 120 10 0 1470403414 1470403414 1470403414 0 0 cacheable";
+            32769, SLT_VCL_call,         "BACKEND_RESPONSE";
+            32769, SLT_VCL_return,       "deliver";
+            32769, SLT_Storage,          "malloc s0";
+            32769, SLT_BackendClose,     "default";
+            32769, SLT_Timestamp,        "BerespBody: 1470403414.672290 0.007367 0.000105";
+            32769, SLT_Length,           "6962";
+            32769, SLT_BereqAcct,        "1021 0 1021 608 6962 7570";
+        );
+
+        let record = apply_last!(builder, 32769, SLT_End, "")
+            .unwrap_backend_access();
+
+        assert_matches!(record.transaction, BackendAccessTransaction::Full { .. });
+    }
+
     #[test]
     fn apply_backend_access_record_full_timing_retry() {
         let mut builder = apply_new!(
@@ -2915,6 +3057,51 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn apply_client_access_record_notice_reset() {
+        let mut builder = apply_new!(
+            7, SLT_Begin,        "req 6 rxreq";
+            7, SLT_Timestamp,    "Start: 1470403413.664824 0.000000 0.000000";
+            7, SLT_Timestamp,    "Req: 1470403414.664824 1.000000 1.000000";
+            7, SLT_VCL_use,      "boot";
+            7, SLT_ReqStart,     "127.0.0.1 39798";
+            7, SLT_ReqMethod,    "GET";
+            7, SLT_ReqURL,       "/";
+            7, SLT_ReqProtocol,  "HTTP/2.0";
+            7, SLT_VCL_call,     "RECV";
+            7, SLT_VCL_use,      "vcl_api via label_api";
+            7, SLT_Notice,       "vsl: Request restarted with different VCL";
+            7, SLT_Notice,       "no identifier";
+            7, SLT_VCL_return,   "hash";
+            7, SLT_VCL_call,     "HASH";
+            7, SLT_VCL_return,   "lookup";
+            7, SLT_VCL_call,     "MISS";
+            7, SLT_VCL_return,   "fetch";
+            7, SLT_Link,         "bereq 8 fetch";
+            7, SLT_Timestamp,    "Fetch: 1470403414.672315 1.007491 0.007491";
+            7, SLT_RespProtocol, "HTTP/2.0";
+            7, SLT_RespStatus,   "200";
+            7, SLT_RespReason,   "OK";
+            7, SLT_VCL_call,     "DELIVER";
+            7, SLT_VCL_return,   "deliver";
+            7, SLT_Timestamp,    "Process: 1470403414.672425 1.007601 0.000111";
+            7, SLT_Timestamp,    "Reset: 1470403414.672458 1.007634 0.000032";
+            7, SLT_ReqAcct,      "82 0 82 304 0 304";
+        );
+
+        set_stub_session(&mut builder);
+
+        let record = apply_last!(builder, 7, SLT_End, "")
+            .unwrap_client_access();
+
+        assert_eq!(record.end, Some(parse!("1470403414.672458")));
+        assert_eq!(record.log, &[
+            LogEntry::Warning("vsl: Request restarted with different VCL".to_string()),
+            LogEntry::Warning("no identifier".to_string()),
+            LogEntry::Warning("Request was reset by the client".to_string()),
+        ]);
+    }
+
     #[test]
     fn apply_client_access_bad_request_proxy() {
         let mut builder = apply_new!(
@@ -71,12 +71,15 @@
 //     6 SLT_SessClose      REM_CLOSE 0.008
 //     6 SLT_End
 
-use crate::store::VslStore;
+use crate::store::{VslStore, StoreEntry};
 use crate::store::Config as StoreConfig;
-use crate::access_log::record_state::RecordState;
+use crate::store::Stats as StoreStats;
+use crate::access_log::record_state::{RecordState, RecordStateSnapshot};
 use crate::access_log::record_state::record_builder::{RecordBuilder, SessionHead};
 use std::rc::Rc;
+use std::num::Wrapping;
 use std::cell::RefCell;
+use serde::{Serialize, Deserialize};
 use crate::access_log::record::{
     AccessRecord,
     ClientAccessRecord,
@@ -85,7 +88,7 @@ use crate::access_log::record::{
     BackendAccessTransaction,
     Link,
 };
-use crate::vsl::record::VslRecord;
+use crate::vsl::record::{AsVslRecord, VslIdent};
 
 #[derive(Debug)]
 pub struct SessionState {
@@ -95,9 +98,36 @@ pub struct SessionState {
     backend: VslStore<BackendAccessRecord>
 }
 
+/// In-flight correlation state taken out of SessionState so it can be restored after restart
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionStateSnapshot {
+    records: RecordStateSnapshot,
+    root: Vec<StoreEntry<ClientAccessRecord>>,
+    client: Vec<StoreEntry<ClientAccessRecord>>,
+    backend: Vec<StoreEntry<BackendAccessRecord>>,
+}
+
+/// Statistics of the stores of SessionState
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionStateStats {
+    /// Epoch of the last transaction that started
+    pub epoch: u64,
+    pub builders: StoreStats,
+    pub root: StoreStats,
+    pub client: StoreStats,
+    pub backend: StoreStats,
+    /// Transactions still being built
+    pub building: usize,
+    /// Transactions that failed to build
+    pub tombstones: usize,
+}
+
+// With `partial` records are linked even if they are not resolved themselves; used to link records
+// that are available when giving up on resolving
 fn try_resolve_client_link(link: &mut Link<ClientAccessRecord>,
                       client_records: &mut VslStore<ClientAccessRecord>,
-                      backend_records: &mut VslStore<BackendAccessRecord>) -> bool {
+                      backend_records: &mut VslStore<BackendAccessRecord>,
+                      partial: bool) -> bool {
     let client_record = if let Link::Unresolved(ref ident, _) = *link {
         // move from store to stack
         client_records.remove(ident)
@@ -107,12 +137,12 @@ fn try_resolve_client_link(link: &mut Link<ClientAccessRecord>,
 
     if let Some(mut client_record) = client_record {
         // recurse down
-        let resolved = try_resolve_client_record(&mut client_record, client_records, backend_records);
+        let resolved = try_resolve_client_record(&mut client_record, client_records, backend_records, partial);
 
-        if resolved {
+        if resolved || partial {
             // move is on heap
             *link = Link::Resolved(Box::new(client_record));
-            return true
+            return resolved
         } else {
             // move it back to store
             client_records.insert(client_record.ident, client_record);
@@ -122,7 +152,8 @@ fn try_resolve_client_link(link: &mut Link<ClientAccessRecord>,
 }
 
 fn try_resolve_backend_link(link: &mut Link<BackendAccessRecord>,
-                       backend_records: &mut VslStore<BackendAccessRecord>) -> bool {
+                       backend_records: &mut VslStore<BackendAccessRecord>,
+                       partial: bool) -> bool {
     let backend_record = if let Link::Unresolved(ref ident, _) = *link {
         backend_records.remove(ident)
     } else {
@@ -130,11 +161,11 @@ fn try_resolve_backend_link(link: &mut Link<BackendAccessRecord>,
     };
 
     if let Some(mut backend_record) = backend_record {
-        let resolved = try_resolve_backend_record(&mut backend_record, backend_records);
+        let resolved = try_resolve_backend_record(&mut backend_record, backend_records, partial);
 
-        if resolved {
+        if resolved || partial {
             *link = Link::Resolved(Box::new(backend_record));
-            return true
+            return resolved
         } else {
             backend_records.insert(backend_record.ident, backend_record)
         }
@@ -143,7 +174,8 @@ fn try_resolve_backend_link(link: &mut Link<BackendAccessRecord>,
 }
 
 fn try_resolve_backend_record(backend_record: &mut BackendAccessRecord,
-                      backend_records: &mut VslStore<BackendAccessRecord>) -> bool {
+                      backend_records: &mut VslStore<BackendAccessRecord>,
+                      partial: bool) -> bool {
     match backend_record.transaction {
         BackendAccessTransaction::Failed {
             ref mut retry_record,
@@ -154,7 +186,7 @@ fn try_resolve_backend_record(backend_record: &mut BackendAccessRecord,
             ..
         } => {
             if let Some(ref mut link) = *retry_record {
-                try_resolve_backend_link(link, backend_records)
+                try_resolve_backend_link(link, backend_records, partial)
             } else {
                 true
             }
@@ -167,7 +199,8 @@ fn try_resolve_backend_record(backend_record: &mut BackendAccessRecord,
 
 fn try_resolve_client_record(client_record: &mut ClientAccessRecord,
                       client_records: &mut VslStore<ClientAccessRecord>,
-                      backend_records: &mut VslStore<BackendAccessRecord>) -> bool {
+                      backend_records: &mut VslStore<BackendAccessRecord>,
+                      partial: bool) -> bool {
     let backend_record_resolved = match client_record.transaction {
         ClientAccessTransaction::Full {
             backend_record: Some(ref mut link),
@@ -181,7 +214,7 @@ fn try_resolve_client_record(client_record: &mut ClientAccessRecord,
             backend_record: ref mut link,
             ..
         } => {
-            try_resolve_backend_link(link, backend_records)
+            try_resolve_backend_link(link, backend_records, partial)
         }
         ClientAccessTransaction::Full { backend_record: None, ..  } |
         ClientAccessTransaction::RestartedLate { backend_record: None, .. } |
@@ -194,9 +227,15 @@ fn try_resolve_client_record(client_record: &mut ClientAccessRecord,
             ref mut esi_records,
             ..
         } => {
-            esi_records.iter_mut().all(|link|
-                try_resolve_client_link(link, client_records, backend_records)
-            )
+            let mut resolved = true;
+            for link in esi_records.iter_mut() {
+                // with `partial` all the available records are linked
+                if !resolved && !partial {
+                    break
+                }
+                resolved &= try_resolve_client_link(link, client_records, backend_records, partial);
+            }
+            resolved
         }
         ClientAccessTransaction::RestartedEarly { .. } |
         ClientAccessTransaction::RestartedLate { .. } |
@@ -213,7 +252,7 @@ fn try_resolve_client_record(client_record: &mut ClientAccessRecord,
             restart_record: ref mut link,
             ..
         } => {
-            try_resolve_client_link(link, client_records, backend_records)
+            try_resolve_client_link(link, client_records, backend_records, partial)
         }
         ClientAccessTransaction::Full { .. } |
         ClientAccessTransaction::Bad { .. } |
@@ -273,58 +312,128 @@ impl SessionState {
         }
     }
 
-    pub fn apply(&mut self, vsl: &VslRecord<'_>) -> Option<ClientAccessRecord> {
+    pub fn apply<V: AsVslRecord + ?Sized>(&mut self, vsl: &V) -> Option<ClientAccessRecord> {
+        match self.apply_access_record(vsl) {
+            Some(AccessRecord::ClientAccess(record)) => Some(record),
+            // Not much use for session record since we complete requests automatically before and also after (e.g. bgfetch) session is closed
+            Some(_) | None => None
+        }
+    }
+
+    /// Like `apply` but also passes through completed `SessionRecord`s
+    pub fn apply_access_record<V: AsVslRecord + ?Sized>(&mut self, vsl: &V) -> Option<AccessRecord> {
         match self.record_state.apply(vsl) {
             Some(AccessRecord::ClientAccess(mut record)) => {
                 if record.root {
-                    if try_resolve_client_record(&mut record, &mut self.client, &mut self.backend) {
-                        return Some(record)
+                    if try_resolve_client_record(&mut record, &mut self.client, &mut self.backend, false) {
+                        return Some(AccessRecord::ClientAccess(record))
                     }
                     self.root.insert(record.ident, record);
                     return None
                 }
 
-                let root_ident =
+                let (root_ident, resolved) =
                     if let Some(ref mut root) = find_root_mut_from_client_record(&record, &mut self.root, &self.client) {
                         self.client.insert(record.ident, record);
-
-                        if !try_resolve_client_record(root, &mut self.client, &mut self.backend) {
-                            return None
-                        }
-
-                        root.ident
+                        (root.ident, try_resolve_client_record(root, &mut self.client, &mut self.backend, false))
                     } else {
                         self.client.insert(record.ident, record);
                         return None
                     };
 
-                self.root.remove(&root_ident)
+                self.take_resolved_root(root_ident, resolved)
             }
             Some(AccessRecord::BackendAccess(record)) => {
-                let root_ident =
+                let (root_ident, resolved) =
                     if let Some(ref mut root) = find_root_mut_from_backend_record(&record, &mut self.root, &self.client, &self.backend) {
                         self.backend.insert(record.ident, record);
-
-                        if !try_resolve_client_record(root, &mut self.client, &mut self.backend) {
-                            return None
-                        }
-
-                        root.ident
+                        (root.ident, try_resolve_client_record(root, &mut self.client, &mut self.backend, false))
                     } else {
                         self.backend.insert(record.ident, record);
                         return None
                     };
 
-                self.root.remove(&root_ident)
-            }
-            Some(AccessRecord::Session(_session)) => {
-                // Not much use for session record since we complete requests automatically before and also after (e.g. bgfetch) session is closed
-                None
+                self.take_resolved_root(root_ident, resolved)
             }
+            Some(AccessRecord::Session(session)) => Some(AccessRecord::Session(session)),
             None => None
         }
     }
 
+    fn take_resolved_root(&mut self, root_ident: VslIdent, resolved: bool) -> Option<AccessRecord> {
+        if !resolved {
+            // records that got resolved were moved into the root
+            self.root.update_size(&root_ident);
+            return None
+        }
+        self.root.remove(&root_ident).map(AccessRecord::ClientAccess)
+    }
+
+    /// Keep client records that expire or are nuked before they are resolved so they can be taken
+    /// with `take_partial_records`
+    pub fn keep_partial_records(&mut self) {
+        self.root.keep_evicted();
+        self.client.keep_evicted();
+    }
+
+    /// Client records with unresolved links that were expired or nuked since last call and
+    /// client records whose parent expired before linking them; empty unless
+    /// `keep_partial_records` was called
+    pub fn take_partial_records(&mut self) -> Vec<ClientAccessRecord> {
+        let mut records = self.root.take_evicted();
+        records.extend(self.client.take_evicted());
+        records.into_iter().map(|(_, record)| record).collect()
+    }
+
+    /// Like `take_partial_records` but also takes all client records still waiting to be
+    /// resolved with the records they link to that are available; used at the end of the stream
+    pub fn drain_partial_records(&mut self) -> Vec<ClientAccessRecord> {
+        let mut records = self.take_partial_records();
+
+        for StoreEntry { mut record, .. } in self.root.drain() {
+            try_resolve_client_record(&mut record, &mut self.client, &mut self.backend, true);
+            records.push(record);
+        }
+
+        // records with root that is not known; parents end after their children so they are
+        // linked from the newest
+        let idents = self.client.values().map(|record| record.ident).collect::<Vec<_>>();
+        let mut orphans = Vec::new();
+        for ident in idents.into_iter().rev() {
+            if let Some(mut record) = self.client.remove(&ident) {
+                try_resolve_client_record(&mut record, &mut self.client, &mut self.backend, true);
+                orphans.push(record);
+            }
+        }
+        records.extend(orphans.into_iter().rev());
+        records
+    }
+
+    /// Takes all the records being built or waiting to be resolved out of the state
+    pub fn take_snapshot(&mut self) -> SessionStateSnapshot {
+        SessionStateSnapshot {
+            records: self.record_state.take_snapshot(),
+            root: self.root.drain(),
+            client: self.client.drain(),
+            backend: self.backend.drain(),
+        }
+    }
+
+    /// Puts back records taken with `take_snapshot`; records keep their age so they expire as if
+    /// the state was never interrupted
+    pub fn restore_snapshot(&mut self, snapshot: SessionStateSnapshot) {
+        self.record_state.restore_snapshot(snapshot.records);
+        for entry in snapshot.root {
+            self.root.restore(entry);
+        }
+        for entry in snapshot.client {
+            self.client.restore(entry);
+        }
+        for entry in snapshot.backend {
+            self.backend.restore(entry);
+        }
+    }
+
     pub fn unresolved_root_client_access_records(&self) -> Vec<&ClientAccessRecord> {
         self.root.values().collect()
     }
@@ -345,6 +454,34 @@ impl SessionState {
         self.record_state.sessions().collect()
     }
 
+    /// Number of records still being built or waiting for records they are linked with
+    pub fn unresolved_count(&self) -> usize {
+        self.root.values().count() + self.client.values().count() + self.backend.values().count() +
+            self.record_state.building_count()
+    }
+
+    /// Number of transactions that failed to build
+    pub fn tombstone_count(&self) -> usize {
+        self.record_state.tombstone_count()
+    }
+
+    /// Epoch of the last transaction that started
+    pub fn epoch(&self) -> Wrapping<u64> {
+        self.record_state.epoch()
+    }
+
+    pub fn stats(&self) -> SessionStateStats {
+        SessionStateStats {
+            epoch: self.epoch().0,
+            builders: self.record_state.store_stats().clone(),
+            root: self.root.stats().clone(),
+            client: self.client.stats().clone(),
+            backend: self.backend.stats().clone(),
+            building: self.record_state.building_count(),
+            tombstones: self.record_state.tombstone_count(),
+        }
+    }
+
     #[cfg(test)]
     pub fn still_building(&self) -> usize {
         self.record_state.building_count()
@@ -1099,4 +1236,98 @@ mod tests {
             assert_matches!(backend_record.get_resolved().unwrap().transaction, BackendAccessTransaction::Piped { .. })
         );
     }
+
+    #[test]
+    fn restore_session_state_snapshot() {
+        use std::fs::File;
+        use crate::vsl::reader::VslReader;
+
+        let log = "logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl";
+        let records = VslReader::new(File::open(log).unwrap()).map(|record| record.unwrap()).collect::<Vec<_>>();
+
+        let mut expected = SessionState::new();
+        let expected = records.iter().filter_map(|record| expected.apply(record)).collect::<Vec<_>>();
+
+        let split = records.len() / 2;
+        let mut state = SessionState::new();
+        let mut client_records = records[..split].iter().filter_map(|record| state.apply(record)).collect::<Vec<_>>();
+
+        let snapshot = serde_json::to_string(&state.take_snapshot()).unwrap();
+        assert_eq!(state.unresolved_count(), 0);
+
+        let mut state = SessionState::new();
+        state.restore_snapshot(serde_json::from_str(&snapshot).unwrap());
+        assert!(state.unresolved_count() > 0);
+        client_records.extend(records[split..].iter().filter_map(|record| state.apply(record)));
+
+        assert_eq!(client_records, expected);
+        assert_eq!(state.unresolved_count(), 0);
+    }
+
+    #[test]
+    fn take_partial_records() {
+        log();
+        let mut state = SessionState::new();
+        state.keep_partial_records();
+
+        // backend fetch 65540 and parent request of the ESI subrequest never arrive
+        apply_all!(state,
+            65539, SLT_Begin,            "req 65538 esi";
+            65539, SLT_ReqStart,         "127.0.0.1 57408";
+            65539, SLT_Timestamp,        "Start: 1470304807.390350 0.000000 0.000000";
+            65539, SLT_ReqMethod,        "GET";
+            65539, SLT_ReqURL,           "/esi/hello";
+            65539, SLT_ReqProtocol,      "HTTP/1.1";
+            65539, SLT_VCL_call,         "RECV";
+            65539, SLT_VCL_return,       "hash";
+            65539, SLT_VCL_call,         "HASH";
+            65539, SLT_VCL_return,       "lookup";
+            65539, SLT_VCL_call,         "MISS";
+            65539, SLT_VCL_return,       "fetch";
+            65539, SLT_Link,             "bereq 65540 fetch";
+            65539, SLT_Timestamp,        "Fetch: 1470304807.394973 0.004623 0.004623";
+            65539, SLT_RespProtocol,     "HTTP/1.1";
+            65539, SLT_RespStatus,       "200";
+            65539, SLT_RespReason,       "OK";
+            65539, SLT_VCL_call,         "DELIVER";
+            65539, SLT_VCL_return,       "deliver";
+            65539, SLT_Timestamp,        "Process: 1470304807.395009 0.004659 0.000036";
+            65539, SLT_Timestamp,        "Resp: 1470304807.395120 0.004770 0.000111";
+            65539, SLT_ReqAcct,          "0 0 0 0 12 12";
+            65539, SLT_End,              "";
+        );
+        assert!(state.take_partial_records().is_empty());
+
+        let partial = state.drain_partial_records();
+        assert_eq!(partial.len(), 1);
+        assert_eq!(partial[0].ident, 65539);
+        assert_eq!(partial[0].unresolved_links(), vec![65540]);
+        assert_eq!(state.unresolved_count(), 0);
+    }
+
+    #[test]
+    fn drain_partial_records_with_available_links() {
+        use std::fs::File;
+        use crate::vsl::reader::VslReader;
+
+        let mut state = SessionState::new();
+
+        // backend fetch of ESI subrequest 65541 is lost
+        let log = "logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl";
+        for record in VslReader::new(File::open(log).unwrap()) {
+            let record = record.unwrap();
+            if record.ident != 65542 {
+                state.apply(&record);
+            }
+        }
+
+        let partial = state.drain_partial_records();
+        assert_eq!(partial.len(), 1);
+        assert_eq!(partial[0].ident, 65538);
+        assert_eq!(partial[0].unresolved_links(), vec![65542]);
+        assert_matches!(partial[0].transaction, ClientAccessTransaction::Full { ref esi_records, .. } => {
+            assert!(esi_records.iter().all(|link| link.is_resolved()));
+        });
+        assert_eq!(state.unresolved_count(), 0);
+    }
 }
@@ -0,0 +1,173 @@
+// Transparent decompression of gzip and zstd compressed input streams.
+//
+// Compression is detected by magic bytes at the beginning of the stream; streams without known
+// magic are passed through as they are. Data is decompressed while it is read so memory use is
+// bounded by the buffer size (and zstd window size) regardless of the size of the stream.
+use std::io::{self, Read, BufReader, Cursor, Chain};
+use std::fmt::{self, Display};
+use flate2::bufread::MultiGzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+// Magic bytes read from the stream are put back in front of it
+type Input<R> = Chain<Cursor<Vec<u8>>, R>;
+
+// Uncompressed stream is not buffered here as it is buffered by its reader
+enum Decoder<R: Read> {
+    None(Input<R>),
+    Gzip(MultiGzDecoder<BufReader<Input<R>>>),
+    Zstd(ZstdDecoder<'static, BufReader<Input<R>>>),
+}
+
+/// Decompresses the stream if it starts with gzip or zstd magic bytes
+pub struct DecompressReader<R: Read> {
+    compression: Compression,
+    decoder: Decoder<R>,
+}
+
+impl<R: Read> DecompressReader<R> {
+    /// Reads magic bytes from input; `buf_size` is size of the buffer of compressed data
+    pub fn with_capacity(mut input: R, buf_size: usize) -> io::Result<DecompressReader<R>> {
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        while magic.len() < ZSTD_MAGIC.len() {
+            let mut byte = [0; 1];
+            match input.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => magic.push(byte[0]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let compression = Compression::detect(&magic);
+        let input = Cursor::new(magic).chain(input);
+
+        let decoder = match compression {
+            Compression::None => Decoder::None(input),
+            Compression::Gzip => Decoder::Gzip(MultiGzDecoder::new(BufReader::with_capacity(buf_size, input))),
+            Compression::Zstd => Decoder::Zstd(ZstdDecoder::with_buffer(BufReader::with_capacity(buf_size, input))?),
+        };
+
+        Ok(DecompressReader { compression, decoder })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
+
+impl<R: Read> Read for DecompressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.decoder {
+            Decoder::None(ref mut input) => input.read(buf),
+            Decoder::Gzip(ref mut decoder) => decoder.read(buf),
+            Decoder::Zstd(ref mut decoder) => decoder.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use crate::vsl::reader::VslReader;
+
+    const LOG: &str = "logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_all(data: &[u8]) -> (Compression, Vec<u8>) {
+        let mut reader = DecompressReader::with_capacity(data, 64).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        (reader.compression(), out)
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Compression::detect(b"VSL\0"), Compression::None);
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 8, 0]), Compression::Gzip);
+        assert_eq!(Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd]), Compression::Zstd);
+        assert_eq!(Compression::detect(&[0x28, 0xb5]), Compression::None);
+    }
+
+    #[test]
+    fn plain_stream() {
+        assert_eq!(read_all(b"VSL\0foo"), (Compression::None, b"VSL\0foo".to_vec()));
+        assert_eq!(read_all(b"VS"), (Compression::None, b"VS".to_vec()));
+        assert_eq!(read_all(b""), (Compression::None, Vec::new()));
+    }
+
+    #[test]
+    fn gzip_stream() {
+        let log = std::fs::read(LOG).unwrap();
+        assert_eq!(read_all(&gzip(&log)), (Compression::Gzip, log.clone()));
+
+        // like zcat concatenated members are read as one stream
+        let mut data = gzip(&log[..100]);
+        data.extend(gzip(&log[100..]));
+        assert_eq!(read_all(&data), (Compression::Gzip, log));
+    }
+
+    #[test]
+    fn zstd_stream() {
+        let log = std::fs::read(LOG).unwrap();
+        let data = zstd::encode_all(log.as_slice(), 3).unwrap();
+        assert_eq!(read_all(&data), (Compression::Zstd, log));
+    }
+
+    #[test]
+    fn read_compressed_vsl_records() {
+        let log = std::fs::read(LOG).unwrap();
+
+        let mut reader = VslReader::new(log.as_slice());
+        let mut records = 0;
+        while reader.read_record().unwrap().is_some() {
+            records += 1;
+        }
+
+        let data = gzip(&log);
+        let mut reader = VslReader::new(DecompressReader::with_capacity(data.as_slice(), 1024).unwrap());
+        let mut compressed_records = 0;
+        while reader.read_record().unwrap().is_some() {
+            compressed_records += 1;
+        }
+        assert_eq!(compressed_records, records);
+    }
+}
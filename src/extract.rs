@@ -0,0 +1,391 @@
+// Extraction of transaction trees from VSL stream
+//
+// Records are grouped by vxid and groups are linked into trees using parent vxid from
+// SLT_Begin and child vxids from SLT_Link records. These are the links session_state.rs
+// resolves between access records (ESI, grace/bgfetch, restarts and retry; see there) but
+// here records are not built into access records so that any transaction can be extracted
+// as logged; only the links are followed.
+//
+// Links taken from corrupt input or from logs of more varnishd instances mixed together may
+// form cycles (e.g. transaction being its own parent) so walks over them stop at transactions
+// already visited.
+//
+// Varnish logs transaction records together on SLT_End so child transactions usually
+// appear before their parents and background fetches may appear after the whole session
+// has ended. A tree is considered complete when its root (transaction with parent 0,
+// e.g. the session) and all transactions linked from it have ended. Complete trees are
+// either passed to the sink (with records in original stream order) or discarded.
+// Trees still incomplete at the end of the stream (e.g. sessions that were not logged)
+// are matched and passed on finish.
+use std::num::Wrapping;
+use std::mem::size_of;
+use log::{debug, warn};
+
+use crate::store::{VslStore, Config as StoreConfig, MemSize, observe_log_time};
+use crate::sink::{RecordSink, SinkError, SinkStats};
+use crate::vsl::record::{VslRecord, VslRecordBuf, VslRecordTag, VslIdent};
+use crate::vsl::record::message::parser::{slt_begin, slt_link, slt_header};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    /// Tree rooted at transaction with given vxid
+    Vxid(VslIdent),
+    /// Trees containing request with URL containing given string
+    ReqUrl(String),
+    /// Trees containing request with header of given name (case insensitive) and value
+    /// containing given string
+    ReqHeader(String, String),
+}
+
+impl Selector {
+    fn matches(&self, record: &VslRecord<'_>) -> bool {
+        match (self, record.tag) {
+            (Selector::Vxid(ident), _) => record.ident == *ident,
+            (Selector::ReqUrl(url), VslRecordTag::SLT_ReqURL) => {
+                contains(record.data, url.as_bytes())
+            }
+            (Selector::ReqHeader(name, value), VslRecordTag::SLT_ReqHeader) => {
+                match record.parse_data(slt_header) {
+                    Ok((header_name, header_value)) =>
+                        header_name.as_bytes().eq_ignore_ascii_case(name.as_bytes()) &&
+                        contains(header_value.map(|v| v.as_bytes()).unwrap_or(b""), value.as_bytes()),
+                    Err(_) => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+#[derive(Debug)]
+struct Group {
+    ident: VslIdent,
+    parent: Option<VslIdent>,
+    children: Vec<VslIdent>,
+    records: Vec<(u64, VslRecordBuf)>,
+    ended: bool,
+    matched: bool,
+}
+
+impl MemSize for Group {
+    fn mem_size(&self) -> usize {
+        self.children.mem_size() + self.records.mem_size()
+    }
+}
+
+impl Group {
+    fn new(ident: VslIdent) -> Group {
+        Group {
+            ident,
+            parent: None,
+            children: Vec::new(),
+            records: Vec::new(),
+            ended: false,
+            matched: false,
+        }
+    }
+}
+
+/// Sink passing only records of transaction trees selected by Selector to other sink
+pub struct ExtractSink<S: RecordSink> {
+    selector: Selector,
+    groups: VslStore<Group>,
+    seq: u64,
+    sink: S,
+}
+
+impl<S: RecordSink> ExtractSink<S> {
+    pub fn new(sink: S, selector: Selector) -> ExtractSink<S> {
+        ExtractSink::with_config(sink, selector, &Default::default())
+    }
+
+    pub fn with_config(sink: S, selector: Selector, store_config: &StoreConfig) -> ExtractSink<S> {
+        ExtractSink {
+            selector,
+            groups: VslStore::with_config("extract", Some(Self::on_evict), Some(Self::on_evict), store_config),
+            seq: 0,
+            sink,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+
+    fn on_evict(store_name: &str, current_epoch: Wrapping<u64>, record_epoch: Wrapping<u64>, record_ident: VslIdent, group: &Group) {
+        warn!("VslStore[{}]: Dropped records of transaction {} from store: current epoch {}, record epoch {}, records: {}",
+              store_name, record_ident, current_epoch, record_epoch, group.records.len());
+    }
+
+    // Topmost ancestor of the transaction that we know of
+    fn top(&self, mut ident: VslIdent) -> VslIdent {
+        let mut visited = vec![ident];
+        while let Some(parent) = self.groups.get(&ident).and_then(|group| group.parent) {
+            if !self.groups.contains_key(&parent) || visited.contains(&parent) {
+                break
+            }
+            visited.push(parent);
+            ident = parent;
+        }
+        ident
+    }
+
+    // Transaction and all transactions linked from it have ended
+    fn is_complete(&self, ident: VslIdent) -> bool {
+        let mut visited = Vec::new();
+        let mut pending = vec![ident];
+        while let Some(ident) = pending.pop() {
+            if visited.contains(&ident) {
+                continue
+            }
+            match self.groups.get(&ident) {
+                Some(group) if group.ended => pending.extend(&group.children),
+                _ => return false,
+            }
+            visited.push(ident);
+        }
+        true
+    }
+
+    // Transaction and all transactions linked from it that we know of, parents before children
+    fn tree(&self, ident: VslIdent, idents: &mut Vec<VslIdent>) {
+        let mut pending = vec![ident];
+        while let Some(ident) = pending.pop() {
+            if idents.contains(&ident) {
+                continue
+            }
+            if let Some(group) = self.groups.get(&ident) {
+                idents.push(ident);
+                pending.extend(group.children.iter().rev());
+            }
+        }
+    }
+
+    // Take complete tree out of the store and write selected records to the sink
+    fn resolve(&mut self, root: VslIdent) -> Result<(), SinkError> {
+        let mut tree = Vec::new();
+        self.tree(root, &mut tree);
+
+        let mut selected = Vec::new();
+        match self.selector {
+            Selector::Vxid(ident) => if tree.contains(&ident) {
+                self.tree(ident, &mut selected);
+            },
+            Selector::ReqUrl(_) |
+            Selector::ReqHeader(..) => if tree.iter().any(|ident| self.groups.get(ident).map(|group| group.matched).unwrap_or(false)) {
+                selected = tree.clone();
+            }
+        }
+
+        let mut records = Vec::new();
+        for ident in &tree {
+            let group = self.groups.remove(ident).expect("transaction tree group");
+            if selected.contains(ident) {
+                records.extend(group.records);
+            }
+        }
+
+        if records.is_empty() {
+            debug!("Discarding transaction tree of {} with {} transactions", root, tree.len());
+            return Ok(())
+        }
+
+        records.sort_by_key(|&(seq, _)| seq);
+        for (_, record) in &records {
+            self.sink.write_record(&record.as_record())?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: RecordSink> RecordSink for ExtractSink<S> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        observe_log_time(record);
+
+        // Do not store 0 SLT_CLI Rd ping etc.
+        if !(record.is_client() || record.is_backend()) {
+            return Ok(())
+        }
+
+        if !self.groups.contains_key(&record.ident) {
+            self.groups.insert(record.ident, Group::new(record.ident));
+        }
+
+        let matched = self.selector.matches(record);
+        let seq = self.seq;
+        self.seq += 1;
+
+        self.groups.grow(&record.ident, size_of::<(u64, VslRecordBuf)>() + record.data.len());
+        {
+            let group = self.groups.get_mut(&record.ident).unwrap();
+            group.records.push((seq, record.to_record_buf()));
+            group.matched |= matched;
+
+            match record.tag {
+                VslRecordTag::SLT_Begin => if let Ok((_, parent, _)) = record.parse_data(slt_begin) {
+                    group.parent = Some(parent);
+                },
+                VslRecordTag::SLT_Link => if let Ok((_, child, _)) = record.parse_data(slt_link) {
+                    group.children.push(child);
+                },
+                VslRecordTag::SLT_End => group.ended = true,
+                _ => return Ok(()),
+            }
+        }
+
+        let top = self.top(record.ident);
+        let is_root = self.groups.get(&top).and_then(|group| group.parent) == Some(0);
+        if is_root && self.is_complete(top) {
+            self.resolve(top)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        let stats = self.sink.stats();
+        SinkStats { unresolved: stats.unresolved + self.groups.values().count(), ..stats }
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.sink.flush()
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        // resolve what is left starting from topmost ancestor of any remaining transaction
+        while let Some(ident) = self.groups.values().next().map(|group| group.ident) {
+            let top = self.top(ident);
+            self.resolve(top)?;
+        }
+        self.sink.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_log::test_helpers::*;
+    use crate::vsl::record::VslRecordTag;
+
+    #[derive(Default)]
+    struct CollectSink {
+        records: Vec<VslRecordBuf>,
+    }
+
+    impl RecordSink for CollectSink {
+        fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+            self.records.push(record.to_record_buf());
+            Ok(())
+        }
+    }
+
+    fn extract(selector: Selector, records: &[VslRecord<'_>]) -> Vec<(VslIdent, VslRecordTag)> {
+        let mut sink = ExtractSink::new(CollectSink::default(), selector);
+        for record in records {
+            sink.write_record(record).unwrap();
+        }
+        sink.finish().unwrap();
+        sink.into_inner().records.iter().map(|record| (record.ident, record.tag)).collect()
+    }
+
+    // Two sessions with ESI request and background fetch after the session has ended
+    fn records() -> Vec<VslRecord<'static>> {
+        vec![
+            vsl(SLT_Begin, 3, "req 2 esi"),
+            vsl(SLT_ReqURL, 3, "/esi"),
+            vsl(SLT_End, 3, ""),
+            vsl(SLT_Begin, 6, "req 5 rxreq"),
+            vsl(SLT_ReqURL, 6, "/other"),
+            vsl(SLT_End, 6, ""),
+            vsl(SLT_Begin, 2, "req 1 rxreq"),
+            vsl(SLT_ReqURL, 2, "/index"),
+            vsl(SLT_ReqHeader, 2, "Host: example.com"),
+            vsl(SLT_Link, 2, "req 3 esi"),
+            vsl(SLT_Link, 2, "bereq 4 bgfetch"),
+            vsl(SLT_End, 2, ""),
+            vsl(SLT_Begin, 1, "sess 0 HTTP/1"),
+            vsl(SLT_Link, 1, "req 2 rxreq"),
+            vsl(SLT_End, 1, ""),
+            vsl(SLT_Begin, 5, "sess 0 HTTP/1"),
+            vsl(SLT_Link, 5, "req 6 rxreq"),
+            vsl(SLT_End, 5, ""),
+            vsl(SLT_Begin, 4, "bereq 2 bgfetch"),
+            vsl(SLT_End, 4, ""),
+        ]
+    }
+
+    fn idents(records: &[(VslIdent, VslRecordTag)]) -> Vec<VslIdent> {
+        let mut idents: Vec<VslIdent> = records.iter().map(|&(ident, _)| ident).collect();
+        idents.dedup();
+        idents
+    }
+
+    #[test]
+    fn extract_by_vxid() {
+        let extracted = extract(Selector::Vxid(2), &records());
+        assert_eq!(idents(&extracted), vec![3, 2, 4]);
+        assert_eq!(extracted.len(), 11);
+
+        let extracted = extract(Selector::Vxid(5), &records());
+        assert_eq!(idents(&extracted), vec![6, 5]);
+    }
+
+    #[test]
+    fn extract_by_url() {
+        let extracted = extract(Selector::ReqUrl("/esi".to_string()), &records());
+        assert_eq!(idents(&extracted), vec![3, 2, 1, 4]);
+
+        let extracted = extract(Selector::ReqUrl("/nothing".to_string()), &records());
+        assert!(extracted.is_empty());
+    }
+
+    #[test]
+    fn extract_by_header() {
+        let extracted = extract(Selector::ReqHeader("host".to_string(), "example".to_string()), &records());
+        assert_eq!(idents(&extracted), vec![3, 2, 1, 4]);
+
+        let extracted = extract(Selector::ReqHeader("host".to_string(), "foo".to_string()), &records());
+        assert!(extracted.is_empty());
+    }
+
+    #[test]
+    fn extract_with_link_cycles() {
+        let records = vec![
+            // own parent and child
+            vsl(SLT_Begin, 1, "req 1 rxreq"),
+            vsl(SLT_Link, 1, "req 1 rxreq"),
+            vsl(SLT_ReqURL, 1, "/self"),
+            vsl(SLT_End, 1, ""),
+            // parents of each other
+            vsl(SLT_Begin, 2, "req 3 esi"),
+            vsl(SLT_ReqURL, 2, "/cycle"),
+            vsl(SLT_Link, 2, "req 3 esi"),
+            vsl(SLT_End, 2, ""),
+            vsl(SLT_Begin, 3, "req 2 esi"),
+            vsl(SLT_Link, 3, "req 2 esi"),
+            vsl(SLT_End, 3, ""),
+        ];
+
+        let extracted = extract(Selector::ReqUrl("/self".to_string()), &records);
+        assert_eq!(idents(&extracted), vec![1]);
+        assert_eq!(extracted.len(), 4);
+
+        let extracted = extract(Selector::ReqUrl("/cycle".to_string()), &records);
+        assert_eq!(idents(&extracted), vec![2, 3]);
+
+        let extracted = extract(Selector::Vxid(3), &records);
+        assert_eq!(idents(&extracted), vec![2, 3]);
+    }
+
+    #[test]
+    fn extract_without_session() {
+        let mut records = records();
+        records.retain(|record| record.ident != 1);
+
+        let extracted = extract(Selector::ReqUrl("/index".to_string()), &records);
+        assert_eq!(idents(&extracted), vec![3, 2, 4]);
+    }
+}
@@ -0,0 +1,344 @@
+// Follows growing file like `tail -F`.
+//
+// At the end of the file FollowReader waits for more data to be appended instead of returning
+// EOF. When the file is rotated (path now points to a file with different inode) or truncated
+// (e.g. logrotate copytruncate) FollowReader returns EOF once all the data of the old file was
+// read so that the reader of the stream can finish it (e.g. drop incomplete record); `reopen`
+// then opens the new file and following continues from its beginning.
+//
+// FollowPosition identifies the file and offset in it so that following can continue from where
+// it was stopped after restart unless the file was rotated or truncated in the meantime.
+use std::io::{self, Read, Seek, SeekFrom};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::MetadataExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use std::thread;
+use log::debug;
+use serde::{Serialize, Deserialize};
+
+// How long to wait for more data to be written
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Identity of the file; path points to new file after rotation
+fn file_id(metadata: &fs::Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Offset in the followed file identified by its device and inode numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FollowPosition {
+    pub dev: u64,
+    pub ino: u64,
+    pub offset: u64,
+}
+
+pub struct FollowReader {
+    path: PathBuf,
+    file: File,
+    file_id: (u64, u64),
+    position: u64,
+    rotated: bool,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl FollowReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FollowReader> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        let file_id = file_id(&file.metadata()?);
+
+        Ok(FollowReader {
+            path,
+            file,
+            file_id,
+            position: 0,
+            rotated: false,
+            stop: None,
+        })
+    }
+
+    /// Return EOF instead of waiting for more data once the flag is set (e.g. by signal handler)
+    pub fn set_stop_flag(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop.as_ref().is_some_and(|stop| stop.load(Ordering::Relaxed))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Position of the data to be read next
+    pub fn position(&self) -> FollowPosition {
+        FollowPosition {
+            dev: self.file_id.0,
+            ino: self.file_id.1,
+            offset: self.position,
+        }
+    }
+
+    /// Continue reading from given position if it is in the file being followed; false if the
+    /// file was rotated or truncated since then
+    pub fn seek(&mut self, position: &FollowPosition) -> io::Result<bool> {
+        if (position.dev, position.ino) != self.file_id || self.file.metadata()?.len() < position.offset {
+            return Ok(false)
+        }
+        self.position = self.file.seek(SeekFrom::Start(position.offset))?;
+        Ok(true)
+    }
+
+    /// True if the file was rotated or truncated and all its data was read
+    pub fn is_rotated(&self) -> bool {
+        self.rotated
+    }
+
+    /// Open the file that replaced the rotated one; waits for it to be created
+    pub fn reopen(&mut self) -> io::Result<()> {
+        loop {
+            match FollowReader::open(&self.path) {
+                Ok(reader) => {
+                    let stop = self.stop.take();
+                    *self = reader;
+                    self.stop = stop;
+                    return Ok(())
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => thread::sleep(POLL_INTERVAL),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn check_rotated(&self) -> io::Result<bool> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // moved away but new file was not created yet
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        if file_id(&metadata) != self.file_id {
+            debug!("File {} was rotated", self.path.display());
+            return Ok(true)
+        }
+        if metadata.len() < self.position {
+            debug!("File {} was truncated", self.path.display());
+            return Ok(true)
+        }
+        Ok(false)
+    }
+}
+
+/// Returns EOF only after the file was rotated
+impl Read for FollowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.rotated {
+            return Ok(0)
+        }
+
+        loop {
+            let bytes = self.file.read(buf)?;
+            if bytes > 0 {
+                self.position += bytes as u64;
+                return Ok(bytes)
+            }
+
+            if self.check_rotated()? {
+                // data may have been written to the old file just before rotation
+                let bytes = self.file.read(buf)?;
+                self.position += bytes as u64;
+                self.rotated = bytes == 0;
+                return Ok(bytes)
+            }
+            if self.is_stopped() {
+                return Ok(0)
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::fs::OpenOptions;
+    use crate::vsl::reader::VslReader;
+    use crate::access_log::session_state::SessionState;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("varnishslog-follow-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn append(path: &Path, data: &[u8]) {
+        OpenOptions::new().append(true).create(true).open(path).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn wait_for_appended_data() {
+        let path = temp_file("append");
+        append(&path, b"foo");
+
+        let mut reader = FollowReader::open(&path).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                append(&path, b"bar");
+            })
+        };
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[0..3], b"bar");
+        assert!(!reader.is_rotated());
+
+        writer.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stop_waiting_for_data() {
+        let path = temp_file("stop");
+        append(&path, b"foo");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut reader = FollowReader::open(&path).unwrap();
+        reader.set_stop_flag(stop.clone());
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(!reader.is_rotated());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn seek_to_position() {
+        let path = temp_file("seek");
+        append(&path, b"foobar");
+
+        let mut reader = FollowReader::open(&path).unwrap();
+        let mut buf = [0; 3];
+        reader.read_exact(&mut buf).unwrap();
+        let position = reader.position();
+        assert_eq!(position.offset, 3);
+
+        let mut reader = FollowReader::open(&path).unwrap();
+        assert!(reader.seek(&position).unwrap());
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bar");
+        assert_eq!(reader.position().offset, 6);
+
+        // replaced with shorter file
+        fs::remove_file(&path).unwrap();
+        append(&path, b"fo");
+        let mut reader = FollowReader::open(&path).unwrap();
+        assert!(!reader.seek(&position).unwrap());
+        assert_eq!(reader.position().offset, 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rotated_file() {
+        let path = temp_file("rotate");
+        let rotated = path.with_extension("1");
+        append(&path, b"foo");
+
+        let mut reader = FollowReader::open(&path).unwrap();
+        fs::rename(&path, &rotated).unwrap();
+        append(&rotated, b"bar");
+        append(&path, b"baz");
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"foobar");
+        assert!(reader.is_rotated());
+
+        reader.reopen().unwrap();
+        assert!(!reader.is_rotated());
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[0..3], b"baz");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    fn truncated_file() {
+        let path = temp_file("truncate");
+        append(&path, b"foobar");
+
+        let mut reader = FollowReader::open(&path).unwrap();
+        let mut data = [0; 16];
+        assert_eq!(reader.read(&mut data).unwrap(), 6);
+
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(0).unwrap();
+        append(&path, b"baz");
+        assert_eq!(reader.read(&mut data).unwrap(), 0);
+        assert!(reader.is_rotated());
+
+        reader.reopen().unwrap();
+        assert_eq!(reader.read(&mut data).unwrap(), 3);
+        assert_eq!(&data[0..3], b"baz");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resolve_transactions_across_rotation() {
+        let log = fs::read("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap();
+
+        // split the log in the middle at record boundary
+        let mut reader = VslReader::new(log.as_slice());
+        let mut offsets = Vec::new();
+        while reader.read_record().unwrap().is_some() {
+            offsets.push(reader.offset() as usize);
+        }
+        let split = offsets[offsets.len() / 2];
+
+        let path = temp_file("session");
+        let rotated = path.with_extension("1");
+        append(&path, &log[..split]);
+
+        let mut follow = FollowReader::open(&path).unwrap();
+        fs::rename(&path, &rotated).unwrap();
+        append(&path, b"VSL\0");
+        append(&path, &log[split..]);
+
+        let mut state = SessionState::new();
+        let mut client_records = 0;
+
+        // first file is read until rotation is detected
+        let mut reader = VslReader::new(&mut follow);
+        while let Some(record) = reader.read_record().unwrap() {
+            client_records += state.apply(&record).iter().count();
+        }
+        assert!(follow.is_rotated());
+
+        // second file with the VSL header is followed; stop once all of it was read
+        follow.reopen().unwrap();
+        let mut reader = VslReader::new(&mut follow);
+        while reader.offset() < (4 + log.len() - split) as u64 {
+            let record = reader.read_record().unwrap().unwrap();
+            client_records += state.apply(&record).iter().count();
+        }
+
+        assert_eq!(client_records, 8);
+        assert!(state.unresolved_client_access_records().is_empty());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -8,3 +8,10 @@ pub mod vsl;
 pub mod access_log;
 pub mod serde_types;
 pub mod serialization;
+pub mod sink;
+pub mod extract;
+pub mod follow;
+pub mod decompress;
+pub mod pipeline;
+pub mod shard;
+pub mod metrics;
@@ -1,23 +1,34 @@
-use std::io::{self, stdin, Read, Write};
-use std::fs::File;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::{stdin, BufReader, BufWriter, Read, Write};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::num::Wrapping;
 use std::time::Duration;
 use std::thread;
 
 use log::{error, warn, info};
-use quick_error::quick_error;
+use serde::{Serialize, Deserialize};
 use clap::{arg_enum, value_t, crate_authors, crate_version};
 
 use clap::{Arg, App};
 
-use varnishslog::stream_buf::{StreamBuf, ReadStreamBuf, FillError, FillApplyError};
-use varnishslog::vsl::record::VslRecord;
-use varnishslog::vsl::record::parser::{binary_vsl_tag, vsl_record_v4};
-use varnishslog::store::Config as StoreConfig;
-use varnishslog::access_log::session_state::SessionState;
-use varnishslog::access_log::record_state::RecordState;
-use varnishslog::serialization::{log_client_record, Config, Format, OutputError};
+use varnishslog::vsl::reader::{VslReader, VslReaderError};
+use varnishslog::stream_buf::{StreamBuf, MmapStreamBuf};
+use varnishslog::store::{Config as StoreConfig, log_time_epoch};
+use varnishslog::serialization::{Config, Format, OutputError};
+use varnishslog::vsl::text::{VslTextReader, VslTextReaderError};
+use varnishslog::vsl::vsm::{VsmReader, vsm_workdir};
+use varnishslog::follow::{FollowReader, FollowPosition};
+use varnishslog::decompress::{DecompressReader, Compression};
+use varnishslog::sink::{RecordSink, SinkError, ProcessingError, write_vsl_records, write_vsl_text_records, finish_sink};
+use varnishslog::sink::{LogSink, VslSink, LogDebugSink, RecordDebugSink, SessionDebugSink, SerdeSink};
+use varnishslog::extract::{ExtractSink, Selector};
+use varnishslog::pipeline::PipelineSink;
+use varnishslog::vsl::record::VslIdent;
+use varnishslog::vsl::record::tag_table::VarnishVersion;
+use varnishslog::access_log::session_state::SessionStateSnapshot;
+use varnishslog::metrics::{Metrics, MetricsSink, MeteredWriter, MetricsServer};
 
 mod program;
 
@@ -39,233 +50,181 @@ fn global_epoch(_epoch: Wrapping<u64>) -> Wrapping<u64> {
     Wrapping(EPOCH.load(Ordering::Relaxed) as u64)
 }
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum ProcessingError {
-        IO(err: io::Error) {
-            display("I/O error: IO Error while processing records: {}", err)
-            from()
-        }
-        InputBuffer(err: FillError) {
-            display("Input buffer error: Failed to fill parsing buffer: {}", err)
-        }
-        Parsing(err: String) {
-            display("Parser error: Failed to parse VSL record: {}", err)
-        }
-        Serialization(err: String) {
-            display("Serialization error: Failed to serialize log record: {}", err)
-        }
+fn to_exit_code(err: &ProcessingError) -> i32 {
+    match *err {
+        ProcessingError::Reading(VslReaderError::Io(_)) => 10,
+        ProcessingError::Reading(VslReaderError::InputBuffer(_)) => 11,
+        ProcessingError::Reading(_) => 20,
+        ProcessingError::TextReading(VslTextReaderError::Io(_)) => 10,
+        ProcessingError::TextReading(_) => 20,
+        ProcessingError::Sink(SinkError::Io(_)) |
+        ProcessingError::Sink(SinkError::Output(OutputError::Io(_))) => 10,
+        ProcessingError::Sink(_) => 30,
     }
 }
 
-impl<'b> From<FillApplyError<&'b[u8], u32>> for ProcessingError {
-    fn from(err: FillApplyError<&'b[u8], u32>) -> ProcessingError {
-        match err {
-            FillApplyError::FillError(FillError::Io(err)) => ProcessingError::IO(err),
-            FillApplyError::FillError(err) => ProcessingError::InputBuffer(err),
-            // we need to convert it to string due to input reference
-            FillApplyError::Parser(err) => ProcessingError::Parsing(format!("nom parser error: {}", err)),
-        }
+fn is_broken_pipe(err: &ProcessingError) -> bool {
+    match *err {
+        ProcessingError::Sink(ref err) => err.is_broken_pipe(),
+        _ => false
     }
 }
 
-impl From<OutputError> for ProcessingError {
-    fn from(err: OutputError) -> ProcessingError {
-        match err {
-            OutputError::Io(err) => ProcessingError::IO(err),
-            err => ProcessingError::Serialization(format!("Serialization error: {}", err)),
-        }
-    }
-}
+fn make_sink<W>(output: W, output_format: OutputFormat, config: Config, selector: Option<Selector>, store_config: &StoreConfig, correlation_shards: usize, serialization_threads: usize) -> Result<Box<dyn RecordSink>, SinkError> where W: Write + Send + 'static {
+    let pipeline = correlation_shards > 1 || serialization_threads > 0;
+    let sink: Box<dyn RecordSink> = match output_format {
+        OutputFormat::Json if pipeline => Box::new(PipelineSink::new(output, Format::Json, config, store_config, correlation_shards, serialization_threads)?),
+        OutputFormat::JsonPretty if pipeline => Box::new(PipelineSink::new(output, Format::JsonPretty, config, store_config, correlation_shards, serialization_threads)?),
+        OutputFormat::NcsaJson if pipeline => Box::new(PipelineSink::new(output, Format::NcsaJson, config, store_config, correlation_shards, serialization_threads)?),
+        OutputFormat::Log => Box::new(LogSink::new(output)),
+        OutputFormat::LogDebug => Box::new(LogDebugSink::new(output)),
+        OutputFormat::RecordDebug => Box::new(RecordDebugSink::new(output, store_config)),
+        OutputFormat::SessionDebug => Box::new(SessionDebugSink::new(output, store_config)),
+        OutputFormat::Vsl => Box::new(VslSink::new(output)?),
+        OutputFormat::Json => Box::new(SerdeSink::new(output, Format::Json, config, store_config)),
+        OutputFormat::JsonPretty => Box::new(SerdeSink::new(output, Format::JsonPretty, config, store_config)),
+        OutputFormat::NcsaJson => Box::new(SerdeSink::new(output, Format::NcsaJson, config, store_config)),
+    };
 
-impl ProcessingError {
-    fn to_exit_code(&self) -> i32 {
-        match *self {
-            ProcessingError::IO(_) => 10,
-            ProcessingError::InputBuffer(_) => 11,
-            ProcessingError::Parsing(_) => 20,
-            ProcessingError::Serialization(_) => 30,
-        }
-    }
+    Ok(match selector {
+        Some(selector) => Box::new(ExtractSink::with_config(sink, selector, store_config)),
+        None => sink,
+    })
 }
 
-impl ProcessingError {
-    fn is_brokend_pipe(&self) -> bool {
-        match *self {
-            ProcessingError::IO(ref err) =>
-                err.kind() == io::ErrorKind::UnexpectedEof || err.kind() == io::ErrorKind::BrokenPipe,
-            _ => false
-        }
-    }
+// Correlation state with position in followed VSL file its records were read up to
+#[derive(Serialize, Deserialize)]
+struct State {
+    follow: Option<FollowPosition>,
+    session: SessionStateSnapshot,
 }
 
-fn try_read_vsl_tag<R: Read>(stream: &mut ReadStreamBuf<R>) -> Result<(), ProcessingError> {
-    loop {
-        match stream.fill_apply(binary_vsl_tag)? {
-            None => continue,
-            Some(Some(_)) => {
-                info!("Found VSL tag");
-                break
-            }
-            Some(_) => break,
-        }
+// State file is kept until it is replaced on SIGTERM or removed at the end of the stream so that
+// the state is not lost if processing fails to start
+fn restore_state<S>(path: &Path, sink: &mut S) -> Option<FollowPosition> where S: RecordSink + ?Sized {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => program::exit_with_error(&format!("Failed to open state file: {}: {}", path.display(), err), 1),
+    };
+
+    let state: State = match serde_json::from_reader(BufReader::new(file)) {
+        Ok(state) => state,
+        Err(err) => program::exit_with_error(&format!("Failed to load state file: {}: {}", path.display(), err), 1),
+    };
+    if !sink.restore_state(state.session) {
+        program::exit_with_error("Correlation state cannot be restored with this output format", 1)
     }
-    Ok(())
-}
 
-trait WriteRecord {
-    fn write_record<W>(&mut self, record: VslRecord<'_>, output: &mut W) -> Result<(), ProcessingError> where W: Write;
-    fn log_reports(&self) {}
+    let stats = sink.stats();
+    info!("Restored correlation state from {}: unresolved: {}", path.display(), stats.unresolved);
+    state.follow
 }
 
-fn process_vsl_records<R, W, P>(stream: &mut ReadStreamBuf<R>, mut writer: P, output: &mut W) -> Result<(), ProcessingError> where R: Read, W: Write, P: WriteRecord {
-    loop {
-        match stream.fill_apply(vsl_record_v4).map_err(ProcessingError::from) {
-            Ok(None) => continue,
-            Ok(Some(record)) => writer.write_record(record, output)?,
-            Err(err) => {
-                //TODO: need better tracking of orphan records and other stats
-                if err.is_brokend_pipe() {
-                    writer.log_reports();
-                }
-                return Err(err)
-            }
+// Written to temporary file first so that the old state file is not left half written
+fn save_state<S>(path: &Path, follow: Option<FollowPosition>, sink: &mut S) -> Result<(), std::io::Error> where S: RecordSink + ?Sized {
+    let session = match sink.take_state() {
+        Some(session) => session,
+        None => {
+            warn!("Correlation state cannot be saved with this output format");
+            return Ok(())
         }
-    }
-}
-
-fn process_vsl_stream<R, W>(input: R, mut output: W, stream_buf_size: usize, output_format: OutputFormat, config: Config, store_config: &StoreConfig) -> Result<(), ProcessingError> where R: Read, W: Write {
-    //TODO: make buffer size configurable
-    let mut stream = ReadStreamBuf::with_capacity(input, stream_buf_size);
+    };
 
-    try_read_vsl_tag(&mut stream)?;
+    let tmp_path = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut file, &State { follow, session })?;
+    file.flush()?;
+    file.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
 
-    match output_format {
-        OutputFormat::Log => process_vsl_records(&mut stream, LogWriter::default(), &mut output),
-        OutputFormat::LogDebug => process_vsl_records(&mut stream, LogDebugWriter::default(), &mut output),
-        OutputFormat::RecordDebug => process_vsl_records(&mut stream, RecordDebugWriter::new(store_config), &mut output),
-        OutputFormat::SessionDebug => process_vsl_records(&mut stream, SessionDebugWriter::new(store_config), &mut output),
-        OutputFormat::Json => process_vsl_records(&mut stream, SerdeWriter::new(Format::Json, config, store_config), &mut output),
-        OutputFormat::JsonPretty => process_vsl_records(&mut stream, SerdeWriter::new(Format::JsonPretty, config, store_config), &mut output),
-        OutputFormat::NcsaJson => process_vsl_records(&mut stream, SerdeWriter::new(Format::NcsaJson, config, store_config), &mut output),
-    }
+    info!("Saved correlation state to {}", path.display());
+    Ok(())
 }
 
-#[derive(Default)]
-struct LogWriter;
-impl WriteRecord for LogWriter {
-    fn write_record<W>(&mut self, record: VslRecord<'_>, output: &mut W) -> Result<(), ProcessingError> where W: Write {
-        writeln!(output, "{:#}", record).map_err(From::from)
+// Writes records of the stream to the sink; sink is not finished as more streams may follow.
+// Returns number of input bytes the records written were read from if it is known (uncompressed
+// VSL stream)
+fn write_vsl_stream<R, S>(input: R, input_format: InputFormat, stream_buf_size: usize, resync: Option<usize>, varnish_version: Option<VarnishVersion>, stop: Option<&Arc<AtomicBool>>, sink: &mut S) -> Result<Option<u64>, ProcessingError> where R: Read, S: RecordSink + ?Sized {
+    let input = DecompressReader::with_capacity(input, stream_buf_size).map_err(|err| match input_format {
+        InputFormat::Vsl => ProcessingError::from(VslReaderError::Io(err)),
+        InputFormat::Text => ProcessingError::from(VslTextReaderError::Io(err)),
+    })?;
+    if input.compression() != Compression::None {
+        info!("Decompressing {} compressed input", input.compression());
     }
-}
 
-#[derive(Default)]
-struct LogDebugWriter;
-impl WriteRecord for LogDebugWriter {
-    fn write_record<W>(&mut self, record: VslRecord<'_>, output: &mut W) -> Result<(), ProcessingError> where W: Write {
-        writeln!(output, "{:#?}", record).map_err(From::from)
+    let compressed = input.compression() != Compression::None;
+    match input_format {
+        InputFormat::Vsl => write_vsl_reader(VslReader::with_capacity(input, stream_buf_size), resync, varnish_version, stop, sink)
+            .map(|offset| if compressed { None } else { Some(offset) }),
+        InputFormat::Text => write_vsl_text_records(&mut VslTextReader::new(BufReader::with_capacity(stream_buf_size, input)), sink)
+            .map(|()| None),
     }
 }
 
-struct RecordDebugWriter {
-    state: RecordState,
-}
-
-impl RecordDebugWriter {
-    fn new(store_config: &StoreConfig) -> RecordDebugWriter {
-        RecordDebugWriter {
-            state: RecordState::with_config(store_config)
-        }
+// Returns stream offset the reader stopped at
+fn write_vsl_reader<B, S>(mut reader: VslReader<B>, resync: Option<usize>, varnish_version: Option<VarnishVersion>, stop: Option<&Arc<AtomicBool>>, sink: &mut S) -> Result<u64, ProcessingError> where B: StreamBuf<u8>, S: RecordSink + ?Sized {
+    if let Some(max_record_len) = resync {
+        reader.enable_resync();
+        reader.set_max_record_len(max_record_len);
     }
-}
-
-impl WriteRecord for RecordDebugWriter {
-    fn write_record<W>(&mut self, record: VslRecord<'_>, output: &mut W) -> Result<(), ProcessingError> where W: Write {
-        if let Some(record) = self.state.apply(&record) {
-            writeln!(output, "{:#?}", record).map_err(From::from)
-        } else {
-            Ok(())
-        }
+    if let Some(version) = varnish_version {
+        reader.set_varnish_version(version);
+    }
+    if let Some(stop) = stop {
+        reader.set_stop_flag(stop.clone());
     }
-}
 
-struct SessionDebugWriter {
-    state: SessionState,
-}
+    let result = write_vsl_records(&mut reader, sink);
 
-impl SessionDebugWriter {
-    fn new(store_config: &StoreConfig) -> SessionDebugWriter {
-        SessionDebugWriter {
-            state: SessionState::with_config(store_config)
-        }
+    let stats = reader.stats();
+    if stats.resyncs > 0 || stats.truncated_bytes > 0 {
+        warn!("VSL stream was corrupted or truncated; {}", stats);
+    } else {
+        info!("VSL stream stats: {}", stats);
     }
+    result.map(|()| reader.offset())
 }
 
-impl WriteRecord for SessionDebugWriter {
-    fn write_record<W>(&mut self, record: VslRecord<'_>, output: &mut W) -> Result<(), ProcessingError> where W: Write {
-        if let Some(record) = self.state.apply(&record) {
-            writeln!(output, "{:#?}", record).map_err(From::from)
-        } else {
-            Ok(())
-        }
+// Tag numbering is detected from the first transaction record so when following continues from the
+// middle of the file it is detected from its beginning
+fn detect_varnish_version(path: &str) -> Option<VarnishVersion> {
+    let mut reader = VslReader::new(File::open(path).ok()?);
+    while reader.tag_table().is_none() {
+        reader.read_record().ok()??;
     }
+    reader.tag_table().map(|tag_table| tag_table.version())
 }
 
-struct SerdeWriter {
-    state: SessionState,
-    format: Format,
-    config: Config,
-}
-
-impl SerdeWriter {
-    fn new(format: Format, config: Config, store_config: &StoreConfig) -> SerdeWriter {
-        SerdeWriter {
-            state: SessionState::with_config(store_config),
-            format: format,
-            config: config,
-        }
-    }
-}
+// Uncompressed regular VSL files are memory mapped and parsed in place so stream buffer size does
+// not apply; they must not be truncated while being processed (the process would get SIGBUS)
+fn write_vsl_file<S>(path: &str, input_format: InputFormat, stream_buf_size: usize, resync: Option<usize>, varnish_version: Option<VarnishVersion>, sink: &mut S) -> Result<(), ProcessingError> where S: RecordSink + ?Sized {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => program::exit_with_error(&format!("Failed to open VSL file: {}: {}", path, err), 1),
+    };
 
-impl WriteRecord for SerdeWriter {
-    fn write_record<W>(&mut self, record: VslRecord<'_>, output: &mut W) -> Result<(), ProcessingError> where W: Write {
-        if let Some(client) = self.state.apply(&record) {
-            log_client_record(&client, &self.format, output, &self.config).map_err(From::from)
-        } else {
-            Ok(())
+    // FIFOs, process substitutions and the like cannot be mapped
+    let is_file = file.metadata().map(|metadata| metadata.is_file()).unwrap_or(false);
+    if let (InputFormat::Vsl, true) = (input_format, is_file) {
+        let stream = match MmapStreamBuf::from_file(&file) {
+            Ok(stream) => stream,
+            Err(err) => program::exit_with_error(&format!("Failed to map VSL file: {}: {}", path, err), 1),
+        };
+        if Compression::detect(stream.data()) == Compression::None {
+            return write_vsl_reader(VslReader::from_stream_buf(stream), resync, varnish_version, None, sink).map(|_| ())
         }
     }
 
-    fn log_reports(&self) {
-        let mut all_finished = true;
-        for client in self.state.unresolved_root_client_access_records() {
-            all_finished = false;
-            warn!("Root ClientAccessRecord left unresolved:\n{:#?}", client)
-        }
-
-        for client in self.state.unresolved_client_access_records() {
-            all_finished = false;
-            warn!("ClientAccessRecord left unresolved:\n{:#?}", client)
-        }
-
-        for backend in self.state.unresolved_backend_access_records() {
-            all_finished = false;
-            warn!("BackendAccessRecord left unresolved:\n{:#?}", backend)
-        }
-
-        for builder in self.state.unresolved_record_builders_records() {
-            all_finished = false;
-            warn!("RecordBuilder left unfinished:\n{:#?}", builder)
-        }
-
-        for session in self.state.unresolved_record_sessions_records() {
-            all_finished = false;
-            warn!("SessionHead left unfinished:\n{:#?}", session.borrow())
-        }
+    write_vsl_stream(file, input_format, stream_buf_size, resync, varnish_version, None, sink).map(|_| ())
+}
 
-        if all_finished {
-            info!("No unresolved or unfinished records left")
-        }
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum InputFormat {
+        Vsl,
+        Text
     }
 }
 
@@ -276,12 +235,42 @@ arg_enum! {
         LogDebug,
         RecordDebug,
         SessionDebug,
+        Vsl,
         Json,
         JsonPretty,
         NcsaJson
     }
 }
 
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum EpochSource {
+        WallClock,
+        LogTime
+    }
+}
+
+fn validate_extract_vxid(value: String) -> Result<(), String> {
+    value.parse::<VslIdent>().map(|_| ()).map_err(|_| format!("extract-vxid expected to be an integer; got: {:?}", value))
+}
+
+fn validate_varnish_version(value: String) -> Result<(), String> {
+    match value.parse::<VarnishVersion>() {
+        // there are no tag tables of Varnish 7 releases
+        Ok(version) if version.major >= 7 => Err(format!("VSL of Varnish {} is not supported; supported releases are up to 6.6", version)),
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn validate_extract_header(value: String) -> Result<(), String> {
+    if value.contains(':') {
+        Ok(())
+    } else {
+        Err(format!("extract-header expected to be in NAME: VALUE format; got: {:?}", value))
+    }
+}
+
 fn validate_max_record_slots(value: String) -> Result<(), String> {
     let v = value.parse::<usize>().map_err(|_| format!("max-record-slots expected to be an integer; got: {:?}", value))?;
 
@@ -322,6 +311,54 @@ fn validate_stat_epoch_inverval(value: String) -> Result<(), String> {
     }
 }
 
+// Number of bytes with optional K, M or G (binary) unit suffix
+fn parse_bytes(value: &str) -> Option<usize> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    number.parse::<usize>().ok().and_then(|number| number.checked_mul(unit))
+}
+
+fn validate_max_memory(value: String) -> Result<(), String> {
+    let v = parse_bytes(&value).ok_or_else(|| format!("max-memory expected to be a number of bytes (with optional K, M or G suffix); got: {:?}", value))?;
+
+    if v == 0 {
+        Err(format!("max-memory must be greater than zero; got: {}", v))
+    } else {
+        Ok(())
+    }
+}
+
+// Arguments containing glob characters are expanded to matching files in alphabetical order
+fn expand_vsl_file_patterns<'a>(patterns: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(pattern.to_string());
+            continue
+        }
+
+        let matches = match glob::glob(pattern) {
+            Ok(matches) => matches,
+            Err(err) => program::exit_with_error(&format!("Bad VSL file pattern: {}: {}", pattern, err), 1),
+        };
+        let count = paths.len();
+        for path in matches {
+            match path {
+                Ok(path) => paths.push(path.to_string_lossy().into_owned()),
+                Err(err) => program::exit_with_error(&format!("Failed to read VSL file: {}", err), 1),
+            }
+        }
+        if paths.len() == count {
+            program::exit_with_error(&format!("No VSL files matching: {}", pattern), 1)
+        }
+    }
+    paths
+}
+
 fn main() {
     let arguments = App::new("Varnish Structured Logger")
         .version(crate_version!())
@@ -334,6 +371,29 @@ fn main() {
              .help("Logging level specification, e.g: info")
              .takes_value(true)
              .default_value("info"))
+        .arg(Arg::with_name("input")
+             .long("input-format")
+             .short("f")
+             .help("Format of the input: binary VSL or varnishlog text output")
+             .takes_value(true)
+             .possible_values(&InputFormat::variants())
+             .default_value(InputFormat::variants().first().unwrap()))
+        .arg(Arg::with_name("resync")
+             .long("resync")
+             .help("Skip corrupted VSL data to next valid looking record instead of failing"))
+        .arg(Arg::with_name("max-record-length")
+             .long("max-record-length")
+             .value_name("BYTES")
+             .display_order(2000)
+             .help("Longest VSL record data not considered corrupted by --resync; set to vsl_reclen parameter of varnishd if it was raised over the default maximum")
+             .takes_value(true)
+             .default_value("4084"))
+        .arg(Arg::with_name("varnish-version")
+             .long("varnish-version")
+             .value_name("MAJOR.MINOR")
+             .help("Varnish version that wrote the VSL stream for VSL tag numbering (detected if not specified)")
+             .takes_value(true)
+             .validator(validate_varnish_version))
         .arg(Arg::with_name("output")
              .long("output-format")
              .short("o")
@@ -357,6 +417,15 @@ fn main() {
              .long("keep-raw-headers")
              .short("I")
              .help("Keep raw header name/value pairs; any indices are moved to top level"))
+        .arg(Arg::with_name("partial-records")
+             .long("partial-records")
+             .help("Output client requests that expired before they were resolved with \"partial\": true and vxids of their unresolved links (JSON output formats only)"))
+        .arg(Arg::with_name("flush-on-eof")
+             .long("flush-on-eof")
+             .help("Output client requests left unresolved at the end of the input as partial records with records linked so far and log summary of what was incomplete (JSON output formats only)"))
+        .arg(Arg::with_name("stats-records")
+             .long("stats-records")
+             .help("Output store stats and parsing throughput as \"varnishslog_stats\" records every stat epoch interval and at the end of the input (JSON output formats only)"))
         .arg(Arg::with_name("stat-epoch-interval")
              .long("stat-epoch-interval")
              .short("s")
@@ -367,14 +436,39 @@ fn main() {
         .arg(Arg::with_name("stream-buffer-size")
              .long("stream-buffer-size")
              .display_order(2000)
-             .help("Size of stream buffer in bytes - must be bigger than biggest VSL record")
+             .help("Size of stream buffer in bytes - must be bigger than biggest VSL record; not used for uncompressed regular VSL files as they are memory mapped (they must not be truncated while processed)")
              .default_value("262144"))
+        .arg(Arg::with_name("serialization-threads")
+             .long("serialization-threads")
+             .display_order(2000)
+             .value_name("THREADS")
+             .help("Correlate, serialize and write out records on separate threads using that many serialization threads (JSON output formats only); 0 processes records on a single thread")
+             .default_value("0"))
+        .arg(Arg::with_name("correlation-shards")
+             .long("correlation-shards")
+             .display_order(2000)
+             .value_name("SHARDS")
+             .help("Correlate records of different sessions on that many threads (JSON output formats only); implies at least one serialization thread")
+             .default_value("1"))
         .arg(Arg::with_name("max-record-slots")
              .long("max-record-slots")
              .display_order(2000)
              .help("Maximum number of records being built or correlated at the same time")
              .validator(validate_max_record_slots)
              .default_value("4000"))
+        .arg(Arg::with_name("epoch-source")
+             .long("epoch-source")
+             .display_order(2000)
+             .help("Measure epoch in seconds of wall clock or of SLT_Timestamp time of the log records; defaults to LogTime when reading VSL files (unless followed) and WallClock otherwise")
+             .takes_value(true)
+             .possible_values(&EpochSource::variants()))
+        .arg(Arg::with_name("max-memory")
+             .long("max-memory")
+             .display_order(2000)
+             .value_name("BYTES")
+             .help("Limit approximate memory used by records being built or correlated (e.g. 512M); oldest records are removed when over the limit")
+             .takes_value(true)
+             .validator(validate_max_memory))
         .arg(Arg::with_name("max-epoch-diff")
              .long("max-epoch-diff")
              .display_order(2000)
@@ -387,23 +481,103 @@ fn main() {
              .display_order(2000)
              .validator(validate_evict_factor)
              .default_value("0.01"))
+        .arg(Arg::with_name("state-file")
+             .long("state-file")
+             .display_order(2000)
+             .value_name("PATH")
+             .help("Save records still being built or correlated to that file on SIGTERM and restore them from it on start (with -n or --follow only; not with --serialization-threads or --correlation-shards)")
+             .takes_value(true))
+        .arg(Arg::with_name("metrics-listen")
+             .long("metrics-listen")
+             .display_order(2000)
+             .value_name("ADDRESS")
+             .help("Serve Prometheus metrics on http://ADDRESS/metrics (e.g. 127.0.0.1:9133)")
+             .takes_value(true))
+        .arg(Arg::with_name("extract-vxid")
+             .long("extract-vxid")
+             .value_name("VXID")
+             .help("Only output records of transaction with given vxid and transactions linked from it")
+             .takes_value(true)
+             .validator(validate_extract_vxid)
+             .conflicts_with_all(&["extract-url", "extract-header"]))
+        .arg(Arg::with_name("extract-url")
+             .long("extract-url")
+             .value_name("URL")
+             .help("Only output records of sessions with request URL containing given string")
+             .takes_value(true)
+             .conflicts_with("extract-header"))
+        .arg(Arg::with_name("extract-header")
+             .long("extract-header")
+             .value_name("NAME: VALUE")
+             .help("Only output records of sessions with request header of given name with value containing given string")
+             .takes_value(true)
+             .validator(validate_extract_header))
+        .arg(Arg::with_name("vsm-name")
+             .long("name")
+             .short("n")
+             .value_name("NAME")
+             .help("Read VSL records from shared memory log of varnishd with given instance name or working directory")
+             .takes_value(true)
+             .conflicts_with("vsl-file"))
+        .arg(Arg::with_name("vsm-dump")
+             .long("dump")
+             .help("Read records already in the shared memory log and exit instead of waiting for new records")
+             .requires("vsm-name"))
+        .arg(Arg::with_name("follow")
+             .long("follow")
+             .short("F")
+             .help("Wait for more data at the end of VSL_FILE and reopen it when it is rotated or truncated")
+             .requires("vsl-file"))
         .arg(Arg::with_name("vsl-file")
              .value_name("VSL_FILE")
-             .help("VSL file to process (read from standard input if not specified)"))
+             .multiple(true)
+             .help("VSL files or glob patterns of VSL files to process in order as one stream (read from standard input if not specified)"))
         .get_matches();
 
     program::init(arguments.value_of("log-spec"));
 
+    let input_format = value_t!(arguments, "input", InputFormat).unwrap_or_else(|e| e.exit());
     let output_format = value_t!(arguments, "output", OutputFormat).unwrap_or_else(|e| e.exit());
     let stream_buf_size = value_t!(arguments, "stream-buffer-size", usize).unwrap_or_else(|e| e.exit());
+    let max_record_length = value_t!(arguments, "max-record-length", usize).unwrap_or_else(|e| e.exit());
+    let resync = if arguments.is_present("resync") { Some(max_record_length) } else { None };
+    let varnish_version = arguments.value_of("varnish-version").map(|version| version.parse().unwrap());
 
     let output = std::io::stdout();
 
+    let metrics = arguments.value_of("metrics-listen").map(|_| Arc::new(Metrics::new()));
+
+    let epoch_source = if arguments.is_present("epoch-source") {
+        value_t!(arguments, "epoch-source", EpochSource).unwrap_or_else(|e| e.exit())
+    } else if arguments.is_present("vsl-file") && !arguments.is_present("follow") {
+        EpochSource::LogTime
+    } else {
+        EpochSource::WallClock
+    };
+
     let config = Config {
         no_log_processing: arguments.is_present("no-log-processing"),
         keep_raw_log: arguments.is_present("keep-raw-log"),
         no_header_indexing: arguments.is_present("no-header-indexing"),
         keep_raw_headers: arguments.is_present("keep-raw-headers"),
+        partial_records: arguments.is_present("partial-records"),
+        flush_on_eof: arguments.is_present("flush-on-eof"),
+        stats_records: arguments.is_present("stats-records"),
+        stats_log_time: matches!(epoch_source, EpochSource::LogTime),
+        metrics: metrics.clone(),
+    };
+
+    let selector = if let Some(vxid) = arguments.value_of("extract-vxid") {
+        Some(Selector::Vxid(vxid.parse().unwrap()))
+    } else if let Some(url) = arguments.value_of("extract-url") {
+        Some(Selector::ReqUrl(url.to_string()))
+    } else if let Some(header) = arguments.value_of("extract-header") {
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap().trim().to_string();
+        let value = parts.next().unwrap().trim().to_string();
+        Some(Selector::ReqHeader(name, value))
+    } else {
+        None
     };
 
     let stat_epoch_interval = if arguments.is_present("stat-epoch-interval") {
@@ -412,34 +586,185 @@ fn main() {
         None
     };
 
-    let _epoch_timer = spawn_epoch_timer();
+    let epoch_source: fn(Wrapping<u64>) -> Wrapping<u64> = match epoch_source {
+        EpochSource::WallClock => {
+            spawn_epoch_timer();
+            global_epoch
+        }
+        EpochSource::LogTime => log_time_epoch,
+    };
 
-    let store_config = StoreConfig::new(
+    let mut store_config = StoreConfig::new(
         value_t!(arguments, "max-record-slots", usize).unwrap_or_else(|e| e.exit()),
         value_t!(arguments, "max-epoch-diff", u64).unwrap_or_else(|e| e.exit()),
         value_t!(arguments, "evict-factor", f32).unwrap_or_else(|e| e.exit()),
         stat_epoch_interval,
-        Some(global_epoch)
+        Some(epoch_source)
     ).unwrap();
+    store_config.set_max_memory(arguments.value_of("max-memory").and_then(parse_bytes)).unwrap();
+    store_config.set_metrics(metrics.clone());
 
-    let result = if let Some(path) = arguments.value_of("vsl-file") {
-        let file = File::open(path);
-        match file {
-            Ok(file) => process_vsl_stream(file, output, stream_buf_size, output_format, config, &store_config),
-            Err(err) => program::exit_with_error(&format!("Failed to open VSL file: {}: {}", path, err), 1),
+    let correlation_shards = value_t!(arguments, "correlation-shards", usize).unwrap_or_else(|e| e.exit());
+    let serialization_threads = value_t!(arguments, "serialization-threads", usize).unwrap_or_else(|e| e.exit());
+    if (correlation_shards > 1 || serialization_threads > 0) && !matches!(output_format, OutputFormat::Json | OutputFormat::JsonPretty | OutputFormat::NcsaJson) {
+        program::exit_with_error("--serialization-threads and --correlation-shards can only be used with JSON output formats", 1)
+    }
+    // correlation state is spread over the pipeline threads
+    if arguments.is_present("state-file") && (correlation_shards > 1 || serialization_threads > 0) {
+        program::exit_with_error("State file cannot be used with --serialization-threads or --correlation-shards", 1)
+    }
+
+    if let Some(ref metrics) = metrics {
+        let addr = arguments.value_of("metrics-listen").unwrap();
+        match MetricsServer::start(addr, metrics.clone()) {
+            Ok(server) => info!("Serving metrics on http://{}/metrics", server.local_addr()),
+            Err(err) => program::exit_with_error(&format!("Failed to listen for metrics requests on {}: {}", addr, err), 1),
+        }
+    }
+
+    let sink = match metrics {
+        Some(ref metrics) => make_sink(MeteredWriter::new(output, metrics.clone()), output_format, config, selector, &store_config, correlation_shards, serialization_threads)
+            .map(|sink| Box::new(MetricsSink::new(sink, metrics.clone())) as Box<dyn RecordSink>),
+        None => make_sink(output, output_format, config, selector, &store_config, correlation_shards, serialization_threads),
+    };
+    let mut sink = match sink {
+        Ok(sink) => sink,
+        Err(err) => program::exit_with_error(&format!("Failed to initialize output: {}", err), 10),
+    };
+
+    // reading stops before the next VSL record once stopped so the state can be saved; text input
+    // would only stop once no more data is available so it is not supported
+    let state_file = arguments.value_of("state-file").map(Path::new);
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut restored_position = None;
+    let mut follow_position = None;
+    if let Some(path) = state_file {
+        if !(arguments.is_present("vsm-name") || arguments.is_present("follow")) {
+            program::exit_with_error("State file can only be used with shared memory log (-n) or followed VSL file (--follow)", 1)
+        }
+        if let InputFormat::Text = input_format {
+            program::exit_with_error("State file can only be used with Vsl input format", 1)
+        }
+        restored_position = restore_state(path, &mut sink);
+        if let Err(err) = signal_hook::flag::register(signal_hook::consts::SIGTERM, stop.clone()) {
+            program::exit_with_error(&format!("Failed to register SIGTERM handler: {}", err), 1)
+        }
+    }
+
+    let result = if let Some(name) = arguments.value_of("vsm-name") {
+        if let InputFormat::Text = input_format {
+            program::exit_with_error("Shared memory log can only be read in Vsl input format", 1)
+        }
+
+        let mut vsm = match VsmReader::open(vsm_workdir(name)) {
+            Ok(vsm) => vsm,
+            Err(err) => program::exit_with_error(&format!("Failed to open shared memory log: {}", err), 1),
+        };
+        if arguments.is_present("vsm-dump") {
+            vsm.enable_dump();
+        }
+        vsm.set_stop_flag(stop.clone());
+
+        let result = write_vsl_stream(&mut vsm, input_format, stream_buf_size, resync, varnish_version, None, &mut sink).map(|_| ());
+
+        let stats = vsm.stats();
+        if stats.overruns > 0 {
+            warn!("Shared memory log was overrun; {}", stats);
+        } else {
+            info!("Shared memory log stats: {}", stats);
+        }
+        result
+    } else if let Some(patterns) = arguments.values_of("vsl-file") {
+        let paths = expand_vsl_file_patterns(patterns);
+
+        if arguments.is_present("follow") {
+            if paths.len() > 1 {
+                program::exit_with_error("Only one VSL file can be followed", 1)
+            }
+            let path = &paths[0];
+            let mut follow = match FollowReader::open(path) {
+                Ok(follow) => follow,
+                Err(err) => program::exit_with_error(&format!("Failed to open VSL file: {}: {}", path, err), 1),
+            };
+            follow.set_stop_flag(stop.clone());
+            let mut resumed_version = None;
+            if let Some(position) = restored_position {
+                match follow.seek(&position) {
+                    Ok(true) => {
+                        info!("Continuing to follow VSL file {} from offset {}", path, position.offset);
+                        resumed_version = varnish_version.or_else(|| detect_varnish_version(path));
+                    }
+                    Ok(false) => info!("VSL file {} was rotated or truncated since the state was saved; following it from the beginning", path),
+                    Err(err) => program::exit_with_error(&format!("Failed to seek VSL file: {}: {}", path, err), 1),
+                }
+            }
+
+            // records of rotated files are written to the same sink so its state is kept
+            loop {
+                let start = follow.position();
+                let varnish_version = resumed_version.take().or(varnish_version);
+                match write_vsl_stream(&mut follow, input_format, stream_buf_size, resync, varnish_version, Some(&stop), &mut sink) {
+                    Ok(offset) => follow_position = offset.map(|offset| FollowPosition { offset: start.offset + offset, ..start }),
+                    Err(err) => break Err(err),
+                }
+                if stop.load(Ordering::Relaxed) {
+                    break Ok(())
+                }
+                info!("VSL file {} was rotated; reopening", follow.path().display());
+                if let Err(err) = follow.reopen() {
+                    break Err(VslReaderError::Io(err).into())
+                }
+            }
+        } else {
+            // files are written to the same sink so transactions spanning files are resolved
+            let mut result = Ok(());
+            for path in &paths {
+                let before = sink.stats();
+                result = write_vsl_file(path, input_format, stream_buf_size, resync, varnish_version, &mut sink)
+                    .and_then(|()| sink.flush().map_err(From::from));
+                if result.is_err() {
+                    break
+                }
+
+                // unresolved and tombstoned transactions are held across files so their change is logged
+                let stats = sink.stats();
+                info!("VSL file {}: records emitted: {} unresolved: {:+} tombstoned: {:+}",
+                      path, stats.emitted - before.emitted,
+                      stats.unresolved as i64 - before.unresolved as i64,
+                      stats.tombstoned as i64 - before.tombstoned as i64);
+            }
+            result
         }
     } else {
         let stdin = stdin();
         let stdin = stdin.lock();
-        process_vsl_stream(stdin, output, stream_buf_size, output_format, config, &store_config)
+        write_vsl_stream(stdin, input_format, stream_buf_size, resync, varnish_version, None, &mut sink).map(|_| ())
     };
 
+    if result.is_ok() {
+        if let Some(path) = state_file {
+            let result = if stop.load(Ordering::Relaxed) {
+                if arguments.is_present("follow") && follow_position.is_none() {
+                    warn!("Position in compressed VSL file cannot be saved; it will be followed from the beginning on restart");
+                }
+                save_state(path, follow_position, &mut sink)
+            } else {
+                // records left are reported by the sink
+                fs::remove_file(path).or_else(|err| if err.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(err) })
+            };
+            if let Err(err) = result {
+                error!("Failed to update state file {}: {}", path.display(), err);
+            }
+        }
+    }
+    let result = finish_sink(result, &mut sink);
+
     if let Err(err) = result {
-        if err.is_brokend_pipe() {
+        if is_broken_pipe(&err) {
             info!("Broken pipe")
         } else {
             error!("{}", err);
-            program::exit_with_error(&err.to_string(), err.to_exit_code())
+            program::exit_with_error(&err.to_string(), to_exit_code(&err))
         }
     }
 
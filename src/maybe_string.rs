@@ -1,6 +1,7 @@
 use std::str::from_utf8;
 use std::fmt::{self, Debug, Display};
 use std::ops::Deref;
+use serde::{Serialize, Deserialize};
 
 #[derive(PartialEq)]
 pub struct MaybeStr([u8]);
@@ -50,7 +51,7 @@ impl Deref for MaybeStr {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Serialize, Deserialize)]
 pub struct MaybeString(pub Vec<u8>);
 
 impl MaybeString {
@@ -0,0 +1,380 @@
+// Prometheus metrics of the running processor.
+//
+// Metrics are shared by all the stages that process records: MetricsSink counts VSL records read
+// before passing them on to the sink it wraps, stores count records they expire or nuke (see
+// `store::Config::set_metrics`), RecordState counts transactions that failed to build and
+// serialization counts access records it writes out (see `serialization::Config::metrics`).
+// Counters are atomic or behind mutex so they can be updated from pipeline threads.
+//
+// MetricsServer serves the metrics in Prometheus text exposition format on its own thread; it is
+// a minimal HTTP/1.1 server answering `GET /metrics` requests one at a time.
+use std::io::{self, Write, BufRead, BufReader};
+use std::fmt::Write as FmtWrite;
+use std::collections::BTreeMap;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::debug;
+
+use crate::sink::{RecordSink, SinkError, SinkStats};
+use crate::access_log::session_state::SessionStateSnapshot;
+use crate::vsl::record::VslRecord;
+use crate::vsl::record::tag_table::TagTable;
+
+// How long to wait for the client to send the request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Counts by label value
+type Counts = Mutex<BTreeMap<&'static str, u64>>;
+
+fn increment(counts: &Counts, label: &'static str) {
+    *counts.lock().expect("metrics lock poisoned").entry(label).or_insert(0) += 1;
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    // by tag number
+    records_read: Vec<AtomicU64>,
+    // by record type and handling
+    records_emitted: Mutex<BTreeMap<&'static str, BTreeMap<String, u64>>>,
+    // by store name
+    store_expired: Counts,
+    store_nuked: Counts,
+    // by RecordBuilderError variant
+    tombstones: Counts,
+    serialization_errors: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            records_read: (0..256).map(|_| AtomicU64::new(0)).collect(),
+            records_emitted: Mutex::new(BTreeMap::new()),
+            store_expired: Mutex::new(BTreeMap::new()),
+            store_nuked: Mutex::new(BTreeMap::new()),
+            tombstones: Mutex::new(BTreeMap::new()),
+            serialization_errors: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_read(&self, record: &VslRecord<'_>) {
+        self.records_read[record.tag as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_emitted(&self, record_type: &'static str, handling: &str) {
+        let mut records_emitted = self.records_emitted.lock().expect("metrics lock poisoned");
+        let by_handling = records_emitted.entry(record_type).or_default();
+        match by_handling.get_mut(handling) {
+            Some(count) => *count += 1,
+            None => {
+                by_handling.insert(handling.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn record_expired(&self, store_name: &'static str) {
+        increment(&self.store_expired, store_name)
+    }
+
+    pub fn record_nuked(&self, store_name: &'static str) {
+        increment(&self.store_nuked, store_name)
+    }
+
+    pub fn tombstone(&self, error_kind: &'static str) {
+        increment(&self.tombstones, error_kind)
+    }
+
+    pub fn serialization_error(&self) {
+        self.serialization_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_written(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        fn header(out: &mut String, name: &str, help: &str) {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+        }
+
+        fn counts(out: &mut String, name: &str, help: &str, label: &str, counts: &Counts) {
+            header(out, name, help);
+            for (value, count) in counts.lock().expect("metrics lock poisoned").iter() {
+                writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), count).unwrap();
+            }
+        }
+
+        let mut out = String::new();
+
+        let name = "varnishslog_vsl_records_read_total";
+        header(&mut out, name, "VSL records read by tag");
+        let tags = TagTable::default();
+        for (num, count) in self.records_read.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                writeln!(out, "{}{{tag=\"{:?}\"}} {}", name, tags.tag(num as u8), count).unwrap();
+            }
+        }
+
+        let name = "varnishslog_access_records_emitted_total";
+        header(&mut out, name, "Access records written to the output by record type and handling");
+        for (record_type, by_handling) in self.records_emitted.lock().expect("metrics lock poisoned").iter() {
+            for (handling, count) in by_handling {
+                writeln!(out, "{}{{record_type=\"{}\",handling=\"{}\"}} {}", name, escape(record_type), escape(handling), count).unwrap();
+            }
+        }
+
+        counts(&mut out, "varnishslog_store_expired_total", "Records expired from store", "store", &self.store_expired);
+        counts(&mut out, "varnishslog_store_nuked_total", "Records nuked from store", "store", &self.store_nuked);
+        counts(&mut out, "varnishslog_tombstones_total", "Transactions that failed to build by error", "error", &self.tombstones);
+
+        let name = "varnishslog_serialization_errors_total";
+        header(&mut out, name, "Access records that failed to be serialized or written to the output");
+        writeln!(out, "{} {}", name, self.serialization_errors.load(Ordering::Relaxed)).unwrap();
+
+        let name = "varnishslog_output_bytes_total";
+        header(&mut out, name, "Bytes written to the output");
+        writeln!(out, "{} {}", name, self.bytes_written.load(Ordering::Relaxed)).unwrap();
+
+        out
+    }
+}
+
+fn escape(label_value: &str) -> String {
+    label_value.replace('\\', r"\\").replace('"', r#"\""#).replace('\n', r"\n")
+}
+
+/// Counts bytes written to the output
+pub struct MeteredWriter<W: Write> {
+    output: W,
+    metrics: Arc<Metrics>,
+}
+
+impl<W: Write> MeteredWriter<W> {
+    pub fn new(output: W, metrics: Arc<Metrics>) -> MeteredWriter<W> {
+        MeteredWriter { output, metrics }
+    }
+}
+
+impl<W: Write> Write for MeteredWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = self.output.write(buf)?;
+        self.metrics.bytes_written(bytes);
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Counts VSL records read before writing them to the wrapped sink
+pub struct MetricsSink<S: RecordSink> {
+    sink: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S: RecordSink> MetricsSink<S> {
+    pub fn new(sink: S, metrics: Arc<Metrics>) -> MetricsSink<S> {
+        MetricsSink { sink, metrics }
+    }
+}
+
+impl<S: RecordSink> RecordSink for MetricsSink<S> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        self.metrics.record_read(record);
+        self.sink.write_record(record)
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.sink.stats()
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.sink.flush()
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        self.sink.finish()
+    }
+
+    fn take_state(&mut self) -> Option<SessionStateSnapshot> {
+        self.sink.take_state()
+    }
+
+    fn restore_state(&mut self, snapshot: SessionStateSnapshot) -> bool {
+        self.sink.restore_state(snapshot)
+    }
+}
+
+/// Serves metrics over HTTP
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+}
+
+impl MetricsServer {
+    /// Listens on given address and serves the metrics on its own thread until the program exits
+    pub fn start<A: ToSocketAddrs>(addr: A, metrics: Arc<Metrics>) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(err) = stream.and_then(|stream| serve(stream, &metrics)) {
+                        debug!("Failed to serve metrics request: {}", err);
+                    }
+                }
+            })?;
+
+        Ok(MetricsServer { local_addr })
+    }
+
+    /// Address the server listens on (e.g. to find out port chosen by the system)
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+fn serve(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut request = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    request.read_line(&mut request_line)?;
+
+    // headers are not used but are read so that the client is not reset
+    let mut header = String::new();
+    while request.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::fs::File;
+    use crate::vsl::record::{VslRecordBuf, VslRecordTag, Marker};
+    use crate::vsl::reader::VslReader;
+    use crate::sink::{SerdeSink, process_vsl_records};
+    use crate::serialization::{Config, Format};
+    use crate::store::Config as StoreConfig;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        let record = VslRecordBuf {
+            tag: VslRecordTag::SLT_Begin,
+            raw_tag: 76,
+            marker: Marker::VSL_CLIENTMARKER,
+            ident: 1,
+            data: b"req 0 rxreq".to_vec(),
+        };
+        metrics.record_read(&record.as_record());
+        metrics.record_read(&record.as_record());
+        metrics.record_emitted("client_request", "hit");
+        metrics.record_emitted("client_request", "miss");
+        metrics.record_emitted("client_request", "hit");
+        metrics.record_nuked("client");
+        metrics.tombstone("SpuriousBegin");
+        metrics.bytes_written(42);
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE varnishslog_vsl_records_read_total counter\n"));
+        assert!(out.contains("varnishslog_vsl_records_read_total{tag=\"SLT_Begin\"} 2\n"));
+        assert!(out.contains("varnishslog_access_records_emitted_total{record_type=\"client_request\",handling=\"hit\"} 2\n"));
+        assert!(out.contains("varnishslog_access_records_emitted_total{record_type=\"client_request\",handling=\"miss\"} 1\n"));
+        assert!(out.contains("varnishslog_store_nuked_total{store=\"client\"} 1\n"));
+        assert!(!out.contains("varnishslog_store_expired_total{"));
+        assert!(out.contains("varnishslog_tombstones_total{error=\"SpuriousBegin\"} 1\n"));
+        assert!(out.contains("varnishslog_serialization_errors_total 0\n"));
+        assert!(out.contains("varnishslog_output_bytes_total 42\n"));
+    }
+
+    #[test]
+    fn count_processed_records() {
+        let log = "logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl";
+        let metrics = Arc::new(Metrics::new());
+        let config = Config {
+            no_log_processing: false,
+            keep_raw_log: false,
+            no_header_indexing: false,
+            keep_raw_headers: false,
+            partial_records: false,
+            flush_on_eof: false,
+            stats_records: false,
+            stats_log_time: false,
+            metrics: Some(metrics.clone()),
+        };
+        let mut store_config = StoreConfig::default();
+        store_config.set_metrics(Some(metrics.clone()));
+
+        let mut buf = Vec::new();
+        let output = MeteredWriter::new(&mut buf, metrics.clone());
+        let mut sink = MetricsSink::new(SerdeSink::new(output, Format::Json, config, &store_config), metrics.clone());
+        process_vsl_records(&mut VslReader::new(File::open(log).unwrap()), &mut sink).unwrap();
+
+        let out = metrics.render();
+        let count = |name: &str| out.lines()
+            .filter(|line| line.starts_with(name))
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum::<u64>();
+        assert_eq!(count("varnishslog_vsl_records_read_total{"), VslReader::new(File::open(log).unwrap()).count() as u64);
+        assert!(out.contains("varnishslog_vsl_records_read_total{tag=\"SLT_Begin\"} 26\n"));
+        assert_eq!(count("varnishslog_access_records_emitted_total{"), 12);
+        assert!(out.contains("varnishslog_access_records_emitted_total{record_type=\"esi_subrequest\",handling=\"miss\"} 4\n"));
+        assert_eq!(count("varnishslog_tombstones_total{"), 0);
+        drop(sink);
+        assert_eq!(count("varnishslog_output_bytes_total"), buf.len() as u64);
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let server = MetricsServer::start("127.0.0.1:0", metrics.clone()).unwrap();
+
+        let mut output = MeteredWriter::new(Vec::new(), metrics.clone());
+        output.write_all(b"foo\n").unwrap();
+
+        let response = get(server.local_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.ends_with("varnishslog_output_bytes_total 4\n"));
+
+        let response = get(server.local_addr(), "/");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
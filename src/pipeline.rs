@@ -0,0 +1,675 @@
+// Processes VSL records on multiple threads.
+//
+// PipelineSink takes records on the thread that reads them and passes owned copies in batches
+// to correlation threads, each running its own SessionState (shard); records are routed to shards
+// by their session (see shard.rs). Client access records they yield are serialized by
+// serialization threads and written to the output by the output thread. Batches are dealt out
+// to serialization threads in turn and collected by the output thread in the same turn so with
+// single correlation shard records are written in the same order as with SerdeSink.
+//
+// Stages are connected with bounded channels so a slow stage blocks the stages before it instead
+// of buffering without limit. Each stage counts records it has processed and time it spent
+// waiting on channels; throughput of stages is logged when the pipeline is finished.
+use std::io::Write;
+use std::fmt::{self, Display};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::info;
+
+use crate::vsl::record::{VslRecord, VslRecordBuf};
+use crate::store::Config as StoreConfig;
+use crate::access_log::record::ClientAccessRecord;
+use crate::access_log::session_state::{SessionState, SessionStateStats};
+use crate::serialization::{log_client_record, log_partial_client_record, log_stats_record, Config, Format, Throughput};
+use crate::sink::{RecordSink, SinkError, SinkStats, StatsMeter, log_session_state_reports, log_flush_summary};
+use crate::shard::ShardRouter;
+
+// Number of VSL records sent to the correlation thread at once
+const BATCH_SIZE: usize = 256;
+// Batch is sent earlier if records are coming slowly or not at all (e.g. from shared memory log)
+const BATCH_MAX_AGE: Duration = Duration::from_millis(100);
+// Number of batches each channel can hold
+const CHANNEL_BOUND: usize = 16;
+
+enum Message<T> {
+    Batch(T),
+    // Acknowledged by the output thread once all batches sent before were written
+    Flush(SyncSender<()>),
+}
+
+// Output of correlation to serialize
+enum Correlated {
+    Client(ClientAccessRecord),
+    Partial(ClientAccessRecord),
+    Stats(Box<(SessionStateStats, usize, Throughput)>),
+}
+
+type RecordBatch = Message<Vec<VslRecordBuf>>;
+type ClientBatch = Message<Vec<Correlated>>;
+// Serialized records and their number
+type OutputBatch = Message<(usize, Vec<u8>)>;
+type ThreadResult = Result<StageStats, SinkError>;
+
+/// Throughput of a pipeline stage
+#[derive(Debug, Clone)]
+pub struct StageStats {
+    pub name: String,
+    /// Records processed
+    pub records: u64,
+    pub elapsed: Duration,
+    /// Time spent waiting for input or for the next stage to take output
+    pub waiting: Duration,
+    started: Instant,
+}
+
+impl StageStats {
+    fn start<N: Into<String>>(name: N) -> StageStats {
+        StageStats {
+            name: name.into(),
+            records: 0,
+            elapsed: Duration::default(),
+            waiting: Duration::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn wait<T, F: FnOnce() -> T>(&mut self, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.waiting += start.elapsed();
+        result
+    }
+
+    fn finish(mut self) -> StageStats {
+        self.elapsed = self.started.elapsed();
+        self
+    }
+
+    /// Records per second of time the stage was not waiting
+    pub fn throughput(&self) -> f64 {
+        let busy = self.elapsed.saturating_sub(self.waiting).as_secs_f64();
+        if busy > 0.0 {
+            self.records as f64 / busy
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Display for StageStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}: records: {} elapsed: {:.3}s waiting: {:.3}s throughput: {:.0} records/s",
+               self.name, self.records, self.elapsed.as_secs_f64(), self.waiting.as_secs_f64(), self.throughput())
+    }
+}
+
+struct Counters {
+    emitted: AtomicU64,
+    // per correlation shard
+    unresolved: Vec<AtomicUsize>,
+    tombstoned: Vec<AtomicUsize>,
+}
+
+impl Counters {
+    fn new(shards: usize) -> Counters {
+        Counters {
+            emitted: AtomicU64::new(0),
+            unresolved: (0..shards).map(|_| AtomicUsize::new(0)).collect(),
+            tombstoned: (0..shards).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+}
+
+// Deals batches of correlated records out to serialization threads in turn; shared by
+// correlation shards so that the output thread can collect them in the same turn
+struct Dealer {
+    outputs: Vec<SyncSender<ClientBatch>>,
+    next: usize,
+}
+
+impl Dealer {
+    fn deal(&mut self, message: ClientBatch) -> Result<(), ()> {
+        self.outputs[self.next].send(message).map_err(|_| ())?;
+        self.next = (self.next + 1) % self.outputs.len();
+        Ok(())
+    }
+}
+
+fn correlate(shard: usize, input: Receiver<RecordBatch>, dealer: Arc<Mutex<Dealer>>, store_config: StoreConfig, config: Arc<Config>, counters: Arc<Counters>) -> ThreadResult {
+    let mut stats = StageStats::start(format!("correlation {}", shard));
+    let mut state = SessionState::with_config(&store_config);
+    if config.partial_records {
+        state.keep_partial_records();
+    }
+    let mut stats_meter = StatsMeter::new(store_config.stat_epoch_interval(), config.stats_log_time);
+
+    while let Ok(message) = stats.wait(|| input.recv()) {
+        let message = match message {
+            Message::Batch(records) => {
+                stats.records += records.len() as u64;
+                let mut clients = Vec::new();
+                for record in &records {
+                    clients.extend(state.apply(record).map(Correlated::Client));
+                    clients.extend(state.take_partial_records().into_iter().map(Correlated::Partial));
+                }
+
+                if config.stats_records {
+                    for record in &records {
+                        stats_meter.count(record.data.len());
+                    }
+                    if let Some(throughput) = stats_meter.due(state.epoch()) {
+                        clients.push(Correlated::Stats(Box::new((state.stats(), shard, throughput))));
+                    }
+                }
+
+                counters.unresolved[shard].store(state.unresolved_count(), Ordering::Relaxed);
+                counters.tombstoned[shard].store(state.tombstone_count(), Ordering::Relaxed);
+
+                if clients.is_empty() {
+                    continue
+                }
+                Message::Batch(clients)
+            }
+            Message::Flush(ack) => Message::Flush(ack),
+        };
+
+        if stats.wait(|| dealer.lock().expect("dealer lock poisoned").deal(message)).is_err() {
+            // next stage failed; its thread reports the error
+            log_session_state_reports(&state);
+            return Ok(stats.finish())
+        }
+    }
+
+    let mut clients = Vec::new();
+    if config.flush_on_eof {
+        let partial = state.drain_partial_records();
+        log_flush_summary(&state, &partial);
+        clients.extend(partial.into_iter().map(Correlated::Partial));
+    }
+    if config.stats_records {
+        clients.push(Correlated::Stats(Box::new((state.stats(), shard, stats_meter.take()))));
+    }
+    if !clients.is_empty() {
+        // records left are reported even if next stage failed
+        let _ = stats.wait(|| dealer.lock().expect("dealer lock poisoned").deal(Message::Batch(clients)));
+    }
+    log_session_state_reports(&state);
+    Ok(stats.finish())
+}
+
+fn serialize(index: usize, input: Receiver<ClientBatch>, output: SyncSender<OutputBatch>, format: Arc<Format>, config: Arc<Config>) -> ThreadResult {
+    let mut stats = StageStats::start(format!("serialization {}", index));
+
+    while let Ok(message) = stats.wait(|| input.recv()) {
+        let message = match message {
+            Message::Batch(clients) => {
+                let mut buf = Vec::new();
+                let mut records = 0;
+                for correlated in &clients {
+                    match correlated {
+                        Correlated::Client(client) => log_client_record(client, &format, &mut buf, &config)?,
+                        Correlated::Partial(client) => log_partial_client_record(client, &format, &mut buf, &config)?,
+                        // not counted as emitted
+                        Correlated::Stats(stats) => {
+                            let (state, shard, throughput) = &**stats;
+                            log_stats_record(state, Some(*shard), throughput, &format, &mut buf)?;
+                            continue
+                        }
+                    }
+                    records += 1;
+                }
+                stats.records += records as u64;
+                Message::Batch((records, buf))
+            }
+            Message::Flush(ack) => Message::Flush(ack),
+        };
+
+        if stats.wait(|| output.send(message)).is_err() {
+            return Ok(stats.finish())
+        }
+    }
+    Ok(stats.finish())
+}
+
+fn write_output<W: Write>(mut output: W, inputs: Vec<Receiver<OutputBatch>>, counters: Arc<Counters>) -> ThreadResult {
+    let mut stats = StageStats::start("output");
+
+    // take batches in the same turn they were dealt out to serialization threads
+    for input in inputs.iter().cycle() {
+        match stats.wait(|| input.recv()) {
+            Ok(Message::Batch((records, buf))) => {
+                output.write_all(&buf)?;
+                stats.records += records as u64;
+                counters.emitted.fetch_add(records as u64, Ordering::Relaxed);
+            }
+            Ok(Message::Flush(ack)) => {
+                output.flush()?;
+                let _ = ack.send(());
+            }
+            Err(_) => break,
+        }
+    }
+
+    output.flush()?;
+    Ok(stats.finish())
+}
+
+// Records read but not sent to correlation threads yet; shared with the batch timer thread that
+// sends partial batches out when no more records come (e.g. varnishd is idle)
+struct Batcher {
+    senders: Vec<SyncSender<RecordBatch>>,
+    batches: Vec<Vec<VslRecordBuf>>,
+    started: Instant,
+}
+
+impl Batcher {
+    fn send(&self, shard: usize, message: RecordBatch) -> Result<(), ()> {
+        self.senders.get(shard).ok_or(())?.send(message).map_err(|_| ())
+    }
+
+    fn send_batches(&mut self) -> Result<(), ()> {
+        self.started = Instant::now();
+        for shard in 0..self.batches.len() {
+            if self.batches[shard].is_empty() {
+                continue
+            }
+            let batch = mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH_SIZE));
+            self.send(shard, Message::Batch(batch))?;
+        }
+        Ok(())
+    }
+
+    fn is_due(&self) -> bool {
+        self.batches.iter().any(|batch| batch.len() >= BATCH_SIZE) || self.started.elapsed() >= BATCH_MAX_AGE
+    }
+}
+
+fn send_old_batches(batcher: Arc<Mutex<Batcher>>, stop: Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(BATCH_MAX_AGE) {
+        let mut batcher = batcher.lock().expect("batcher lock poisoned");
+        if batcher.started.elapsed() >= BATCH_MAX_AGE && batcher.send_batches().is_err() {
+            // correlation failed; reading thread will find out on next send
+            return
+        }
+    }
+}
+
+/// Correlates records and writes them in JSON formats like SerdeSink but using separate threads
+/// for correlation, serialization and output
+///
+/// With more than one correlation shard records are routed to shards with ShardRouter; records
+/// are written in order in which shards have correlated them. Records are sent to correlation
+/// threads in batches; partial batches are sent once they are BATCH_MAX_AGE old even if no more
+/// records are written.
+pub struct PipelineSink {
+    batcher: Arc<Mutex<Batcher>>,
+    router: Option<ShardRouter>,
+    timer: Option<(SyncSender<()>, JoinHandle<()>)>,
+    threads: Vec<JoinHandle<ThreadResult>>,
+    counters: Arc<Counters>,
+    stats: Option<StageStats>,
+    stage_stats: Vec<StageStats>,
+}
+
+impl PipelineSink {
+    /// Uses given number of correlation shards and serialization threads (at least one each)
+    pub fn new<W>(output: W, format: Format, config: Config, store_config: &StoreConfig, correlation_shards: usize, serialization_threads: usize) -> Result<PipelineSink, SinkError> where W: Write + Send + 'static {
+        let correlation_shards = correlation_shards.max(1);
+        let serialization_threads = serialization_threads.max(1);
+        let counters = Arc::new(Counters::new(correlation_shards));
+        let format = Arc::new(format);
+        let config = Arc::new(config);
+        let mut threads = Vec::new();
+
+        let mut clients = Vec::new();
+        let mut buffers = Vec::new();
+
+        for index in 0..serialization_threads {
+            let (client_sender, client_receiver) = sync_channel(CHANNEL_BOUND);
+            let (buf_sender, buf_receiver) = sync_channel(CHANNEL_BOUND);
+            clients.push(client_sender);
+            buffers.push(buf_receiver);
+
+            let format = format.clone();
+            let config = config.clone();
+            threads.push(thread::Builder::new()
+                .name(format!("serialization {}", index))
+                .spawn(move || serialize(index, client_receiver, buf_sender, format, config))?);
+        }
+
+        let dealer = Arc::new(Mutex::new(Dealer { outputs: clients, next: 0 }));
+        let mut senders = Vec::new();
+
+        for shard in 0..correlation_shards {
+            let (sender, records) = sync_channel(CHANNEL_BOUND);
+            senders.push(sender);
+
+            let dealer = dealer.clone();
+            let store_config = store_config.clone();
+            let config = config.clone();
+            let counters = counters.clone();
+            threads.push(thread::Builder::new()
+                .name(format!("correlation {}", shard))
+                .spawn(move || correlate(shard, records, dealer, store_config, config, counters))?);
+        }
+
+        let output_counters = counters.clone();
+        threads.push(thread::Builder::new()
+            .name("output".to_string())
+            .spawn(move || write_output(output, buffers, output_counters))?);
+
+        let batcher = Arc::new(Mutex::new(Batcher {
+            senders,
+            batches: (0..correlation_shards).map(|_| Vec::with_capacity(BATCH_SIZE)).collect(),
+            started: Instant::now(),
+        }));
+
+        let (stop, stopped) = sync_channel(0);
+        let timer_batcher = batcher.clone();
+        let timer = thread::Builder::new()
+            .name("batch timer".to_string())
+            .spawn(move || send_old_batches(timer_batcher, stopped))?;
+
+        Ok(PipelineSink {
+            batcher,
+            router: if correlation_shards > 1 {
+                Some(ShardRouter::with_config(correlation_shards, store_config))
+            } else {
+                None
+            },
+            timer: Some((stop, timer)),
+            threads,
+            counters,
+            stats: Some(StageStats::start("reading")),
+            stage_stats: Vec::new(),
+        })
+    }
+
+    /// Throughput of pipeline stages; available once the sink is finished
+    pub fn stage_stats(&self) -> &[StageStats] {
+        &self.stage_stats
+    }
+
+    // Runs f with the batcher; pipeline is shut down if sending to correlation threads failed
+    fn with_batcher<F>(&mut self, f: F) -> Result<(), SinkError> where F: FnOnce(&mut Batcher, &mut Option<ShardRouter>) -> Result<(), ()> {
+        let stats = self.stats.as_mut().expect("pipeline was shut down");
+        let batcher = &self.batcher;
+        let router = &mut self.router;
+
+        let result = stats.wait(|| f(&mut batcher.lock().expect("batcher lock poisoned"), router));
+        if result.is_err() {
+            self.shutdown()?;
+            return Err(SinkError::Other("Pipeline stopped unexpectedly".into()))
+        }
+        Ok(())
+    }
+
+    // Closes the pipeline and waits for all the stages to finish; returns first error of a stage
+    fn shutdown(&mut self) -> Result<(), SinkError> {
+        if let Some((stop, timer)) = self.timer.take() {
+            drop(stop);
+            let _ = timer.join();
+        }
+        if let Ok(mut batcher) = self.batcher.lock() {
+            batcher.senders.clear();
+        }
+        if let Some(stats) = self.stats.take() {
+            self.stage_stats.push(stats.finish());
+        }
+
+        let mut result = Ok(());
+        for thread in self.threads.drain(..) {
+            match thread.join() {
+                Ok(Ok(stats)) => self.stage_stats.push(stats),
+                Ok(Err(err)) => if result.is_ok() {
+                    result = Err(err)
+                },
+                Err(_) => if result.is_ok() {
+                    result = Err(SinkError::Other("Pipeline thread panicked".into()))
+                },
+            }
+        }
+        result
+    }
+}
+
+impl RecordSink for PipelineSink {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        let record = record.to_record_buf();
+        if let Some(stats) = self.stats.as_mut() {
+            stats.records += 1;
+        }
+
+        self.with_batcher(|batcher, router| {
+            match router {
+                Some(ref mut router) => {
+                    let batches = &mut batcher.batches;
+                    router.route(record, |shard, record| batches[shard].push(record));
+                }
+                None => batcher.batches[0].push(record),
+            }
+
+            if batcher.is_due() {
+                batcher.send_batches()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Emitted records are counted once written to the output; flush to get exact counts
+    fn stats(&self) -> SinkStats {
+        let sum = |counters: &[AtomicUsize]| counters.iter().map(|counter| counter.load(Ordering::Relaxed)).sum::<usize>();
+        SinkStats {
+            emitted: self.counters.emitted.load(Ordering::Relaxed),
+            unresolved: sum(&self.counters.unresolved) + self.router.as_ref().map(|router| router.held_count()).unwrap_or(0),
+            tombstoned: sum(&self.counters.tombstoned),
+        }
+    }
+
+    /// Waits for all records written so far to go through the pipeline
+    fn flush(&mut self) -> Result<(), SinkError> {
+        let shards = self.counters.unresolved.len();
+        let (ack, flushed) = sync_channel(shards);
+
+        self.with_batcher(|batcher, _router| {
+            batcher.send_batches()?;
+            for shard in 0..shards {
+                batcher.send(shard, Message::Flush(ack.clone()))?;
+            }
+            Ok(())
+        })?;
+        drop(ack);
+
+        for _ in 0..shards {
+            if flushed.recv().is_err() {
+                self.shutdown()?;
+                return Err(SinkError::Other("Pipeline stopped unexpectedly".into()))
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        self.with_batcher(|batcher, router| {
+            if let Some(ref mut router) = router {
+                let batches = &mut batcher.batches;
+                router.release_held(|shard, record| batches[shard].push(record));
+            }
+            batcher.send_batches()
+        })?;
+        self.shutdown()?;
+
+        for stats in &self.stage_stats {
+            info!("Pipeline stage {}", stats);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipelineSink {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::sync::Mutex;
+    use crate::vsl::reader::VslReader;
+    use crate::sink::{SerdeSink, process_vsl_records, write_vsl_records};
+
+    const LOG: &str = "logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl";
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            no_log_processing: false,
+            keep_raw_log: false,
+            no_header_indexing: false,
+            keep_raw_headers: false,
+            partial_records: false,
+            flush_on_eof: false,
+            stats_records: false,
+            stats_log_time: false,
+            metrics: None,
+        }
+    }
+
+    fn serde_output() -> Vec<u8> {
+        let output = SharedOutput::default();
+        let mut sink = SerdeSink::new(output.clone(), Format::Json, config(), &Default::default());
+        process_vsl_records(&mut VslReader::new(File::open(LOG).unwrap()), &mut sink).unwrap();
+        let output = output.0.lock().unwrap().clone();
+        output
+    }
+
+    #[test]
+    fn same_output_as_serde_sink() {
+        for threads in 1..=3 {
+            let output = SharedOutput::default();
+            let mut sink = PipelineSink::new(output.clone(), Format::Json, config(), &Default::default(), 1, threads).unwrap();
+            process_vsl_records(&mut VslReader::new(File::open(LOG).unwrap()), &mut sink).unwrap();
+
+            assert_eq!(*output.0.lock().unwrap(), serde_output());
+            assert_eq!(sink.stats(), SinkStats { emitted: 8, unresolved: 0, tombstoned: 0 });
+
+            let stages = sink.stage_stats().iter().map(|stats| stats.name.as_str()).collect::<Vec<_>>();
+            assert_eq!(stages.len(), 3 + threads);
+            assert!(stages.contains(&"reading"));
+            assert!(stages.contains(&"correlation 0"));
+            assert!(stages.contains(&"output"));
+            assert_eq!(sink.stage_stats().iter().find(|stats| stats.name == "output").unwrap().records, 8);
+        }
+    }
+
+    fn sorted_lines(output: &SharedOutput) -> Vec<String> {
+        let output = output.0.lock().unwrap();
+        let mut lines = String::from_utf8_lossy(&output).lines().map(ToString::to_string).collect::<Vec<_>>();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn sharded_correlation() {
+        // sample logs were captured from different varnishd instances and reuse vxids
+        let mut paths = std::fs::read_dir("logs").unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        paths.retain(|path| path.extension().map(|ext| ext == "vsl").unwrap_or(false));
+        paths.sort();
+
+        let mut correlated = [0; 3];
+        for path in paths {
+            let expected = SharedOutput::default();
+            let mut sink = SerdeSink::new(expected.clone(), Format::Json, config(), &Default::default());
+            write_vsl_records(&mut VslReader::new(File::open(&path).unwrap()), &mut sink).unwrap();
+            sink.finish().unwrap();
+            let expected_stats = sink.stats();
+
+            let output = SharedOutput::default();
+            let mut sink = PipelineSink::new(output.clone(), Format::Json, config(), &Default::default(), 3, 2).unwrap();
+            write_vsl_records(&mut VslReader::new(File::open(&path).unwrap()), &mut sink).unwrap();
+            sink.finish().unwrap();
+
+            assert_eq!(sorted_lines(&output), sorted_lines(&expected), "{}", path.display());
+            assert_eq!(sink.stats(), expected_stats, "{}", path.display());
+
+            let stage_stats = sink.stage_stats();
+            let shards = stage_stats.iter().filter(|stats| stats.name.starts_with("correlation"));
+            for (records, stats) in correlated.iter_mut().zip(shards) {
+                *records += stats.records;
+            }
+        }
+        assert!(correlated.iter().all(|records| *records > 0));
+    }
+
+    #[test]
+    fn send_batches_when_idle() {
+        let output = SharedOutput::default();
+        let mut sink = PipelineSink::new(output.clone(), Format::Json, config(), &Default::default(), 1, 2).unwrap();
+
+        // last batch is not full and no more records come after it
+        write_vsl_records(&mut VslReader::new(File::open(LOG).unwrap()), &mut sink).unwrap();
+
+        let started = Instant::now();
+        while sink.stats().emitted < 8 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(BATCH_MAX_AGE);
+        }
+        assert_eq!(sink.stats().emitted, 8);
+        assert_eq!(*output.0.lock().unwrap(), serde_output());
+
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn flush_waits_for_output() {
+        let output = SharedOutput::default();
+        let mut sink = PipelineSink::new(output.clone(), Format::Json, config(), &Default::default(), 1, 2).unwrap();
+
+        write_vsl_records(&mut VslReader::new(File::open(LOG).unwrap()), &mut sink).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(sink.stats().emitted, 8);
+        assert_eq!(*output.0.lock().unwrap(), serde_output());
+
+        sink.finish().unwrap();
+    }
+
+    struct FailingOutput;
+
+    impl Write for FailingOutput {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_error() {
+        let mut sink = PipelineSink::new(FailingOutput, Format::Json, config(), &Default::default(), 2, 2).unwrap();
+        let result = process_vsl_records(&mut VslReader::new(File::open(LOG).unwrap()), &mut sink);
+
+        match result {
+            Err(crate::sink::ProcessingError::Sink(err)) => assert!(err.is_broken_pipe()),
+            result => panic!("expected broken pipe error; got: {:?}", result),
+        }
+    }
+}
@@ -2,6 +2,7 @@ use serde::{Serialize, Serializer};
 use serde::ser::{SerializeSeq, SerializeMap};
 use crate::access_log::record::LogEntry as VslLogEntry;
 use crate::access_log::record::AclResult as VslAclResult;
+use crate::access_log::session_state::SessionStateStats;
 
 use linked_hash_map::LinkedHashMap;
 
@@ -48,6 +49,10 @@ pub struct ClientAccess<'a, 'i> {
     pub request_header_index: Option<Index<'a, 'i>>,
     #[serde(skip_serializing_if="Option::is_none")]
     pub response_header_index: Option<Index<'a, 'i>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub partial: Option<bool>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub unresolved_links: Option<&'i [u32]>,
 }
 
 impl<'a: 'i, 'i> EntryType for ClientAccess<'a, 'i> {
@@ -128,6 +133,10 @@ pub struct PipeSession<'a, 'i> {
     pub request_header_index: Option<Index<'a, 'i>>,
     #[serde(skip_serializing_if="Option::is_none")]
     pub backend_request_header_index: Option<Index<'a, 'i>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub partial: Option<bool>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub unresolved_links: Option<&'i [u32]>,
 }
 
 impl<'a: 'i, 'i> EntryType for PipeSession<'a, 'i> {
@@ -157,6 +166,52 @@ impl<'a: 'i, 'i> EntryType for PipeSession<'a, 'i> {
     }
 }
 
+#[derive(Serialize, Debug)]
+pub struct StatsRecord<'a> {
+    pub record_type: &'a str,
+    pub timestamp: f64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub shard: Option<usize>,
+    #[serde(flatten)]
+    pub state: &'a SessionStateStats,
+    pub parsed: Parsed,
+}
+
+impl<'a> EntryType for StatsRecord<'a> {
+    fn type_name(&self) -> &str {
+        self.record_type
+    }
+    fn remote_ip(&self) -> &str {
+        "-"
+    }
+    fn timestamp(&self) -> f64 {
+        self.timestamp
+    }
+    fn request_method(&self) -> Option<&str> {
+        None
+    }
+    fn request_url(&self) -> Option<&str> {
+        None
+    }
+    fn request_protocol(&self) -> Option<&str> {
+        None
+    }
+    fn response_status(&self) -> Option<u32> {
+        None
+    }
+    fn response_bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Parsed {
+    pub records: u64,
+    pub bytes: u64,
+    pub records_per_second: f64,
+    pub bytes_per_second: f64,
+}
+
 #[derive(Serialize, Debug)]
 pub struct Address<'a> {
     pub ip: &'a str,
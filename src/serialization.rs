@@ -2,6 +2,8 @@ use std::io::Write;
 use std::io::Error as IoError;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
 use quick_error::quick_error;
 use log::warn;
 
@@ -13,6 +15,9 @@ use chrono::NaiveDateTime;
 use linked_hash_map::LinkedHashMap;
 use boolinator::Boolinator;
 
+use crate::vsl::record::VslIdent;
+use crate::metrics::Metrics;
+use crate::access_log::session_state::SessionStateStats;
 use crate::access_log::record::{
     Address,
     Handling,
@@ -45,6 +50,41 @@ pub struct Config {
     pub keep_raw_log: bool,
     pub no_header_indexing: bool,
     pub keep_raw_headers: bool,
+    /// Also log client records that were expired or nuked before they were resolved (see
+    /// `log_partial_client_record`)
+    pub partial_records: bool,
+    /// Log client records left unresolved at the end of the stream as partial records
+    pub flush_on_eof: bool,
+    /// Also log `varnishslog_stats` records with statistics of correlation state and parsing
+    /// throughput (see `log_stats_record`)
+    pub stats_records: bool,
+    /// Take timestamps of stats records from log time (see `store::log_time_epoch`) instead of
+    /// wall clock; rates are measured in wall clock time either way
+    pub stats_log_time: bool,
+    /// Count access records written and failed to be written
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+/// VSL records parsed over a period of time
+#[derive(Debug, Clone, Default)]
+pub struct Throughput {
+    pub records: u64,
+    /// Bytes of record data
+    pub bytes: u64,
+    pub elapsed: StdDuration,
+    /// Seconds since UNIX epoch at the end of the period
+    pub timestamp: f64,
+}
+
+impl Throughput {
+    fn per_second(&self, count: u64) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            count as f64 / seconds
+        } else {
+            0.0
+        }
+    }
 }
 
 pub enum Format {
@@ -304,51 +344,84 @@ impl<T: Display> Display for NcsaOption<T> {
     }
 }
 
-pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format, out: &mut W, config: &Config)
-    -> Result<(), OutputError> where W: Write {
-    fn write<W, E>(format: &Format, out: &mut W, log_entry: &E) -> Result<(), OutputError> where W: Write, E: ser::EntryType {
-        let write_entry = match *format {
-            Format::Json |
-            Format::NcsaJson => write_json,
-            Format::JsonPretty => write_json_pretty,
-        };
-        match *format {
-            Format::Json |
-            Format::JsonPretty => {
-                write_entry(&mut *out, &log_entry)?;
-
-                writeln!(out, "")?;
+// Writes JSON serialized entry line in given format
+fn write_entry<W, E>(format: &Format, out: &mut W, log_entry: &E) -> Result<(), OutputError> where W: Write, E: ser::EntryType {
+    let write_json_entry = match *format {
+        Format::Json |
+        Format::NcsaJson => write_json,
+        Format::JsonPretty => write_json_pretty,
+    };
+    match *format {
+        Format::Json |
+        Format::JsonPretty => {
+            write_json_entry(&mut *out, &log_entry)?;
+
+            writeln!(out, "")?;
+        }
+        Format::NcsaJson => {
+            // 192.168.1.115 - - [25/Aug/2016:11:56:55 +0000] "GET http://staging.eod.whatclinic.net/ HTTP/1.1" 503 1366
+            let date_time = NaiveDateTime::from_timestamp(log_entry.timestamp() as i64, 0);
+
+            write!(out, "{} {} - [{}]",
+                        log_entry.remote_ip(),
+                        log_entry.type_name(),
+                        date_time.format("%d/%b/%Y:%H:%M:%S +0000"))?;
+
+            if let (Some(method), Some(url), Some(protocol)) = (log_entry.request_method(), log_entry.request_url(), log_entry.request_protocol()) {
+                write!(out, " \"{} {} {}\"",
+                    NcsaEscaped(method),
+                    NcsaEscaped(url),
+                    NcsaEscaped(protocol))?;
+            } else {
+                write!(out, " -")?;
             }
-            Format::NcsaJson => {
-                // 192.168.1.115 - - [25/Aug/2016:11:56:55 +0000] "GET http://staging.eod.whatclinic.net/ HTTP/1.1" 503 1366
-                let date_time = NaiveDateTime::from_timestamp(log_entry.timestamp() as i64, 0);
-
-                write!(out, "{} {} - [{}]",
-                            log_entry.remote_ip(),
-                            log_entry.type_name(),
-                            date_time.format("%d/%b/%Y:%H:%M:%S +0000"))?;
-
-                if let (Some(method), Some(url), Some(protocol)) = (log_entry.request_method(), log_entry.request_url(), log_entry.request_protocol()) {
-                    write!(out, " \"{} {} {}\"",
-                        NcsaEscaped(method),
-                        NcsaEscaped(url),
-                        NcsaEscaped(protocol))?;
-                } else {
-                    write!(out, " -")?;
-                }
 
-                write!(out, " {} {} ",
-                            NcsaOption(log_entry.response_status()),
-                            NcsaOption(log_entry.response_bytes()))?;
+            write!(out, " {} {} ",
+                        NcsaOption(log_entry.response_status()),
+                        NcsaOption(log_entry.response_bytes()))?;
 
-                write_entry(&mut *out, &log_entry)?;
+            write_json_entry(&mut *out, &log_entry)?;
 
-                writeln!(out, "")?;
-            }
+            writeln!(out, "")?;
         }
-        Ok(())
     }
+    Ok(())
+}
 
+pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format, out: &mut W, config: &Config)
+    -> Result<(), OutputError> where W: Write {
+    log_client_record_tree(client_record, None, format, out, config)
+}
+
+/// Logs record that was evicted before all its links were resolved (or that was not linked by its
+/// parent) marked as partial with vxids of unresolved links
+pub fn log_partial_client_record<W>(client_record: &ClientAccessRecord, format: &Format, out: &mut W, config: &Config)
+    -> Result<(), OutputError> where W: Write {
+    let unresolved_links = client_record.unresolved_links();
+    log_client_record_tree(client_record, Some(&unresolved_links), format, out, config)
+}
+
+/// Logs `varnishslog_stats` record with statistics of the stores of correlation state (of given
+/// shard) and throughput of parsing since the last stats record
+pub fn log_stats_record<W>(stats: &SessionStateStats, shard: Option<usize>, throughput: &Throughput, format: &Format, out: &mut W)
+    -> Result<(), OutputError> where W: Write {
+    write_entry(format, out, &ser::StatsRecord {
+        record_type: "varnishslog_stats",
+        timestamp: throughput.timestamp,
+        shard,
+        state: stats,
+        parsed: ser::Parsed {
+            records: throughput.records,
+            bytes: throughput.bytes,
+            records_per_second: throughput.per_second(throughput.records),
+            bytes_per_second: throughput.per_second(throughput.bytes),
+        },
+    })
+}
+
+// Logs the record and its ESI subrequests
+fn log_client_record_tree<W>(client_record: &ClientAccessRecord, unresolved_links: Option<&[VslIdent]>, format: &Format, out: &mut W, config: &Config)
+    -> Result<(), OutputError> where W: Write {
     fn follow_restarts(record: &ClientAccessRecord, restart_count: usize) -> Option<(&ClientAccessRecord, usize)> {
         match record.transaction {
             ClientAccessTransaction::Full { .. } |
@@ -739,6 +812,7 @@ pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format,
         out: &mut W,
         record: &ClientAccessRecord,
         record_type: &'static str,
+        unresolved_links: Option<&[VslIdent]>,
         config: &Config) -> Result<(), OutputError> where W: Write {
         flatten_client_log_record(record, |client_log_record| {
             if let Some(client_log_record) = client_log_record {
@@ -762,7 +836,9 @@ pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format,
                         if let Some(esi_records) = esi_records {
                             for esi_record_link in esi_records {
                                 if let Some(esi_record) = esi_record_link.get_resolved() {
-                                    log_client_access_record(format, out, esi_record, "esi_subrequest", config)?;
+                                    // subrequests of partial record may be partial too
+                                    let esi_unresolved_links = unresolved_links.map(|_| esi_record.unresolved_links()).filter(|links| !links.is_empty());
+                                    log_client_access_record(format, out, esi_record, "esi_subrequest", esi_unresolved_links.as_deref(), config)?;
                                 } else {
                                     warn!("Found unresolved ESI record link {:?} in:\n{:#?}", esi_record_link, record);
                                 }
@@ -773,7 +849,7 @@ pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format,
 
                         flatten_linked_backend_log_record(record, ber, 0, |backend_log_record| {
                             // backend record
-                            // Need to live up to write()
+                            // Need to live up to write_entry()
                             let mut log_index = None;
 
                             let mut request_header_index = None;
@@ -916,8 +992,14 @@ pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format,
                                 log: log,
                                 request_header_index: (config.keep_raw_headers & !config.no_header_indexing).as_some_from(|| request_header_index.as_ref().unwrap().as_ser()),
                                 response_header_index: (config.keep_raw_headers & !config.no_header_indexing).as_some_from(|| response_header_index.as_ref().unwrap().as_ser()),
+                                partial: unresolved_links.map(|_| true),
+                                unresolved_links,
                             };
-                            write(format, out, &client_access)
+                            let result = write_entry(format, out, &client_access);
+                            if let (Ok(()), Some(metrics)) = (&result, &config.metrics) {
+                                metrics.record_emitted(record_type, client_access.handling);
+                            }
+                            result
                         })?;
                         Ok(())
                     },
@@ -979,8 +1061,14 @@ pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format,
                             backend_connection: backend_connection.map(|b| b.as_ser()),
                             request_header_index: (config.keep_raw_headers & !config.no_header_indexing).as_some_from(|| request_header_index.as_ref().unwrap().as_ser()),
                             backend_request_header_index: (config.keep_raw_headers & !config.no_header_indexing).as_some_from(|| backend_request_header_index.as_ref().unwrap().as_ser()),
+                            partial: unresolved_links.map(|_| true),
+                            unresolved_links,
                         };
-                        write(format, out, &pipe_session)
+                        let result = write_entry(format, out, &pipe_session);
+                        if let (Ok(()), Some(metrics)) = (&result, &config.metrics) {
+                            metrics.record_emitted("pipe_session", "pipe");
+                        }
+                        result
                     }
                 }
             } else {
@@ -990,5 +1078,11 @@ pub fn log_client_record<W>(client_record: &ClientAccessRecord, format: &Format,
         })
     }
 
-    log_client_access_record(format, out, client_record, "client_request", config)
+    // partial records may be ESI subrequests that were not linked by their parent
+    let record_type = if !client_record.root && client_record.reason == "esi" { "esi_subrequest" } else { "client_request" };
+    let result = log_client_access_record(format, out, client_record, record_type, unresolved_links, config);
+    if let (Err(_), Some(metrics)) = (&result, &config.metrics) {
+        metrics.serialization_error();
+    }
+    result
 }
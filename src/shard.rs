@@ -0,0 +1,348 @@
+// Routing of VSL records to correlation shards.
+//
+// All transactions of a session (requests, ESI subrequests, restarts, backend fetches and
+// retries) need to be correlated by the same SessionState so records are routed to a shard by
+// vxid of their root session. Root of a transaction is learned from its SLT_Begin record: session
+// is its own root, client request received on a session (`req <session> rxreq`) has the session
+// as root and other transactions have the root of their parent transaction. Roots are remembered
+// per vxid so that transactions logged after the session ended (e.g. bgfetch) follow their session.
+//
+// Varnish logs transaction records when the transaction ends so child transactions usually appear
+// before their parents (see access_log/session_state.rs); records of transactions with parent
+// that was not seen yet are held until root of the parent is learned and then passed on in
+// original stream order. Children logged after their parent ended (bgfetch, retry) are announced
+// by SLT_Link record of the parent so root of a transaction is forgotten once it ends; this keeps
+// vxids reused by a different varnishd instance (e.g. logs of several servers) from being routed
+// by stale roots. Records of transactions that started before the stream are routed by their own
+// vxid.
+use std::num::Wrapping;
+use std::mem::size_of;
+use log::{debug, warn};
+
+use crate::store::{VslStore, Config as StoreConfig, MemSize, observe_log_time};
+use crate::vsl::record::{VslRecordBuf, VslRecordTag, VslIdent};
+use crate::vsl::record::message::parser::{slt_begin, slt_link};
+
+#[derive(Debug)]
+struct Held {
+    records: Vec<(u64, VslRecordBuf)>,
+    ended: bool,
+}
+
+impl MemSize for Held {
+    fn mem_size(&self) -> usize {
+        self.records.mem_size()
+    }
+}
+
+pub struct ShardRouter {
+    shards: usize,
+    seq: u64,
+    // root session vxid by transaction vxid
+    roots: VslStore<VslIdent>,
+    // records of transactions with root not known yet
+    held: VslStore<Held>,
+    // held transactions by vxid of parent they are waiting for
+    waiting: VslStore<Vec<VslIdent>>,
+}
+
+impl ShardRouter {
+    pub fn new(shards: usize) -> ShardRouter {
+        ShardRouter::with_config(shards, &Default::default())
+    }
+
+    pub fn with_config(shards: usize, store_config: &StoreConfig) -> ShardRouter {
+        assert!(shards > 0, "at least one shard is needed");
+        ShardRouter {
+            shards,
+            seq: 0,
+            roots: VslStore::with_config("shard roots", Some(Self::on_forget), Some(Self::on_forget), store_config),
+            held: VslStore::with_config("shard held", Some(Self::on_drop), Some(Self::on_drop), store_config),
+            waiting: VslStore::with_config("shard waiting", Some(Self::on_forget), Some(Self::on_forget), store_config),
+        }
+    }
+
+    fn on_forget<T>(store_name: &str, current_epoch: Wrapping<u64>, record_epoch: Wrapping<u64>, record_ident: VslIdent, _value: &T) {
+        debug!("VslStore[{}]: Forgot transaction {}: current epoch {}, record epoch {}",
+               store_name, record_ident, current_epoch, record_epoch);
+    }
+
+    fn on_drop(store_name: &str, current_epoch: Wrapping<u64>, record_epoch: Wrapping<u64>, record_ident: VslIdent, held: &Held) {
+        warn!("VslStore[{}]: Dropped records of transaction {} from store: current epoch {}, record epoch {}, records: {}",
+              store_name, record_ident, current_epoch, record_epoch, held.records.len());
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards
+    }
+
+    /// Shard of transactions of given root session
+    pub fn shard(&self, root: VslIdent) -> usize {
+        root as usize % self.shards
+    }
+
+    /// Number of transactions held until their root is known
+    pub fn held_count(&self) -> usize {
+        self.held.values().count()
+    }
+
+    /// Passes the record and records held for transactions whose root became known to `route`
+    /// with their shard
+    pub fn route<F: FnMut(usize, VslRecordBuf)>(&mut self, record: VslRecordBuf, mut route: F) {
+        observe_log_time(&record);
+
+        let ident = record.ident;
+        let seq = self.seq;
+        self.seq += 1;
+
+        // non transactional records
+        if ident == 0 {
+            return route(0, record)
+        }
+
+        if record.tag == VslRecordTag::SLT_Begin {
+            if let Ok((kind, parent, reason)) = record.as_record().parse_data(slt_begin) {
+                let root = if parent == 0 {
+                    Some(ident)
+                } else if kind == "req" && reason == "rxreq" {
+                    Some(parent)
+                } else {
+                    // root may be known from SLT_Link record of ended parent
+                    self.roots.get(&ident).or_else(|| self.roots.get(&parent)).cloned()
+                };
+
+                match root {
+                    Some(root) => self.resolve(ident, root, &mut route),
+                    None => {
+                        self.held.insert(ident, Held { records: vec![(seq, record)], ended: false });
+                        let mut children = self.waiting.remove(&parent).unwrap_or_default();
+                        children.push(ident);
+                        self.waiting.insert(parent, children);
+                        return
+                    }
+                }
+            }
+        }
+
+        let ended = record.tag == VslRecordTag::SLT_End;
+
+        if let Some(held) = self.held.get_mut(&ident) {
+            let size = size_of::<(u64, VslRecordBuf)>() + record.data.len();
+            held.ended |= ended;
+            held.records.push((seq, record));
+            return self.held.grow(&ident, size)
+        }
+
+        let root = match self.roots.get(&ident).cloned() {
+            Some(root) => root,
+            None => {
+                // transaction started before the stream
+                self.roots.insert(ident, ident);
+                ident
+            }
+        };
+
+        let link = if record.tag == VslRecordTag::SLT_Link {
+            record.as_record().parse_data(slt_link).ok().map(|(_, child, _)| child)
+        } else {
+            None
+        };
+
+        route(self.shard(root), record);
+
+        if let Some(child) = link {
+            self.resolve(child, root, &mut route);
+        }
+        if ended {
+            self.roots.remove(&ident);
+        }
+    }
+
+    // Learns the root of the transaction and of all the held transactions waiting for it
+    fn resolve<F: FnMut(usize, VslRecordBuf)>(&mut self, ident: VslIdent, root: VslIdent, route: &mut F) {
+        let mut released = Vec::new();
+        let mut resolved = vec![ident];
+        let mut first = true;
+
+        while let Some(ident) = resolved.pop() {
+            let ended = match self.held.remove(&ident) {
+                Some(held) => {
+                    released.extend(held.records);
+                    held.ended
+                }
+                None if first => false,
+                // already resolved by SLT_Link record of its parent
+                None => continue,
+            };
+            first = false;
+            if !ended {
+                self.roots.insert(ident, root);
+            }
+            if let Some(children) = self.waiting.remove(&ident) {
+                resolved.extend(children);
+            }
+        }
+
+        released.sort_by_key(|&(seq, _)| seq);
+        for (_, record) in released {
+            route(self.shard(root), record)
+        }
+    }
+
+    /// Passes on all held records routing them by their own vxid; used at the end of the stream
+    pub fn release_held<F: FnMut(usize, VslRecordBuf)>(&mut self, mut route: F) {
+        let idents = self.waiting.values().flatten().cloned().collect::<Vec<_>>();
+        let mut released = Vec::new();
+
+        for ident in idents {
+            if let Some(held) = self.held.remove(&ident) {
+                released.extend(held.records.into_iter().map(|(seq, record)| (seq, ident, record)));
+            }
+        }
+
+        released.sort_by_key(|&(seq, _, _)| seq);
+        for (_, ident, record) in released {
+            route(self.shard(ident), record)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use crate::vsl::reader::VslReader;
+    use crate::vsl::record::Marker;
+
+    fn record(ident: VslIdent, tag: VslRecordTag, data: &str) -> VslRecordBuf {
+        VslRecordBuf {
+            tag,
+            raw_tag: 0,
+            marker: Marker::VSL_CLIENTMARKER,
+            ident,
+            data: data.as_bytes().to_vec(),
+        }
+    }
+
+    fn route_all(router: &mut ShardRouter, records: Vec<VslRecordBuf>) -> Vec<(usize, VslIdent)> {
+        let mut routed = Vec::new();
+        for record in records {
+            router.route(record, |shard, record| routed.push((shard, record.ident)));
+        }
+        routed
+    }
+
+    #[test]
+    fn hold_children_until_root_is_known() {
+        use crate::vsl::record::VslRecordTag::*;
+        let mut router = ShardRouter::new(4);
+
+        // ESI subrequest and its backend fetch end before parent request and session
+        let routed = route_all(&mut router, vec![
+            record(65539, SLT_Begin, "req 65538 esi"),
+            record(65539, SLT_End, ""),
+            record(65542, SLT_Begin, "bereq 65539 fetch"),
+            record(65542, SLT_End, ""),
+        ]);
+        assert!(routed.is_empty());
+        assert_eq!(router.held_count(), 2);
+
+        let routed = route_all(&mut router, vec![
+            record(65538, SLT_Begin, "req 65537 rxreq"),
+            record(65538, SLT_End, ""),
+            record(65537, SLT_Begin, "sess 0 HTTP/1"),
+            record(65537, SLT_End, ""),
+        ]);
+        let shard = 65537 % 4;
+        assert_eq!(routed, vec![
+            (shard, 65539), (shard, 65539), (shard, 65542), (shard, 65542),
+            (shard, 65538), (shard, 65538), (shard, 65537), (shard, 65537),
+        ]);
+        assert_eq!(router.held_count(), 0);
+    }
+
+    #[test]
+    fn bgfetch_after_session_close() {
+        use crate::vsl::record::VslRecordTag::*;
+        let mut router = ShardRouter::new(3);
+
+        let routed = route_all(&mut router, vec![
+            record(65540, SLT_Begin, "req 65539 rxreq"),
+            record(65540, SLT_Link, "bereq 65541 bgfetch"),
+            record(65540, SLT_End, ""),
+            record(65539, SLT_Begin, "sess 0 HTTP/1"),
+            record(65539, SLT_End, ""),
+            record(65541, SLT_Begin, "bereq 65540 bgfetch"),
+            record(65541, SLT_End, ""),
+        ]);
+        assert!(routed.iter().all(|&(shard, _)| shard == 65539 % 3));
+        assert_eq!(routed.len(), 7);
+    }
+
+    #[test]
+    fn forget_roots_of_ended_transactions() {
+        use crate::vsl::record::VslRecordTag::*;
+        let mut router = ShardRouter::new(3);
+
+        let routed = route_all(&mut router, vec![
+            record(11, SLT_Begin, "req 10 rxreq"),
+            record(11, SLT_End, ""),
+        ]);
+        assert_eq!(routed, vec![(1, 11), (1, 11)]);
+
+        // vxid 11 reused by other varnishd instance
+        let routed = route_all(&mut router, vec![
+            record(12, SLT_Begin, "bereq 11 fetch"),
+            record(12, SLT_End, ""),
+            record(11, SLT_Begin, "req 9 rxreq"),
+            record(11, SLT_End, ""),
+        ]);
+        assert_eq!(routed, vec![(0, 12), (0, 12), (0, 11), (0, 11)]);
+    }
+
+    #[test]
+    fn unknown_transactions() {
+        use crate::vsl::record::VslRecordTag::*;
+        let mut router = ShardRouter::new(2);
+
+        // transaction that started before the stream and non transactional record
+        let routed = route_all(&mut router, vec![
+            record(7, SLT_ReqURL, "/"),
+            record(7, SLT_End, ""),
+            record(0, SLT_CLI, "Rd ping"),
+        ]);
+        assert_eq!(routed, vec![(1, 7), (1, 7), (0, 0)]);
+
+        // parent never logged
+        let routed = route_all(&mut router, vec![
+            record(9, SLT_Begin, "bereq 8 fetch"),
+            record(9, SLT_End, ""),
+        ]);
+        assert!(routed.is_empty());
+
+        let mut released = Vec::new();
+        router.release_held(|shard, record| released.push((shard, record.ident)));
+        assert_eq!(released, vec![(1, 9), (1, 9)]);
+        assert_eq!(router.held_count(), 0);
+    }
+
+    #[test]
+    fn route_whole_sessions_to_the_same_shard() {
+        let log = "logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl";
+        let mut router = ShardRouter::new(3);
+        let mut shards = HashMap::new();
+        let mut records = 0;
+
+        for record in VslReader::new(File::open(log).unwrap()) {
+            router.route(record.unwrap(), |shard, record| {
+                records += 1;
+                assert_eq!(*shards.entry(record.ident).or_insert(shard), shard);
+            });
+        }
+        router.release_held(|_, _| records += 1);
+
+        assert_eq!(records, VslReader::new(File::open(log).unwrap()).count());
+        assert_eq!(router.held_count(), 0);
+    }
+}
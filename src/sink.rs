@@ -0,0 +1,809 @@
+// Outputs for VSL records.
+//
+// RecordSink is fed with every VSL record read from the stream; it is up to the sink
+// what it does with it, e.g. print it out as is or correlate it into access records
+// and serialize them once complete.
+// Sinks own their output; `finish` is called once at the end of the stream.
+use std::io::{self, Write};
+use std::fmt::{self, Display};
+use std::error::Error;
+use std::mem;
+use std::num::Wrapping;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use quick_error::quick_error;
+use log::{warn, info};
+
+use crate::stream_buf::StreamBuf;
+use crate::vsl::record::VslRecord;
+use crate::vsl::reader::{VslReader, VslReaderError};
+use crate::vsl::writer::{VslWriter, VslWriterError};
+use crate::vsl::text::{VslTextReader, VslTextReaderError};
+use crate::store::{Config as StoreConfig, log_time};
+use crate::access_log::session_state::{SessionState, SessionStateSnapshot};
+use crate::access_log::record_state::RecordState;
+use crate::access_log::record::ClientAccessRecord;
+use crate::serialization::{log_client_record, log_partial_client_record, log_stats_record, Config, Format, OutputError, Throughput};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SinkError {
+        Io(err: io::Error) {
+            display("Failed to write to output: {}", err)
+            from()
+        }
+        Output(err: OutputError) {
+            display("Failed to output record: {}", err)
+            from()
+        }
+        Other(err: Box<dyn Error + Send + Sync>) {
+            display("Sink error: {}", err)
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ProcessingError {
+        Reading(err: VslReaderError) {
+            display("{}", err)
+            from()
+        }
+        TextReading(err: VslTextReaderError) {
+            display("{}", err)
+            from()
+        }
+        Sink(err: SinkError) {
+            display("{}", err)
+            from()
+        }
+    }
+}
+
+impl From<VslWriterError> for SinkError {
+    fn from(err: VslWriterError) -> SinkError {
+        match err {
+            VslWriterError::Io(err) => SinkError::Io(err),
+            err => SinkError::Other(Box::new(err)),
+        }
+    }
+}
+
+impl SinkError {
+    /// True if output was closed by the other end
+    pub fn is_broken_pipe(&self) -> bool {
+        match *self {
+            SinkError::Io(ref err) |
+            SinkError::Output(OutputError::Io(ref err)) => err.kind() == io::ErrorKind::BrokenPipe,
+            _ => false
+        }
+    }
+}
+
+/// Counts of records written to the output and held by the sink
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SinkStats {
+    /// Records written to the output
+    pub emitted: u64,
+    /// Records still being built or waiting for records they are linked with
+    pub unresolved: usize,
+    /// Transactions that failed to build; their further VSL records are ignored
+    pub tombstoned: usize,
+}
+
+impl Display for SinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "emitted: {} unresolved: {} tombstoned: {}", self.emitted, self.unresolved, self.tombstoned)
+    }
+}
+
+pub trait RecordSink {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError>;
+
+    fn stats(&self) -> SinkStats {
+        SinkStats::default()
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Called once when there are no more records to write
+    fn finish(&mut self) -> Result<(), SinkError> {
+        self.flush()
+    }
+
+    /// Takes in-flight correlation state out of the sink; None if the sink does not support it
+    fn take_state(&mut self) -> Option<SessionStateSnapshot> {
+        None
+    }
+
+    /// Restores correlation state taken with `take_state`; false if the sink does not support it
+    fn restore_state(&mut self, _snapshot: SessionStateSnapshot) -> bool {
+        false
+    }
+}
+
+impl<S: RecordSink + ?Sized> RecordSink for Box<S> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        (**self).write_record(record)
+    }
+
+    fn stats(&self) -> SinkStats {
+        (**self).stats()
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        (**self).flush()
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        (**self).finish()
+    }
+
+    fn take_state(&mut self) -> Option<SessionStateSnapshot> {
+        (**self).take_state()
+    }
+
+    fn restore_state(&mut self, snapshot: SessionStateSnapshot) -> bool {
+        (**self).restore_state(snapshot)
+    }
+}
+
+/// Writes all records from the reader to the sink and finishes it at the end of the stream
+///
+/// Stream ending with incomplete record is not considered an error. The sink is finished on
+/// error as well so that it reports records left (e.g. on broken pipe).
+pub fn process_vsl_records<B, S>(reader: &mut VslReader<B>, sink: &mut S) -> Result<(), ProcessingError> where B: StreamBuf<u8>, S: RecordSink + ?Sized {
+    let result = write_vsl_records(reader, sink);
+    finish_sink(result, sink)
+}
+
+/// Finishes the sink after the result of writing records to it; if writing failed the sink is
+/// still finished for its reports but its error is ignored as output is likely broken
+pub fn finish_sink<S>(result: Result<(), ProcessingError>, sink: &mut S) -> Result<(), ProcessingError> where S: RecordSink + ?Sized {
+    match result {
+        Ok(()) => sink.finish().map_err(From::from),
+        Err(err) => {
+            let _ = sink.finish();
+            Err(err)
+        }
+    }
+}
+
+/// Like `process_vsl_records` but does not finish the sink so that records of the next stream
+/// (e.g. rotated file) can be written to it
+pub fn write_vsl_records<B, S>(reader: &mut VslReader<B>, sink: &mut S) -> Result<(), ProcessingError> where B: StreamBuf<u8>, S: RecordSink + ?Sized {
+    loop {
+        match reader.read_record() {
+            Ok(Some(record)) => sink.write_record(&record)?,
+            Ok(None) => return Ok(()),
+            Err(VslReaderError::Truncated(bytes)) => {
+                warn!("VSL stream ended with incomplete record; ignoring last {} bytes", bytes);
+                return Ok(())
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Writes all records read from varnishlog text output to the sink and finishes it at the end of the stream
+pub fn process_vsl_text_records<R, S>(reader: &mut VslTextReader<R>, sink: &mut S) -> Result<(), ProcessingError> where R: io::BufRead, S: RecordSink + ?Sized {
+    let result = write_vsl_text_records(reader, sink);
+    finish_sink(result, sink)
+}
+
+/// Like `process_vsl_text_records` but does not finish the sink
+pub fn write_vsl_text_records<R, S>(reader: &mut VslTextReader<R>, sink: &mut S) -> Result<(), ProcessingError> where R: io::BufRead, S: RecordSink + ?Sized {
+    while let Some(record) = reader.read_record()? {
+        sink.write_record(&record.as_record())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn session_state_stats(state: &SessionState, emitted: u64) -> SinkStats {
+    SinkStats {
+        emitted,
+        unresolved: state.unresolved_count(),
+        tombstoned: state.tombstone_count(),
+    }
+}
+
+/// Counts parsed VSL records and tells when `varnishslog_stats` record is due
+pub(crate) struct StatsMeter {
+    // in epochs; None to log stats only at the end of the stream
+    interval: Option<u64>,
+    last_epoch: Wrapping<u64>,
+    started: Instant,
+    // timestamp stats records with log time
+    log_time: bool,
+    throughput: Throughput,
+}
+
+impl StatsMeter {
+    pub(crate) fn new(interval: Option<u64>, log_time: bool) -> StatsMeter {
+        StatsMeter {
+            interval,
+            last_epoch: Wrapping(0),
+            started: Instant::now(),
+            log_time,
+            throughput: Throughput::default(),
+        }
+    }
+
+    pub(crate) fn count(&mut self, bytes: usize) {
+        self.throughput.records += 1;
+        self.throughput.bytes += bytes as u64;
+    }
+
+    /// Throughput since the last stats record if the next one is due at given epoch
+    pub(crate) fn due(&mut self, epoch: Wrapping<u64>) -> Option<Throughput> {
+        match self.interval {
+            Some(interval) if epoch - self.last_epoch >= Wrapping(interval) => {
+                self.last_epoch = epoch;
+                Some(self.take())
+            }
+            _ => None,
+        }
+    }
+
+    /// Throughput since the last stats record; rates are always measured in wall clock time
+    pub(crate) fn take(&mut self) -> Throughput {
+        let mut throughput = mem::take(&mut self.throughput);
+        throughput.elapsed = self.started.elapsed();
+        self.started = Instant::now();
+        throughput.timestamp = match log_time() {
+            Some((_first, latest)) if self.log_time => latest as f64,
+            _ => SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs_f64()).unwrap_or(0.0),
+        };
+        throughput
+    }
+}
+
+/// Logs summary of what was incomplete at the end of the stream after unresolved client records
+/// were flushed as partial records
+pub(crate) fn log_flush_summary(state: &SessionState, flushed: &[ClientAccessRecord]) {
+    let unresolved_links: usize = flushed.iter().map(|client| client.unresolved_links().len()).sum();
+    let backend = state.unresolved_backend_access_records().len();
+    let unfinished = state.unresolved_record_builders_records().len();
+
+    if flushed.is_empty() && backend == 0 && unfinished == 0 {
+        info!("No unresolved records to flush at the end of the stream");
+    } else {
+        warn!("Flushed {} unresolved client records as partial records with {} links to transactions that were not logged; backend records left unlinked: {}; records left unfinished: {}",
+              flushed.len(), unresolved_links, backend, unfinished);
+    }
+}
+
+/// Logs records left in the state
+pub(crate) fn log_session_state_reports(state: &SessionState) {
+    let mut all_finished = true;
+    for client in state.unresolved_root_client_access_records() {
+        all_finished = false;
+        warn!("Root ClientAccessRecord left unresolved:\n{:#?}", client)
+    }
+
+    for client in state.unresolved_client_access_records() {
+        all_finished = false;
+        warn!("ClientAccessRecord left unresolved:\n{:#?}", client)
+    }
+
+    for backend in state.unresolved_backend_access_records() {
+        all_finished = false;
+        warn!("BackendAccessRecord left unresolved:\n{:#?}", backend)
+    }
+
+    for builder in state.unresolved_record_builders_records() {
+        all_finished = false;
+        warn!("RecordBuilder left unfinished:\n{:#?}", builder)
+    }
+
+    for session in state.unresolved_record_sessions_records() {
+        all_finished = false;
+        warn!("SessionHead left unfinished:\n{:#?}", session.borrow())
+    }
+
+    if all_finished {
+        info!("No unresolved or unfinished records left")
+    }
+}
+
+/// Writes VSL records in `varnishlog` like format
+pub struct LogSink<W: Write> {
+    output: W,
+    emitted: u64,
+}
+
+impl<W: Write> LogSink<W> {
+    pub fn new(output: W) -> LogSink<W> {
+        LogSink { output, emitted: 0 }
+    }
+}
+
+impl<W: Write> RecordSink for LogSink<W> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        writeln!(self.output, "{:#}", record)?;
+        self.emitted += 1;
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        SinkStats { emitted: self.emitted, ..Default::default() }
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.output.flush().map_err(From::from)
+    }
+}
+
+/// Writes VSL records in binary format readable with `varnishlog -r`
+pub struct VslSink<W: Write> {
+    writer: VslWriter<W>,
+    emitted: u64,
+}
+
+impl<W: Write> VslSink<W> {
+    pub fn new(output: W) -> Result<VslSink<W>, SinkError> {
+        Ok(VslSink {
+            writer: VslWriter::new(output)?,
+            emitted: 0,
+        })
+    }
+}
+
+impl<W: Write> RecordSink for VslSink<W> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        self.writer.write_record(record)?;
+        self.emitted += 1;
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        SinkStats { emitted: self.emitted, ..Default::default() }
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.writer.flush().map_err(From::from)
+    }
+}
+
+/// Writes VSL records debug output
+pub struct LogDebugSink<W: Write> {
+    output: W,
+    emitted: u64,
+}
+
+impl<W: Write> LogDebugSink<W> {
+    pub fn new(output: W) -> LogDebugSink<W> {
+        LogDebugSink { output, emitted: 0 }
+    }
+}
+
+impl<W: Write> RecordSink for LogDebugSink<W> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        writeln!(self.output, "{:#?}", record)?;
+        self.emitted += 1;
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        SinkStats { emitted: self.emitted, ..Default::default() }
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.output.flush().map_err(From::from)
+    }
+}
+
+/// Writes debug output of access records as they are built (not linked)
+pub struct RecordDebugSink<W: Write> {
+    state: RecordState,
+    output: W,
+    emitted: u64,
+}
+
+impl<W: Write> RecordDebugSink<W> {
+    pub fn new(output: W, store_config: &StoreConfig) -> RecordDebugSink<W> {
+        RecordDebugSink {
+            state: RecordState::with_config(store_config),
+            output,
+            emitted: 0,
+        }
+    }
+}
+
+impl<W: Write> RecordSink for RecordDebugSink<W> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        if let Some(record) = self.state.apply(record) {
+            writeln!(self.output, "{:#?}", record)?;
+            self.emitted += 1;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        SinkStats {
+            emitted: self.emitted,
+            unresolved: self.state.building_count(),
+            tombstoned: self.state.tombstone_count(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.output.flush().map_err(From::from)
+    }
+}
+
+/// Writes debug output of linked client access records
+pub struct SessionDebugSink<W: Write> {
+    state: SessionState,
+    output: W,
+    emitted: u64,
+}
+
+impl<W: Write> SessionDebugSink<W> {
+    pub fn new(output: W, store_config: &StoreConfig) -> SessionDebugSink<W> {
+        SessionDebugSink {
+            state: SessionState::with_config(store_config),
+            output,
+            emitted: 0,
+        }
+    }
+}
+
+impl<W: Write> RecordSink for SessionDebugSink<W> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        if let Some(record) = self.state.apply(record) {
+            writeln!(self.output, "{:#?}", record)?;
+            self.emitted += 1;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        session_state_stats(&self.state, self.emitted)
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.output.flush().map_err(From::from)
+    }
+
+    fn take_state(&mut self) -> Option<SessionStateSnapshot> {
+        Some(self.state.take_snapshot())
+    }
+
+    fn restore_state(&mut self, snapshot: SessionStateSnapshot) -> bool {
+        self.state.restore_snapshot(snapshot);
+        true
+    }
+}
+
+/// Serializes linked client access records; reports records left unresolved on finish
+pub struct SerdeSink<W: Write> {
+    state: SessionState,
+    format: Format,
+    config: Config,
+    output: W,
+    emitted: u64,
+    stats: StatsMeter,
+}
+
+impl<W: Write> SerdeSink<W> {
+    pub fn new(output: W, format: Format, config: Config, store_config: &StoreConfig) -> SerdeSink<W> {
+        let mut state = SessionState::with_config(store_config);
+        if config.partial_records {
+            state.keep_partial_records();
+        }
+        SerdeSink {
+            state,
+            format,
+            stats: StatsMeter::new(store_config.stat_epoch_interval(), config.stats_log_time),
+            config,
+            output,
+            emitted: 0,
+        }
+    }
+
+    pub fn session_state(&self) -> &SessionState {
+        &self.state
+    }
+
+    pub fn log_reports(&self) {
+        log_session_state_reports(&self.state)
+    }
+
+    fn log_final_records(&mut self) -> Result<(), SinkError> {
+        if self.config.flush_on_eof {
+            let partial = self.state.drain_partial_records();
+            log_flush_summary(&self.state, &partial);
+            self.log_partial_records(&partial)?;
+        }
+        if self.config.stats_records {
+            let throughput = self.stats.take();
+            self.log_stats_record(&throughput)?;
+        }
+        Ok(())
+    }
+
+    fn log_partial_records(&mut self, records: &[ClientAccessRecord]) -> Result<(), SinkError> {
+        for client in records {
+            log_partial_client_record(client, &self.format, &mut self.output, &self.config)?;
+            self.emitted += 1;
+        }
+        Ok(())
+    }
+
+    fn log_stats_record(&mut self, throughput: &Throughput) -> Result<(), SinkError> {
+        log_stats_record(&self.state.stats(), None, throughput, &self.format, &mut self.output).map_err(From::from)
+    }
+}
+
+impl<W: Write> RecordSink for SerdeSink<W> {
+    fn write_record(&mut self, record: &VslRecord<'_>) -> Result<(), SinkError> {
+        if let Some(client) = self.state.apply(record) {
+            log_client_record(&client, &self.format, &mut self.output, &self.config)?;
+            self.emitted += 1;
+        }
+        let partial = self.state.take_partial_records();
+        self.log_partial_records(&partial)?;
+
+        if self.config.stats_records {
+            self.stats.count(record.data.len());
+            if let Some(throughput) = self.stats.due(self.state.epoch()) {
+                self.log_stats_record(&throughput)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        session_state_stats(&self.state, self.emitted)
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.output.flush().map_err(From::from)
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        // records left are reported even if they could not be written out
+        let result = self.log_final_records();
+        self.log_reports();
+        result.and_then(|()| self.flush())
+    }
+
+    fn take_state(&mut self) -> Option<SessionStateSnapshot> {
+        Some(self.state.take_snapshot())
+    }
+
+    fn restore_state(&mut self, snapshot: SessionStateSnapshot) -> bool {
+        self.state.restore_snapshot(snapshot);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use assert_matches::assert_matches;
+
+    fn process<S: RecordSink>(sink: &mut S) {
+        let mut reader = VslReader::new(File::open("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap());
+        process_vsl_records(&mut reader, sink).unwrap();
+    }
+
+    #[derive(Default)]
+    struct CountingSink {
+        records: usize,
+        finished: bool,
+        broken_pipe: bool,
+    }
+
+    impl RecordSink for CountingSink {
+        fn write_record(&mut self, _record: &VslRecord<'_>) -> Result<(), SinkError> {
+            if self.broken_pipe {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe).into())
+            }
+            self.records += 1;
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), SinkError> {
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_sink() {
+        let mut sink = CountingSink::default();
+        process(&mut sink);
+
+        assert!(sink.records > 0);
+        assert!(sink.finished);
+    }
+
+    #[test]
+    fn finish_sink_on_broken_pipe() {
+        let mut sink = CountingSink { broken_pipe: true, ..Default::default() };
+        let mut reader = VslReader::new(File::open("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap());
+        let err = process_vsl_records(&mut reader, &mut sink).unwrap_err();
+
+        assert_matches!(err, ProcessingError::Sink(ref err) if err.is_broken_pipe());
+        assert!(sink.finished);
+    }
+
+    #[test]
+    fn serde_sink() {
+        let config = Config {
+            no_log_processing: false,
+            keep_raw_log: false,
+            no_header_indexing: false,
+            keep_raw_headers: false,
+            partial_records: false,
+            flush_on_eof: false,
+            stats_records: false,
+            stats_log_time: false,
+            metrics: None,
+        };
+        let mut sink = SerdeSink::new(Vec::new(), Format::Json, config, &Default::default());
+        process(&mut sink);
+
+        assert_eq!(sink.stats(), SinkStats { emitted: 8, unresolved: 0, tombstoned: 0 });
+        let output = String::from_utf8(sink.output).unwrap();
+        assert_eq!(output.lines().count(), 12);
+    }
+
+    #[test]
+    fn serde_sink_flush_on_eof() {
+        let config = Config {
+            no_log_processing: false,
+            keep_raw_log: false,
+            no_header_indexing: false,
+            keep_raw_headers: false,
+            partial_records: false,
+            flush_on_eof: true,
+            stats_records: false,
+            stats_log_time: false,
+            metrics: None,
+        };
+        let mut sink = SerdeSink::new(Vec::new(), Format::Json, config, &Default::default());
+
+        // backend fetch of request 4 is lost
+        let mut reader = VslReader::new(File::open("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap());
+        while let Some(record) = reader.read_record().unwrap() {
+            if record.ident != 5 {
+                sink.write_record(&record).unwrap();
+            }
+        }
+        sink.finish().unwrap();
+
+        assert_eq!(sink.stats().emitted, 8);
+        let output = String::from_utf8(sink.output).unwrap();
+        let partial = output.lines().filter(|line| line.contains(r#""partial":true"#)).collect::<Vec<_>>();
+        assert_eq!(partial.len(), 1);
+        assert!(partial[0].contains(r#""vxid":4,"#));
+        assert!(partial[0].ends_with(r#""unresolved_links":[5]}"#));
+    }
+
+    #[test]
+    fn serde_sink_partial_records() {
+        let config = Config {
+            no_log_processing: false,
+            keep_raw_log: false,
+            no_header_indexing: false,
+            keep_raw_headers: false,
+            partial_records: true,
+            flush_on_eof: false,
+            stats_records: false,
+            stats_log_time: false,
+            metrics: None,
+        };
+        // records waiting for their ESI subrequests are nuked
+        let store_config = StoreConfig::new(1, 1000, 0.01, None, None).unwrap();
+        let mut sink = SerdeSink::new(Vec::new(), Format::Json, config, &store_config);
+        process(&mut sink);
+
+        let output = String::from_utf8(sink.output).unwrap();
+        let partial = output.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|record| record["partial"] == true)
+            .collect::<Vec<_>>();
+        assert!(partial.iter().any(|record| record["record_type"] == "client_request" &&
+                                            record["vxid"] == 65538 &&
+                                            record["unresolved_links"] == serde_json::json!([65539, 65541])));
+        assert!(partial.iter().all(|record| !record["unresolved_links"].as_array().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn serde_sink_stats_records() {
+        let config = Config {
+            no_log_processing: false,
+            keep_raw_log: false,
+            no_header_indexing: false,
+            keep_raw_headers: false,
+            partial_records: false,
+            flush_on_eof: false,
+            stats_records: true,
+            stats_log_time: false,
+            metrics: None,
+        };
+        let store_config = StoreConfig::new(4000, 1000, 0.01, Some(10), None).unwrap();
+        let mut sink = SerdeSink::new(Vec::new(), Format::Json, config, &store_config);
+        process(&mut sink);
+
+        // stats records are not counted as emitted
+        assert_eq!(sink.stats().emitted, 8);
+        let output = String::from_utf8(sink.output).unwrap();
+        let stats = output.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|record| record["record_type"] == "varnishslog_stats")
+            .collect::<Vec<_>>();
+        assert_eq!(output.lines().count(), 12 + stats.len());
+
+        // every 10 transactions and at the end
+        assert_eq!(stats.len(), 4);
+        let last = stats.last().unwrap();
+        assert_eq!(last["epoch"], 34);
+        assert_eq!(last["builders"]["inserted"], 34);
+        assert_eq!(last["client"]["inserted"], last["client"]["removed"]);
+        assert_eq!(last["building"], 0);
+        assert_eq!(last["tombstones"], 0);
+
+        let parsed = stats.iter().map(|record| record["parsed"]["records"].as_u64().unwrap()).sum::<u64>();
+        assert_eq!(parsed, VslReader::new(File::open("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap()).count() as u64);
+    }
+
+    #[test]
+    fn serde_sink_stats_records_in_log_time() {
+        // log time is kept per thread
+        let output = std::thread::spawn(|| {
+            let config = Config {
+                no_log_processing: false,
+                keep_raw_log: false,
+                no_header_indexing: false,
+                keep_raw_headers: false,
+                partial_records: false,
+                flush_on_eof: false,
+                stats_records: true,
+                stats_log_time: true,
+                metrics: None,
+            };
+            let store_config = StoreConfig::new(4000, 1000, 0.01, Some(1), Some(crate::store::log_time_epoch)).unwrap();
+            let mut sink = SerdeSink::new(Vec::new(), Format::Json, config, &store_config);
+            process(&mut sink);
+            String::from_utf8(sink.output).unwrap()
+        }).join().unwrap();
+
+        let stats = output.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|record| record["record_type"] == "varnishslog_stats")
+            .collect::<Vec<_>>();
+        // whole log was written within one second
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0]["timestamp"], 1470304807.0);
+        assert!(stats[0]["parsed"]["records_per_second"].as_f64().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn write_records_of_split_stream() {
+        let log = std::fs::read("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap();
+
+        // split the log in the middle at record boundary
+        let mut reader = VslReader::new(log.as_slice());
+        let mut offsets = Vec::new();
+        while reader.read_record().unwrap().is_some() {
+            offsets.push(reader.offset() as usize);
+        }
+        let split = offsets[offsets.len() / 2];
+
+        let mut sink = SessionDebugSink::new(Vec::new(), &Default::default());
+        write_vsl_records(&mut VslReader::new(&log[..split]), &mut sink).unwrap();
+        let first = sink.stats();
+        assert!(first.unresolved > 0);
+
+        write_vsl_records(&mut VslReader::new(&log[split..]), &mut sink).unwrap();
+        let second = sink.stats();
+        assert_eq!(second, SinkStats { emitted: 8, unresolved: 0, tombstoned: 0 });
+        assert!(first.emitted < second.emitted);
+    }
+}
@@ -12,8 +12,21 @@
 // To constrain memory we just set a limit of how many slots are available at any given
 // time. To constraint time we can count VSL records in u64 or smaller wrapping integer
 // so we can tell if two VslIdent's are from two different times.
+//
+// Since stored records can differ in size a lot (e.g. request with hundreds of headers or deep ESI
+// tree) memory can also be constrained with a budget of bytes shared by all stores created with
+// the same config. Size of stored values is approximated with MemSize. Store that inserts or
+// grows a record over the budget nukes its oldest records in proportion to its share of the used
+// bytes so that stores holding most of the bytes free most of them and small busy stores are not
+// emptied on every insert.
+//
+// Epoch may also be taken from the log itself: with log_time_epoch as the epoch source epoch
+// is the number of seconds of SLT_Timestamp time since the first timestamp seen by the thread
+// so that records expire relative to the data even when old log file is processed at full
+// speed and processing of the same file gives the same results every time.
 use quick_error::quick_error;
 use log::{warn, info};
+use serde::{Serialize, Deserialize};
 
 use linked_hash_map::{self, LinkedHashMap};
 use std::cmp::min;
@@ -21,8 +34,14 @@ use std::num::Wrapping;
 use std::fmt::{self, Debug, Display};
 use fnv::FnvHasher;
 use std::hash::BuildHasherDefault;
+use std::cell::Cell;
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::vsl::record::VslIdent;
+use crate::vsl::record::{AsVslRecord, VslIdent, VslRecordTag, VslRecordBuf};
+use crate::metrics::Metrics;
+use crate::vsl::record::message::parser::slt_timestamp;
 
 // How many VslIdent recorts to keep in the store
 const MAX_SLOTS: usize = 4000;
@@ -31,23 +50,110 @@ const MAX_EPOCH_DIFF: u64 = 14410;
 // How many objects to nuke/expire when store is full or we are expiring as factor of MAX_SLOTS
 const EVICT_FACTOR: f32 = 0.01;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     max_slots: usize,
     max_epoch_diff: u64,
     evict_count: usize,
     stat_epoch_interval: Option<u64>,
     epoch_source: fn(Wrapping<u64>) -> Wrapping<u64>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    metrics: Option<Arc<Metrics>>,
 }
 
+/// Bytes used by all the stores sharing the budget
 #[derive(Debug)]
-struct Stats {
-    inserted: Wrapping<u64>,
-    removed: Wrapping<u64>,
-    expired: Wrapping<u64>,
-    nuked: Wrapping<u64>,
-    slots_free: usize,
-    max_slots: usize,
+pub struct MemoryBudget {
+    max_bytes: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn overrun(&self) -> usize {
+        self.used().saturating_sub(self.max_bytes)
+    }
+}
+
+/// Approximate size of heap memory owned by the value
+pub trait MemSize {
+    fn mem_size(&self) -> usize;
+}
+
+impl MemSize for u8 {
+    fn mem_size(&self) -> usize { 0 }
+}
+
+impl MemSize for u16 {
+    fn mem_size(&self) -> usize { 0 }
+}
+
+impl MemSize for u32 {
+    fn mem_size(&self) -> usize { 0 }
+}
+
+impl MemSize for u64 {
+    fn mem_size(&self) -> usize { 0 }
+}
+
+impl MemSize for String {
+    fn mem_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl MemSize for VslRecordBuf {
+    fn mem_size(&self) -> usize {
+        self.data.capacity()
+    }
+}
+
+impl<T: MemSize> MemSize for Vec<T> {
+    fn mem_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(MemSize::mem_size).sum::<usize>()
+    }
+}
+
+impl<T: MemSize> MemSize for Option<T> {
+    fn mem_size(&self) -> usize {
+        self.as_ref().map_or(0, MemSize::mem_size)
+    }
+}
+
+impl<T: MemSize> MemSize for Box<T> {
+    fn mem_size(&self) -> usize {
+        size_of::<T>() + self.as_ref().mem_size()
+    }
+}
+
+impl<A: MemSize, B: MemSize> MemSize for (A, B) {
+    fn mem_size(&self) -> usize {
+        self.0.mem_size() + self.1.mem_size()
+    }
+}
+
+// Memory used by LinkedHashMap entry (key, value and list pointers) besides heap of the value
+fn entry_size<T>() -> usize {
+    size_of::<(VslIdent, (Wrapping<u64>, usize, T))>() + 2 * size_of::<usize>()
+}
+
+/// Counts of store operations and current use of the store
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub inserted: Wrapping<u64>,
+    pub removed: Wrapping<u64>,
+    pub expired: Wrapping<u64>,
+    pub nuked: Wrapping<u64>,
+    pub slots_free: usize,
+    pub max_slots: usize,
+    pub bytes: usize,
 }
 
 impl Stats {
@@ -57,16 +163,17 @@ impl Stats {
             removed: Wrapping(0),
             expired: Wrapping(0),
             nuked: Wrapping(0),
-            slots_free: 0,
+            slots_free: max_slots,
             max_slots: max_slots,
+            bytes: 0,
         }
     }
 }
 
 impl Display for Stats {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "records inserted: {} removed: {} expired: {} nuked: {} slots used: {} free: {}",
-               self.inserted, self.removed, self.expired, self.nuked, self.max_slots - self.slots_free, self.slots_free)
+        write!(f, "records inserted: {} removed: {} expired: {} nuked: {} slots used: {} free: {} bytes: {}",
+               self.inserted, self.removed, self.expired, self.nuked, self.max_slots - self.slots_free, self.slots_free, self.bytes)
     }
 }
 
@@ -91,6 +198,9 @@ quick_error! {
         InvalidStatEpochInterval {
             display("Stat eposh interval must be greater then 0")
         }
+        InvalidMaxMemory {
+            display("Max memory must be greater then 0")
+        }
     }
 }
 
@@ -98,6 +208,40 @@ fn sequential_epoch(epoch: Wrapping<u64>) -> Wrapping<u64> {
     epoch + Wrapping(1)
 }
 
+thread_local! {
+    // Seconds of the first and the latest log time seen by this thread
+    static LOG_TIME: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+/// Advances log time of the current thread with `SLT_Timestamp` record; other records are ignored
+pub fn observe_log_time<V: AsVslRecord + ?Sized>(vsl: &V) {
+    let vsl = vsl.as_vsl_record();
+    if vsl.tag != VslRecordTag::SLT_Timestamp {
+        return
+    }
+    if let Ok((_label, timestamp, _since_start, _since_last)) = vsl.parse_data(slt_timestamp) {
+        let seconds = timestamp as u64;
+        LOG_TIME.with(|log_time| log_time.set(match log_time.get() {
+            None => Some((seconds, seconds)),
+            // transactions are logged when they end so timestamps are not in order
+            Some((first, latest)) => Some((first, latest.max(seconds))),
+        }));
+    }
+}
+
+/// Seconds of the first and the latest log time observed by the current thread
+pub fn log_time() -> Option<(u64, u64)> {
+    LOG_TIME.with(|log_time| log_time.get())
+}
+
+/// Epoch source giving seconds of log time observed by the current thread
+pub fn log_time_epoch(_epoch: Wrapping<u64>) -> Wrapping<u64> {
+    LOG_TIME.with(|log_time| match log_time.get() {
+        Some((first, latest)) => Wrapping(latest - first),
+        None => Wrapping(0),
+    })
+}
+
 impl Config {
     pub fn new(max_slots: usize, max_epoch_diff: u64, evict_factor: f32, stat_epoch_interval: Option<u64>, epoch_source: Option<fn(Wrapping<u64>) -> Wrapping<u64>>) -> Result<Config, ConfigError> {
         let evict_count = (max_slots as f32 * evict_factor).ceil() as usize;
@@ -121,8 +265,47 @@ impl Config {
             evict_count: evict_count,
             stat_epoch_interval: stat_epoch_interval,
             epoch_source: epoch_source.unwrap_or(sequential_epoch),
+            memory_budget: None,
+            metrics: None,
         })
     }
+
+    /// Limit approximate memory used by all the stores created with this config (or its clones)
+    pub fn set_max_memory(&mut self, max_bytes: Option<usize>) -> Result<(), ConfigError> {
+        if let Some(0) = max_bytes {
+            return Err(ConfigError::InvalidMaxMemory)
+        }
+        self.memory_budget = max_bytes.map(|max_bytes| Arc::new(MemoryBudget {
+            max_bytes,
+            used: AtomicUsize::new(0),
+        }));
+        Ok(())
+    }
+
+    pub fn memory_budget(&self) -> Option<&MemoryBudget> {
+        self.memory_budget.as_deref()
+    }
+
+    pub fn stat_epoch_interval(&self) -> Option<u64> {
+        self.stat_epoch_interval
+    }
+
+    /// Count records expired and nuked by the stores created with this config (or its clones)
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> Option<&Arc<Metrics>> {
+        self.metrics.as_ref()
+    }
+}
+
+/// Record taken out of the store with its age in epochs so that it can be restored to another store
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoreEntry<T> {
+    pub ident: VslIdent,
+    pub age: u64,
+    pub record: T,
 }
 
 type Callback<T> = fn(&str, Wrapping<u64>, Wrapping<u64>, VslIdent, &T) -> ();
@@ -138,8 +321,9 @@ impl<T> fmt::Debug for DebugCallback<T> {
 #[derive(Debug)]
 pub struct VslStore<T: Debug> {
     name: &'static str,
-    // in order of insertion, oldest (lowest epoch) records are at the front
-    store: LinkedHashMap<VslIdent, (Wrapping<u64>, T), BuildHasherDefault<FnvHasher>>,
+    // in order of insertion, oldest (lowest epoch) records are at the front; records are stored
+    // with their approximate size
+    store: LinkedHashMap<VslIdent, (Wrapping<u64>, usize, T), BuildHasherDefault<FnvHasher>>,
     slots_free: usize,
     expire_count: usize,
     nuke_count: usize,
@@ -147,13 +331,17 @@ pub struct VslStore<T: Debug> {
     epoch_source: fn(Wrapping<u64>) -> Wrapping<u64>,
     max_epoch_diff: u64,
     stat_epoch_interval: Option<u64>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    metrics: Option<Arc<Metrics>>,
     stats: Stats,
     last_stats_epoch: Wrapping<u64>,
     on_expire: DebugCallback<Callback<T>>,
     on_nuke: DebugCallback<Callback<T>>,
+    // expired and nuked records waiting to be taken
+    evicted: Option<Vec<(VslIdent, T)>>,
 }
 
-impl<T: Debug> VslStore<T> {
+impl<T: Debug + MemSize> VslStore<T> {
     pub fn new(name: &'static str, on_expire: Option<Callback<T>>, on_nuke: Option<Callback<T>>) -> VslStore<T> {
         VslStore::with_config(name, on_expire, on_nuke, &Default::default())
     }
@@ -169,10 +357,13 @@ impl<T: Debug> VslStore<T> {
             epoch_source: config.epoch_source,
             max_epoch_diff: config.max_epoch_diff,
             stat_epoch_interval: config.stat_epoch_interval,
+            memory_budget: config.memory_budget.clone(),
+            metrics: config.metrics.clone(),
             stats: Stats::new(config.max_slots),
             last_stats_epoch: Wrapping(0),
             on_expire: DebugCallback(on_expire.unwrap_or(Self::log_expire)),
             on_nuke: DebugCallback(on_nuke.unwrap_or(Self::log_nuke)),
+            evicted: None,
         }
     }
 
@@ -194,11 +385,8 @@ impl<T: Debug> VslStore<T> {
 
         assert!(self.slots_free >= 1);
 
-        if self.store.insert(ident, (self.epoch, value)).is_none() {
-            self.slots_free -= 1;
-            self.stats.slots_free = self.slots_free;
-            self.stats.inserted += Wrapping(1);
-        }
+        let epoch = self.epoch;
+        self.put(ident, epoch, value);
 
         if let Some(true) = self.stat_epoch_interval.map(|i| self.epoch - self.last_stats_epoch >= Wrapping(i)) {
             info!("VslStore[{}] (epoch: {}): Statistics: {}", self.name, self.epoch, self.stats);
@@ -206,12 +394,77 @@ impl<T: Debug> VslStore<T> {
         }
     }
 
+    /// Takes all the records out of the store, oldest first
+    pub fn drain(&mut self) -> Vec<StoreEntry<T>> {
+        let epoch = self.epoch;
+        let mut entries = Vec::with_capacity(self.store.len());
+
+        while let Some((ident, (record_epoch, size, record))) = self.store.pop_front() {
+            self.sub_bytes(size);
+            self.slots_free += 1;
+            self.stats.slots_free = self.slots_free;
+            self.stats.removed += Wrapping(1);
+            entries.push(StoreEntry { ident, age: (epoch - record_epoch).0, record });
+        }
+        entries
+    }
+
+    /// Puts back record taken out of (other) store keeping its age; records need to be restored
+    /// oldest first
+    pub fn restore(&mut self, entry: StoreEntry<T>) {
+        if self.slots_free < 1 {
+            self.nuke();
+        }
+
+        let epoch = self.epoch - Wrapping(entry.age);
+        self.put(entry.ident, epoch, entry.record);
+    }
+
+    fn put(&mut self, ident: VslIdent, epoch: Wrapping<u64>, value: T) {
+        let size = entry_size::<T>() + value.mem_size();
+        self.add_bytes(size);
+
+        match self.store.insert(ident, (epoch, size, value)) {
+            None => {
+                self.slots_free -= 1;
+                self.stats.slots_free = self.slots_free;
+                self.stats.inserted += Wrapping(1);
+            }
+            Some((_epoch, size, _record)) => self.sub_bytes(size),
+        }
+        self.nuke_over_budget(ident);
+    }
+
+    /// Accounts for bytes by which the record grew after it was inserted (e.g. via `get_mut`)
+    pub fn grow(&mut self, ident: &VslIdent, bytes: usize) {
+        match self.store.get_mut(ident) {
+            Some(&mut (_epoch, ref mut size, _)) => *size += bytes,
+            None => return,
+        }
+        self.add_bytes(bytes);
+        self.nuke_over_budget(*ident);
+    }
+
+    /// Approximates size of the record again after it was modified (e.g. via `get_mut`)
+    pub fn update_size(&mut self, ident: &VslIdent) {
+        let (old_size, new_size) = match self.store.get_mut(ident) {
+            Some(&mut (_epoch, ref mut size, ref record)) => {
+                let new_size = entry_size::<T>() + record.mem_size();
+                (std::mem::replace(size, new_size), new_size)
+            }
+            None => return,
+        };
+        self.add_bytes(new_size);
+        self.sub_bytes(old_size);
+        self.nuke_over_budget(*ident);
+    }
+
     pub fn get_mut(&mut self, ident: &VslIdent) -> Option<&mut T> {
-        self.store.get_mut(ident).map(|&mut (_epoch, ref mut t)| t)
+        self.store.get_mut(ident).map(|&mut (_epoch, _size, ref mut t)| t)
     }
 
     pub fn get(&self, ident: &VslIdent) -> Option<&T> {
-        self.store.get(ident).map(|&(_epoch, ref t)| t)
+        self.store.get(ident).map(|&(_epoch, _size, ref t)| t)
     }
 
     pub fn contains_key(&self, ident: &VslIdent) -> bool {
@@ -219,23 +472,56 @@ impl<T: Debug> VslStore<T> {
     }
 
     pub fn remove(&mut self, ident: &VslIdent) -> Option<T> {
-        let val = self.store.remove(ident).map(|(_epoch, t)| t);
-        if val.is_some() {
-            self.slots_free += 1;
-            self.stats.slots_free = self.slots_free;
-            self.stats.removed += Wrapping(1);
-        }
-        val
+        let (size, val) = match self.store.remove(ident) {
+            Some((_epoch, size, t)) => (size, t),
+            None => return None,
+        };
+        self.sub_bytes(size);
+        self.slots_free += 1;
+        self.stats.slots_free = self.slots_free;
+        self.stats.removed += Wrapping(1);
+        Some(val)
     }
 
     pub fn values(&self) -> Values<'_, T> {
         Values(self.store.values())
     }
 
+    /// Keep expired and nuked records (after `on_expire` and `on_nuke` are called) so they can be
+    /// taken with `take_evicted` instead of dropping them
+    pub fn keep_evicted(&mut self) {
+        self.evicted.get_or_insert_with(Vec::new);
+    }
+
+    /// Records expired or nuked since last call, oldest first; empty unless `keep_evicted` was called
+    pub fn take_evicted(&mut self) -> Vec<(VslIdent, T)> {
+        self.evicted.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn evict(&mut self, ident: VslIdent, record: T) {
+        if let Some(ref mut evicted) = self.evicted {
+            evicted.push((ident, record));
+        }
+    }
+
+    /// Approximate memory used by the records in the store
+    pub fn bytes(&self) -> usize {
+        self.stats.bytes
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Epoch of the last insert
+    pub fn epoch(&self) -> Wrapping<u64> {
+        self.epoch
+    }
+
     fn expire(&mut self) where T: Debug {
         let to_expire = self.store.values()
             .take(self.expire_count)
-            .take_while(|&&(epoch, _)| self.epoch - epoch >= Wrapping(self.max_epoch_diff))
+            .take_while(|&&(epoch, _, _)| self.epoch - epoch >= Wrapping(self.max_epoch_diff))
             .count();
 
         if to_expire == 0 {
@@ -243,11 +529,16 @@ impl<T: Debug> VslStore<T> {
         }
 
         for _ in 0..to_expire {
-            let (ident, (epoch, record)) = self.store.pop_front().unwrap();
+            let (ident, (epoch, size, record)) = self.store.pop_front().unwrap();
+            self.sub_bytes(size);
             self.slots_free += 1;
             self.stats.slots_free = self.slots_free;
             self.stats.expired += Wrapping(1);
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_expired(self.name);
+            }
             self.on_expire.0(&self.name, self.epoch, epoch, ident, &record);
+            self.evict(ident, record);
         }
     }
 
@@ -255,21 +546,86 @@ impl<T: Debug> VslStore<T> {
         let to_nuke: usize = min(self.nuke_count, self.store.len());
 
         for _ in 0..to_nuke {
-            let (ident, (epoch, record)) = self.store.pop_front().unwrap();
+            let (ident, (epoch, size, record)) = self.store.pop_front().unwrap();
+            self.sub_bytes(size);
             self.slots_free += 1;
             self.stats.slots_free = self.slots_free;
             self.stats.nuked += Wrapping(1);
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_nuked(self.name);
+            }
             self.on_nuke.0(&self.name, self.epoch, epoch, ident, &record);
+            self.evict(ident, record);
+        }
+    }
+
+    // Nuke oldest records other than the one just inserted or updated freeing up to this store's
+    // share of the budget overrun; other stores free their shares when they insert or grow
+    fn nuke_over_budget(&mut self, keep: VslIdent) where T: Debug {
+        let (overrun, used) = match self.memory_budget {
+            Some(ref budget) => (budget.overrun(), budget.used()),
+            None => return,
+        };
+        if overrun == 0 {
+            return
+        }
+
+        let share = (overrun as u128 * self.stats.bytes as u128).div_ceil(used as u128) as usize;
+
+        let mut freed = 0;
+        let to_nuke = self.store.iter()
+            .filter(|&(ident, _)| *ident != keep)
+            .take_while(|&(_, &(_, size, _))| {
+                freed += size;
+                freed <= share
+            })
+            .map(|(ident, _)| *ident)
+            .collect::<Vec<_>>();
+
+        for ident in to_nuke {
+            let (epoch, size, record) = self.store.remove(&ident).unwrap();
+            self.sub_bytes(size);
+            self.slots_free += 1;
+            self.stats.slots_free = self.slots_free;
+            self.stats.nuked += Wrapping(1);
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_nuked(self.name);
+            }
+            self.on_nuke.0(self.name, self.epoch, epoch, ident, &record);
+            self.evict(ident, record);
+        }
+    }
+
+    fn add_bytes(&mut self, bytes: usize) {
+        self.stats.bytes += bytes;
+        if let Some(ref budget) = self.memory_budget {
+            budget.used.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn sub_bytes(&mut self, bytes: usize) {
+        self.stats.bytes -= bytes;
+        if let Some(ref budget) = self.memory_budget {
+            budget.used.fetch_sub(bytes, Ordering::Relaxed);
         }
     }
 }
 
-pub struct Values<'a, T>(linked_hash_map::Values<'a, VslIdent, (Wrapping<u64>, T)>);
+impl<T: Debug> Drop for VslStore<T> {
+    fn drop(&mut self) {
+        // records left in the store are freed
+        if let Some(ref budget) = self.memory_budget {
+            budget.used.fetch_sub(self.stats.bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct Values<'a, T>(linked_hash_map::Values<'a, VslIdent, (Wrapping<u64>, usize, T)>);
 
 impl<'a, T> Iterator for Values<'a, T> {
     type Item = &'a T;
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.next().map(|v| &v.1)
+        self.0.next().map(|v| &v.2)
     }
 }
 
@@ -280,7 +636,7 @@ mod tests {
     use crate::vsl::record::VslIdent;
     impl<T: Debug> VslStore<T> {
         pub fn oldest(&self) -> Option<(&VslIdent, &T)> {
-            self.store.front().map(|(i, v)| (i, &v.1))
+            self.store.front().map(|(i, v)| (i, &v.2))
         }
     }
 
@@ -294,6 +650,21 @@ mod tests {
         assert_eq!(*s.oldest().unwrap().0, 130 - 10);
     }
 
+    #[test]
+    fn nuking_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let mut config = Config::new(10, 200, 0.1, None, None).unwrap();
+        config.set_metrics(Some(metrics.clone()));
+
+        let mut s = VslStore::with_config("foo", None, None, &config);
+        for i in 0..130 {
+            s.insert(i, i);
+        }
+
+        assert_eq!(s.stats().nuked, Wrapping(120));
+        assert!(metrics.render().contains("varnishslog_store_nuked_total{store=\"foo\"} 120\n"));
+    }
+
     #[test]
     fn slot_count() {
         let mut s = VslStore::with_config("foo", None, None, &Config::new(10, 100, 0.1, None, None).unwrap());
@@ -315,6 +686,137 @@ mod tests {
         assert_eq!(*s.oldest().unwrap().0, 13 - 10);
     }
 
+    fn timestamp(time: &str) -> crate::vsl::record::VslRecordBuf {
+        crate::vsl::record::VslRecordBuf {
+            tag: VslRecordTag::SLT_Timestamp,
+            raw_tag: 0,
+            marker: crate::vsl::record::Marker::VSL_CLIENTMARKER,
+            ident: 1,
+            data: format!("Resp: {} 0.000000 0.000000", time).into_bytes(),
+        }
+    }
+
+    #[test]
+    fn log_time_epoch_source() {
+        let mut s = VslStore::with_config("foo", None, None, &Config::new(200, 10, 0.1, None, Some(log_time_epoch)).unwrap());
+
+        observe_log_time(&timestamp("1470304807.000000"));
+        s.insert(1, 1u32);
+        observe_log_time(&timestamp("1470304812.000000"));
+        s.insert(2, 2);
+        // out of order timestamps do not move the log time back
+        observe_log_time(&timestamp("1470304801.000000"));
+        s.insert(3, 3);
+        assert_eq!(log_time_epoch(Wrapping(0)), Wrapping(5));
+        assert_eq!(*s.oldest().unwrap().0, 1);
+
+        observe_log_time(&timestamp("1470304817.500000"));
+        s.insert(4, 4);
+        assert_eq!(*s.oldest().unwrap().0, 2);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let mut config = Config::new(100, 200, 0.1, None, None).unwrap();
+        let record = entry_size::<String>() + 100;
+        config.set_max_memory(Some(3 * record)).unwrap();
+
+        let mut a = VslStore::with_config("a", None, None, &config);
+        let mut b = VslStore::with_config("b", None, None, &config);
+        for i in 0..3 {
+            a.insert(i, "a".repeat(100));
+        }
+        assert_eq!(a.bytes(), 3 * record);
+
+        // budget is shared; b holds no other records to nuke so it goes over it
+        b.insert(10, "b".repeat(100));
+        assert_eq!(config.memory_budget().unwrap().used(), 4 * record);
+
+        // a holds 4/5 of the bytes so it frees up to 4/5 of the overrun
+        a.insert(3, "a".repeat(100));
+        assert_eq!(*a.oldest().unwrap().0, 1);
+        assert_eq!(a.bytes(), 3 * record);
+        assert_eq!(b.bytes(), record);
+
+        // growing record is not nuked
+        a.grow(&3, 1000);
+        assert_eq!(*a.oldest().unwrap().0, 3);
+        assert_eq!(a.bytes(), record + 1000);
+
+        drop(a);
+        assert_eq!(config.memory_budget().unwrap().used(), b.bytes());
+        assert!(config.clone().set_max_memory(Some(0)).is_err());
+    }
+
+    #[test]
+    fn memory_budget_shares() {
+        let mut config = Config::new(100, 200, 0.1, None, None).unwrap();
+        let record = entry_size::<String>() + 100;
+        config.set_max_memory(Some(8 * record)).unwrap();
+
+        let mut a = VslStore::with_config("a", None, None, &config);
+        let mut b = VslStore::with_config("b", None, None, &config);
+        for i in 0..8 {
+            b.insert(i, "b".repeat(100));
+        }
+
+        // b holds the bytes so small store inserting over the budget keeps its records
+        a.insert(10, "a".repeat(100));
+        a.insert(11, "a".repeat(100));
+        assert_eq!(*a.oldest().unwrap().0, 10);
+        assert_eq!(a.bytes(), 2 * record);
+        assert_eq!(config.memory_budget().unwrap().used(), 10 * record);
+
+        // b frees most of the overrun when it inserts
+        b.insert(8, "b".repeat(100));
+        assert_eq!(*b.oldest().unwrap().0, 2);
+        assert_eq!(b.bytes(), 7 * record);
+        assert_eq!(a.bytes(), 2 * record);
+        assert_eq!(config.memory_budget().unwrap().used(), 9 * record);
+    }
+
+    #[test]
+    fn drain_and_restore() {
+        let config = Config::new(200, 10, 0.1, None, None).unwrap();
+        let mut s = VslStore::with_config("foo", None, None, &config);
+        for i in 0..5 {
+            s.insert(i, i);
+        }
+
+        let entries = s.drain();
+        assert!(s.oldest().is_none());
+        assert_eq!(s.bytes(), 0);
+        assert_eq!(entries.iter().map(|entry| (entry.ident, entry.age)).collect::<Vec<_>>(),
+                   vec![(0, 4), (1, 3), (2, 2), (3, 1), (4, 0)]);
+
+        // records keep their age in the new store and expire as if they were never moved
+        let mut s = VslStore::with_config("foo", None, None, &config);
+        for entry in entries {
+            s.restore(entry);
+        }
+        for i in 5..10 {
+            s.insert(i, i);
+        }
+        assert_eq!(*s.oldest().unwrap().0, 0);
+        s.insert(10, 10);
+        assert_eq!(*s.oldest().unwrap().0, 1);
+    }
+
+    #[test]
+    fn keep_evicted() {
+        let mut s = VslStore::with_config("foo", None, None, &Config::new(10, 10, 0.1, None, None).unwrap());
+        for i in 0..10 {
+            s.insert(i, i);
+        }
+        assert!(s.take_evicted().is_empty());
+
+        s.keep_evicted();
+        s.insert(10, 10);
+        s.insert(11, 11);
+        assert_eq!(s.take_evicted(), vec![(0, 0), (1, 1)]);
+        assert!(s.take_evicted().is_empty());
+    }
+
     #[test]
     fn expire() {
         let mut s = VslStore::with_config("foo", None, None, &Config::new(200, 10, 0.1, None, None).unwrap());
@@ -4,8 +4,11 @@ use std::any::Any;
 use std::cell::Cell;
 use std::io::Read;
 use std::io;
+use std::fs::File;
+use std::path::Path;
 use std::ptr::copy;
 use log::trace;
+use memmap2::Mmap;
 use nom;
 
 pub const DEFAULT_BUF_SIZE: usize = 256 * 1024;
@@ -262,13 +265,103 @@ impl<R: Read> StreamBuf<u8> for ReadStreamBuf<R> {
     }
 }
 
+/// Stream buffer over memory mapped file; parsed data is borrowed straight from the mapping
+/// so nothing is copied or relocated and there is no limit on the size of the data parsed at once
+///
+/// Only regular files can be mapped; other files (e.g. FIFOs or `/dev/stdin`) need to be read
+/// with `ReadStreamBuf`.
+///
+/// The file should not be modified while mapped (e.g. it should not be a file that is still
+/// being written to) as the mapping would change under borrowed data. If the file gets truncated
+/// while mapped, accessing the pages past its new end kills the process with SIGBUS.
+pub struct MmapStreamBuf {
+    mmap: Option<Mmap>,
+    needed: Cell<Option<nom::Needed>>,
+    offset: Cell<usize>,
+}
+
+impl MmapStreamBuf {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MmapStreamBuf> {
+        MmapStreamBuf::from_file(&File::open(path)?)
+    }
+
+    pub fn from_file(file: &File) -> io::Result<MmapStreamBuf> {
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"))
+        }
+
+        // empty files cannot be mapped
+        let mmap = if metadata.len() == 0 {
+            None
+        } else {
+            Some(unsafe { Mmap::map(file)? })
+        };
+
+        Ok(MmapStreamBuf {
+            mmap,
+            needed: Cell::new(Some(nom::Needed::Unknown)),
+            offset: Cell::new(0),
+        })
+    }
+
+    fn mapped(&self) -> &[u8] {
+        self.mmap.as_ref().map(|mmap| mmap.as_ref()).unwrap_or(&[])
+    }
+}
+
+impl StreamBuf<u8> for MmapStreamBuf {
+    /// All the data is already available; fails with UnexpectedEof if there is not enough of it
+    fn fill(&mut self, min_bytes: usize) -> Result<(), FillError> {
+        if self.data().len() < min_bytes {
+            return Err(FillError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "end of mapped file")))
+        }
+        Ok(())
+    }
+
+    fn relocate(&mut self) {
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        let consume = bytes.min(self.data().len());
+        self.offset.set(self.offset.get() + consume);
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.mapped()[self.offset.get()..]
+    }
+
+    fn needed(&self) -> Option<nom::Needed> {
+        self.needed.get()
+    }
+
+    fn apply<'b, C, CO, E>(&'b self, combinator: C) -> Result<Option<CO>, nom::Err<&'b [u8], E>> where
+        C: Fn(&'b [u8]) -> nom::IResult<&'b [u8], CO, E> {
+        let data = self.data();
+        match combinator(data) {
+            nom::IResult::Done(left, out) => {
+                self.offset.set(self.offset.get() + data.len() - left.len());
+                self.needed.set(None);
+                Ok(Some(out))
+            },
+            nom::IResult::Error(err) => Err(err),
+            nom::IResult::Incomplete(needed) => {
+                self.needed.set(Some(needed));
+                Ok(None)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::StreamBuf;
     use super::ReadStreamBuf;
+    use super::MmapStreamBuf;
     use super::FillError;
     use nom::{IResult, Needed};
-    use std::io::Cursor;
+    use std::io::{Cursor, Read};
+    use std::fs::File;
 
     fn subject(data: Vec<u8>) -> ReadStreamBuf<Cursor<Vec<u8>>> {
         ReadStreamBuf::new(Cursor::new(data))
@@ -398,6 +491,74 @@ mod tests {
         assert_eq!(rsb.fill_apply(be_u8).unwrap(), Some(3));
     }
 
+    fn mmap_subject(name: &str, data: &[u8]) -> MmapStreamBuf {
+        let path = std::env::temp_dir().join(format!("varnishslog-mmap-test-{}-{}", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let msb = MmapStreamBuf::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        msb
+    }
+
+    #[test]
+    fn mmap_apply() {
+        use nom::be_u8;
+        let mut msb = mmap_subject("apply", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+        fn comb(input: &[u8]) -> IResult<&[u8], &[u8]> {
+            tag!(input, [0, 1, 2])
+        }
+
+        assert_eq!(msb.fill_apply(comb).unwrap(), Some([0, 1, 2].as_ref()));
+        assert_eq!(msb.fill_apply(be_u8).unwrap(), Some(3));
+        msb.consume(4);
+        assert_eq!(msb.data(), [8, 9].as_ref());
+        msb.consume(3);
+        assert!(msb.data().is_empty());
+    }
+
+    #[test]
+    fn mmap_end_of_data() {
+        let mut msb = mmap_subject("eof", &[0, 1, 2]);
+
+        fn comb(input: &[u8]) -> IResult<&[u8], &[u8]> {
+            take!(input, 4)
+        }
+
+        assert!(msb.apply(comb).unwrap().is_none());
+        assert_eq!(msb.needed(), Some(Needed::Size(4)));
+        match msb.fill(4).unwrap_err() {
+            FillError::Io(err) => assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof),
+            err => panic!("was expecting Io error; got: {}", err),
+        }
+
+        let msb = mmap_subject("empty", &[]);
+        assert!(msb.data().is_empty());
+    }
+
+    #[test]
+    fn mmap_fifo() {
+        let path = std::env::temp_dir().join(format!("varnishslog-mmap-test-fifo-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+
+        // opening FIFO for reading blocks until there is a writer
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || std::fs::write(path, [0, 1, 2]).unwrap())
+        };
+        let mut file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // FIFO reports length of 0 so it has to be read and not mapped as empty file
+        let err = MmapStreamBuf::from_file(&file).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [0, 1, 2]);
+        writer.join().unwrap();
+    }
+
     #[test]
     fn fill_over_buf() {
         let mut rsb = subject_with_default_data();
@@ -1 +1,5 @@
 pub mod record;
+pub mod reader;
+pub mod writer;
+pub mod text;
+pub mod vsm;
@@ -0,0 +1,621 @@
+// Reads VSL records from binary log stream as written by `varnishlog -w`.
+//
+// The stream may start with "VSL\0" file header that is skipped if present.
+// Records are parsed in place in the ReadStreamBuf buffer so VslRecord returned by the
+// reader borrows its data from it and is only valid until next record is read.
+// Files can be read through MmapStreamBuf instead in which case records borrow their data
+// directly from the memory mapping.
+//
+// With resync enabled records that do not look plausible (unknown tag, markers not matching
+// ident, missing NUL terminator, longer than vsl_reclen of varnishd) are treated as corrupted
+// data and skipped 4 bytes at a time (records are 4 byte aligned) until next plausible record
+// is found.
+//
+// Tag numbers depend on Varnish release that wrote the stream; unless given, tag numbering is
+// detected from the tag number of the first transaction record which is always SLT_Begin.
+//
+// Batch records (SLT__Batch) written by Varnish when vsl_buffer is flushed to the shared memory
+// log are unpacked: the batch header is skipped and records it contains are read one by one.
+//
+// Once stop flag is set the reader ends the stream before the next record so that input that
+// keeps coming (e.g. followed file) can be stopped on a record boundary.
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt::{self, Display};
+use std::cmp::min;
+use quick_error::quick_error;
+use log::{info, warn};
+use nom;
+
+use crate::stream_buf::{StreamBuf, ReadStreamBuf, MmapStreamBuf, FillError, FillApplyError, DEFAULT_BUF_SIZE};
+use super::record::{VslRecord, VslRecordBuf};
+use super::record::tag_table::{TagTable, VarnishVersion};
+use super::record::parser::{binary_vsl_tag, vsl_batch_header, vsl_record_v4, vsl_record_v4_checked, DEFAULT_MAX_RECORD_LEN};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VslReaderError {
+        Io(err: io::Error) {
+            display("Failed to read VSL stream: {}", err)
+            from()
+        }
+        InputBuffer(err: FillError) {
+            display("Failed to fill parsing buffer: {}", err)
+        }
+        Truncated(bytes: usize) {
+            display("VSL stream ended in the middle of a record; {} bytes left unparsed", bytes)
+        }
+        Parse(kind: nom::ErrorKind<u32>, offset: u64) {
+            display("Failed to parse VSL record at stream offset {}: {}", offset, kind.description())
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VslReaderStats {
+    /// Number of records read
+    pub records: u64,
+    /// Number of batch records unpacked
+    pub batches: u64,
+    /// Number of corrupted regions skipped to find next plausible record
+    pub resyncs: u64,
+    /// Bytes of corrupted data skipped
+    pub skipped_bytes: u64,
+    /// Bytes of incomplete record at the end of the stream
+    pub truncated_bytes: u64,
+}
+
+impl Display for VslReaderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "records: {} batches: {} resyncs: {} skipped bytes: {} truncated bytes: {}",
+               self.records, self.batches, self.resyncs, self.skipped_bytes, self.truncated_bytes)
+    }
+}
+
+// Owned version of the FillApplyError so that it does not keep the buffer borrowed
+enum ReadFailure {
+    EndOfStream,
+    Corrupted,
+    Error(VslReaderError),
+}
+
+impl ReadFailure {
+    fn from_fill_apply(err: FillApplyError<&[u8], u32>, offset: u64) -> ReadFailure {
+        match err {
+            FillApplyError::FillError(FillError::Io(err)) => if err.kind() == io::ErrorKind::UnexpectedEof {
+                ReadFailure::EndOfStream
+            } else {
+                ReadFailure::Error(VslReaderError::Io(err))
+            },
+            FillApplyError::FillError(err) => ReadFailure::Error(VslReaderError::InputBuffer(err)),
+            FillApplyError::Parser(err) => ReadFailure::Error(VslReaderError::Parse(error_kind(err), offset)),
+        }
+    }
+}
+
+fn error_kind(err: nom::Err<&[u8], u32>) -> nom::ErrorKind<u32> {
+    match err {
+        nom::Err::Code(kind) |
+        nom::Err::Node(kind, _) |
+        nom::Err::Position(kind, _) |
+        nom::Err::NodePosition(kind, _, _) => kind,
+    }
+}
+
+// Succeeds without consuming any input once the whole record is available in the buffer
+// Records are checked to be plausible if longest record data length is given
+fn vsl_record(input: &[u8], max_len: Option<usize>) -> nom::IResult<&[u8], VslRecord<'_>, u32> {
+    match max_len {
+        Some(max_len) => vsl_record_v4_checked(input, max_len),
+        None => vsl_record_v4(input),
+    }
+}
+
+fn vsl_record_available(input: &[u8], max_len: Option<usize>) -> nom::IResult<&[u8], (), u32> {
+    peek!(input, map!(call!(vsl_record, max_len), |_| ()))
+}
+
+fn nothing(input: &[u8]) -> nom::IResult<&[u8], (), u32> {
+    nom::IResult::Done(input, ())
+}
+
+fn detect_tag_table(first_transaction_tag: u8) -> TagTable {
+    match TagTable::detect(first_transaction_tag) {
+        Some(tag_table) => {
+            info!("Using VSL tag numbering of Varnish {} (detected; compatible with older releases with the same SLT_Begin number)", tag_table.version());
+            tag_table
+        }
+        None => {
+            let tag_table = TagTable::default();
+            warn!("Failed to detect Varnish version from first transaction record tag number {}; using VSL tag numbering of Varnish {}",
+                  first_transaction_tag, tag_table.version());
+            tag_table
+        }
+    }
+}
+
+pub struct VslReader<S: StreamBuf<u8>> {
+    stream: S,
+    header_checked: bool,
+    offset: u64,
+    resync: bool,
+    max_record_len: usize,
+    tag_table: Option<TagTable>,
+    stop: Option<Arc<AtomicBool>>,
+    stats: VslReaderStats,
+}
+
+impl<R: Read> VslReader<ReadStreamBuf<R>> {
+    pub fn new(input: R) -> VslReader<ReadStreamBuf<R>> {
+        VslReader::with_capacity(input, DEFAULT_BUF_SIZE)
+    }
+
+    /// Buffer capacity needs to be bigger than the biggest VSL record in the stream
+    pub fn with_capacity(input: R, buf_size: usize) -> VslReader<ReadStreamBuf<R>> {
+        VslReader::from_stream_buf(ReadStreamBuf::with_capacity(input, buf_size))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.stream.into_inner()
+    }
+}
+
+impl VslReader<MmapStreamBuf> {
+    /// Reads records of the file through memory mapping; see MmapStreamBuf
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> io::Result<VslReader<MmapStreamBuf>> {
+        Ok(VslReader::from_stream_buf(MmapStreamBuf::open(path)?))
+    }
+}
+
+impl<S: StreamBuf<u8>> VslReader<S> {
+    pub fn from_stream_buf(stream: S) -> VslReader<S> {
+        VslReader {
+            stream,
+            header_checked: false,
+            offset: 0,
+            resync: false,
+            max_record_len: DEFAULT_MAX_RECORD_LEN,
+            tag_table: None,
+            stop: None,
+            stats: VslReaderStats::default(),
+        }
+    }
+
+    /// Use tag numbering of given Varnish release instead of detecting it
+    pub fn set_varnish_version(&mut self, version: VarnishVersion) {
+        self.tag_table = Some(TagTable::for_version(version));
+    }
+
+    /// Tag numbering in use; `None` until detected
+    pub fn tag_table(&self) -> Option<&TagTable> {
+        self.tag_table.as_ref()
+    }
+
+    /// Skip corrupted data instead of failing with parse error
+    pub fn enable_resync(&mut self) {
+        self.resync = true;
+    }
+
+    /// Longest record data that is not considered corrupted when resyncing; should be set to
+    /// vsl_reclen parameter of varnishd that wrote the stream if it was raised over the default
+    /// (DEFAULT_MAX_RECORD_LEN is the most it can be with default vsl_buffer)
+    pub fn set_max_record_len(&mut self, max_len: usize) {
+        self.max_record_len = max_len;
+    }
+
+    /// End the stream before the next record once the flag is set (e.g. by signal handler); the
+    /// rest of the stream is left unread and `offset` tells where it starts
+    pub fn set_stop_flag(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop.as_ref().is_some_and(|stop| stop.load(Ordering::Relaxed))
+    }
+
+    pub fn stats(&self) -> &VslReaderStats {
+        &self.stats
+    }
+
+    /// Number of stream bytes consumed so far (header included)
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn end_of_stream<T>(&mut self) -> Result<Option<T>, VslReaderError> {
+        match self.stream.data().len() {
+            0 => Ok(None),
+            bytes => {
+                self.stats.truncated_bytes = bytes as u64;
+                Err(VslReaderError::Truncated(bytes))
+            }
+        }
+    }
+
+    fn skip_corrupted(&mut self, skipping: &mut Option<u64>) {
+        if skipping.is_none() {
+            warn!("Corrupted VSL data at stream offset {}; looking for next record", self.offset);
+            self.stats.resyncs += 1;
+            *skipping = Some(self.offset);
+        }
+
+        let bytes = min(4, self.stream.data().len());
+        self.stream.consume(bytes);
+        // clear what parser needed for the data we have just skipped
+        let _ = self.stream.apply(nothing);
+
+        self.offset += bytes as u64;
+        self.stats.skipped_bytes += bytes as u64;
+    }
+
+    fn read_header(&mut self) -> Result<Option<()>, VslReaderError> {
+        loop {
+            let failure = match self.stream.fill_apply(binary_vsl_tag) {
+                Ok(None) => continue,
+                Ok(Some(Some(_))) => {
+                    info!("Found VSL tag");
+                    self.offset += 4;
+                    break
+                }
+                Ok(Some(None)) => break,
+                Err(err) => ReadFailure::from_fill_apply(err, self.offset),
+            };
+
+            return match failure {
+                ReadFailure::EndOfStream => self.end_of_stream(),
+                ReadFailure::Error(err) => Err(err),
+                ReadFailure::Corrupted => unreachable!("VSL header is optional"),
+            }
+        }
+        self.header_checked = true;
+        Ok(Some(()))
+    }
+
+    /// Reads next record from the stream; returns `None` at the end of the stream
+    pub fn read_record(&mut self) -> Result<Option<VslRecord<'_>>, VslReaderError> {
+        if !self.header_checked && self.read_header()?.is_none() {
+            return Ok(None)
+        }
+        if self.is_stopped() {
+            return Ok(None)
+        }
+
+        let max_len = if self.resync {
+            Some(self.max_record_len)
+        } else {
+            None
+        };
+
+        // Make sure that whole record is in the buffer before parsing it out for real so the
+        // record borrowing from the buffer can be returned from within the loop
+        let mut skipping = None;
+        loop {
+            let failure = match self.stream.fill_apply(vsl_batch_header) {
+                Ok(None) => continue,
+                Ok(Some(Some(_batch_len))) => {
+                    // records of the batch follow its header
+                    self.offset += 8;
+                    self.stats.batches += 1;
+                    continue
+                }
+                Ok(Some(None)) => match self.stream.fill_apply(|input| vsl_record_available(input, max_len)) {
+                    Ok(None) => continue,
+                    Ok(Some(())) => break,
+                    Err(FillApplyError::Parser(_)) if self.resync => ReadFailure::Corrupted,
+                    Err(err) => ReadFailure::from_fill_apply(err, self.offset),
+                },
+                Err(err) => ReadFailure::from_fill_apply(err, self.offset),
+            };
+
+            match failure {
+                ReadFailure::Corrupted => self.skip_corrupted(&mut skipping),
+                ReadFailure::EndOfStream => return self.end_of_stream(),
+                ReadFailure::Error(err) => return Err(err),
+            }
+        }
+
+        if let Some(start) = skipping {
+            warn!("Found next VSL record at stream offset {} after skipping {} bytes", self.offset, self.offset - start);
+        }
+
+        let available = self.stream.data().len();
+        match self.stream.apply(|input| vsl_record(input, max_len)) {
+            Ok(Some(mut record)) => {
+                self.offset += (available - self.stream.data().len()) as u64;
+                self.stats.records += 1;
+
+                if self.tag_table.is_none() && (record.is_client() || record.is_backend()) {
+                    self.tag_table = Some(detect_tag_table(record.raw_tag));
+                }
+                record.tag = self.tag_table.unwrap_or_default().tag(record.raw_tag);
+                Ok(Some(record))
+            }
+            _ => unreachable!("VSL record was already found complete in the buffer"),
+        }
+    }
+}
+
+/// Yields owned copies of the records; use `read_record` to avoid copying
+impl<S: StreamBuf<u8>> Iterator for VslReader<S> {
+    type Item = Result<VslRecordBuf, VslReaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().map(|record| record.map(|record| record.to_record_buf())).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::vsl::record::{Marker, VslRecordTag};
+    use crate::vsl::record::parser::VSL_RECORD_BAD_LENGTH;
+
+    fn subject(data: &[u8]) -> VslReader<ReadStreamBuf<Cursor<Vec<u8>>>> {
+        VslReader::new(Cursor::new(data.to_vec()))
+    }
+
+    // 76 SLT_Begin, ident 3 with client marker and "sess 0 HTTP/1" message
+    const BEGIN: &[u8] = b"\x0e\x00\x00\x4c\x03\x00\x00\x40sess 0 HTTP/1\x00\x00\x00";
+
+    #[test]
+    fn read_records_with_header() {
+        let mut data = b"VSL\0".to_vec();
+        data.extend_from_slice(BEGIN);
+        data.extend_from_slice(BEGIN);
+        let mut reader = subject(&data);
+
+        {
+            let record = reader.read_record().unwrap().unwrap();
+            assert_eq!(record.tag, VslRecordTag::SLT_Begin);
+            assert_eq!(record.marker, Marker::VSL_CLIENTMARKER);
+            assert_eq!(record.ident, 3);
+            assert_eq!(record.data, b"sess 0 HTTP/1");
+        }
+        assert_eq!(reader.offset(), 4 + BEGIN.len() as u64);
+        assert!(reader.read_record().unwrap().is_some());
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_records_without_header() {
+        let mut reader = subject(BEGIN);
+
+        assert!(reader.read_record().unwrap().is_some());
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_empty_stream() {
+        let mut reader = subject(b"");
+        assert!(reader.read_record().unwrap().is_none());
+
+        let mut reader = subject(b"VSL\0");
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn stop_on_record_boundary() {
+        let mut data = b"VSL\0".to_vec();
+        data.extend_from_slice(BEGIN);
+        data.extend_from_slice(BEGIN);
+        let mut reader = subject(&data);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        reader.set_stop_flag(stop.clone());
+
+        assert!(reader.read_record().unwrap().is_some());
+        stop.store(true, Ordering::Relaxed);
+        assert!(reader.read_record().unwrap().is_none());
+        assert_eq!(reader.offset(), 4 + BEGIN.len() as u64);
+        assert_eq!(reader.stats().truncated_bytes, 0);
+    }
+
+    #[test]
+    fn read_truncated_stream() {
+        let mut reader = subject(&BEGIN[0..10]);
+
+        match reader.read_record() {
+            Err(VslReaderError::Truncated(10)) => (),
+            result => panic!("expected Truncated error; got: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn read_corrupted_stream() {
+        let mut data = b"VSL\0".to_vec();
+        data.extend_from_slice(BEGIN);
+        // bogus tag, bad marker and missing NUL terminator
+        data.extend_from_slice(b"\x04\x00\x00\xf0\x03\x00\x00\x40foo\x00");
+        data.extend_from_slice(b"\x04\x00\x00\x4c\x03\x00\x00\xc0foo\x00");
+        data.extend_from_slice(b"\x08\x00\x00\x4c\x03\x00\x00\x40foobarba");
+        data.extend_from_slice(BEGIN);
+
+        let mut reader = subject(&data);
+        reader.enable_resync();
+
+        assert!(reader.read_record().unwrap().is_some());
+        assert!(reader.read_record().unwrap().is_some());
+        assert!(reader.read_record().unwrap().is_none());
+        assert_eq!(reader.stats(), &VslReaderStats {
+            records: 2,
+            batches: 0,
+            resyncs: 1,
+            skipped_bytes: 40,
+            truncated_bytes: 0,
+        });
+    }
+
+    #[test]
+    fn resync_long_records() {
+        // record longer than varnishd logs with default vsl_buffer
+        let mut long = ((5000u32 + 1) | (0x4c << 24)).to_le_bytes().to_vec();
+        long.extend_from_slice(b"\x03\x00\x00\x40");
+        long.extend_from_slice(&[b'a'; 5000]);
+        long.extend_from_slice(b"\x00\x00\x00\x00");
+
+        let mut data = b"VSL\0".to_vec();
+        data.extend_from_slice(BEGIN);
+        data.extend_from_slice(&long);
+        data.extend_from_slice(BEGIN);
+
+        let mut reader = subject(&data);
+        reader.enable_resync();
+        assert_eq!(reader.by_ref().count(), 2);
+        assert_eq!(reader.stats().resyncs, 1);
+
+        let mut reader = subject(&data);
+        reader.enable_resync();
+        reader.set_max_record_len(8192);
+        assert_eq!(reader.by_ref().map(|record| record.unwrap().data.len()).collect::<Vec<_>>(), vec![13, 5000, 13]);
+        assert_eq!(reader.stats().resyncs, 0);
+    }
+
+    #[test]
+    fn read_batched_records() {
+        // batch of two records followed by record outside of batch
+        let mut data = b"\x00\x00\x00\xff\x30\x00\x00\x00".to_vec();
+        data.extend_from_slice(BEGIN);
+        data.extend_from_slice(BEGIN);
+        data.extend_from_slice(BEGIN);
+
+        for resync in &[false, true] {
+            let mut reader = subject(&data);
+            if *resync {
+                reader.enable_resync();
+            }
+
+            for _ in 0..3 {
+                assert_eq!(reader.read_record().unwrap().unwrap().tag, VslRecordTag::SLT_Begin);
+            }
+            assert!(reader.read_record().unwrap().is_none());
+            assert_eq!(reader.offset(), data.len() as u64);
+            assert_eq!(reader.stats().records, 3);
+            assert_eq!(reader.stats().batches, 1);
+            assert_eq!(reader.stats().resyncs, 0);
+        }
+    }
+
+    #[test]
+    fn read_zero_length_record() {
+        let mut reader = subject(b"\x00\x00\x00\x4c\x03\x00\x00\x40");
+        match reader.read_record() {
+            Err(VslReaderError::Parse(nom::ErrorKind::Custom(VSL_RECORD_BAD_LENGTH), 0)) => (),
+            result => panic!("expected Parse error; got: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn read_log_file() {
+        let file = std::fs::File::open("logs/raw.vsl").unwrap();
+        let mut reader = VslReader::new(file);
+
+        let mut count = 0;
+        while let Some(_record) = reader.read_record().unwrap() {
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn detect_tag_numbering() {
+        let mut reader = subject(BEGIN);
+        assert!(reader.tag_table().is_none());
+
+        reader.read_record().unwrap().unwrap();
+        assert_eq!(reader.tag_table(), Some(&TagTable::default()));
+    }
+
+    #[test]
+    fn read_with_varnish_version() {
+        // SLT_Filters is not known to Varnish 5.2
+        let mut data = BEGIN.to_vec();
+        data.extend_from_slice(b"\x01\x00\x00\x5c\x03\x00\x00\x40\x00\x00\x00\x00");
+
+        let mut reader = subject(&data);
+        reader.read_record().unwrap().unwrap();
+        assert_eq!(reader.read_record().unwrap().unwrap().tag, VslRecordTag::SLT_Filters);
+
+        let mut reader = subject(&data);
+        reader.set_varnish_version(VarnishVersion::new(5, 2));
+        assert_eq!(reader.read_record().unwrap().unwrap().tag, VslRecordTag::SLT_Begin);
+
+        let record = reader.read_record().unwrap().unwrap();
+        assert_eq!(record.tag, VslRecordTag::SLT__Bogus);
+        assert_eq!(record.raw_tag, 92);
+        assert_eq!(format!("{:#}", record), "[C ]     3 SLT__Bogus(92)     ");
+    }
+
+    #[test]
+    fn detect_versioned_log_files() {
+        for (dir, version) in &[("logs-v5.2", "5.2"), ("logs-v6.1.1", "6.1.1"), ("logs-v6.2.0", "6.2.0"), ("logs-v6.6.1", "6.6.1")] {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+
+                let mut reader = VslReader::new(std::fs::File::open(&path).unwrap());
+                let detected = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+                assert_eq!(reader.tag_table().map(|table| table.version()), Some(VarnishVersion::new(6, 6)), "{}", path.display());
+
+                // decoded the same as with table of the release that wrote it
+                let mut reader = VslReader::new(std::fs::File::open(&path).unwrap());
+                reader.set_varnish_version(version.parse().unwrap());
+                assert_eq!(reader.collect::<Result<Vec<_>, _>>().unwrap(), detected, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn read_versioned_log_files() {
+        for (dir, version) in &[("logs-v5.2", "5.2"), ("logs-v6.1.1", "6.1.1"), ("logs-v6.2.0", "6.2.0"), ("logs-v6.6.1", "6.6.1")] {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let mut reader = VslReader::new(std::fs::File::open(entry.unwrap().path()).unwrap());
+                reader.set_varnish_version(version.parse().unwrap());
+
+                while let Some(record) = reader.read_record().unwrap() {
+                    assert!(record.tag != VslRecordTag::SLT__Bogus, "unexpected bogus tag in {}: {}", dir, record);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn read_memory_mapped_log_file() {
+        let path = "logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl";
+        let records = VslReader::new(std::fs::File::open(path).unwrap()).collect::<Result<Vec<_>, _>>().unwrap();
+
+        let mut reader = VslReader::open_mmap(path).unwrap();
+        let mapped_records = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(mapped_records, records);
+        assert_eq!(reader.offset(), std::fs::metadata(path).unwrap().len());
+        assert_eq!(reader.stats().truncated_bytes, 0);
+    }
+
+    #[test]
+    fn read_truncated_memory_mapped_file() {
+        let path = std::env::temp_dir().join(format!("varnishslog-mmap-reader-test-{}", std::process::id()));
+        let mut data = b"VSL\0".to_vec();
+        data.extend_from_slice(BEGIN);
+        data.extend_from_slice(&BEGIN[..10]);
+        std::fs::write(&path, &data).unwrap();
+
+        let mut reader = VslReader::open_mmap(&path).unwrap();
+        assert_eq!(reader.read_record().unwrap().unwrap().data, b"sess 0 HTTP/1");
+        match reader.read_record() {
+            Err(VslReaderError::Truncated(10)) => (),
+            result => panic!("expected Truncated error; got: {:?}", result),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn iterate_owned_records() {
+        let records = subject(BEGIN).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].tag, VslRecordTag::SLT_Begin);
+        assert_eq!(records[0].data, b"sess 0 HTTP/1".to_vec());
+    }
+}
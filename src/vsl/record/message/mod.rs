@@ -1,5 +1,7 @@
 pub mod parser;
 
+use serde::{Serialize, Deserialize};
+
 pub type TimeStamp = f64;
 pub type Duration = f64;
 pub type ByteCount = u64;
@@ -8,21 +10,22 @@ pub type FetchMode = u32;
 pub type Status = u32;
 pub type Port = u16;
 pub type FileDescriptor = isize;
+pub type ErrorNumber = i32;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AclResult {
     Match,
     NoMatch,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CompressionOperation {
     Gzip,
     Gunzip,
     GunzipTest,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CompressionDirection {
     Fetch,
     Deliver,
@@ -27,6 +27,7 @@ use super::{
     Status,
     Port,
     FileDescriptor,
+    ErrorNumber,
     AclResult,
     CompressionOperation,
     CompressionDirection,
@@ -67,6 +68,7 @@ named_parsed_symbol!(time_stamp<TimeStamp>);
 named_parsed_symbol!(duration<Duration>);
 named_parsed_symbol!(port<Port>);
 named_parsed_symbol!(file_descriptor<FileDescriptor>);
+named_parsed_symbol!(error_number<ErrorNumber>);
 
 fn map_opt_duration(duration: Duration) -> Option<Duration> {
     if duration < 0.0 {
@@ -262,3 +264,27 @@ named!(pub slt_backend_open<&[u8], (FileDescriptor, &str, Option<(&str, Port)>,
         // Note: this can be <none> <none> if backend socket is not connected
         alt!(map!(terminated!(tag!(b"<none> <none>"), space), |_| None) | map!(tuple!(symbol, port), |t| Some(t))),   // Remote IPv4/6 address Remote TCP port
         tuple!(symbol, port))); // Local IPv4/6 address Local TCP port
+
+named!(pub slt_backend_close<&[u8], (FileDescriptor, &str, &str, Option<&MaybeStr>)>, tuple!(
+        file_descriptor,        // Connection file descriptor
+        symbol,                 // Backend display name
+        symbol,                 // "close" or "recycle"
+        opt!(maybe_str!(non_empty)))); // Optional reason
+
+named!(pub slt_sess_error<&[u8], (&str, (&str, Port), FileDescriptor, ErrorNumber, &MaybeStr)>, tuple!(
+        symbol,                 // Socket name (-a argument)
+        // Local IPv4/6 address (0.0.0.0 for UDS)
+        // Local TCP port (0 for UDS)
+        tuple!(symbol, port),
+        file_descriptor,        // File descriptor number
+        error_number,           // Error number (errno) from accept(2)
+        maybe_str!(non_empty))); // Detailed error message
+
+named!(pub slt_vcl_use<&[u8], (&str, Option<&str>)>, tuple!(
+        symbol,                 // Name of VCL put in use
+        // Name of label used to find it
+        opt!(complete!(preceded!(tag!(b"via "), symbol)))));
+
+named!(pub slt_notice<&[u8], (&str, &MaybeStr)>, tuple!(
+        label,                  // Short description/identifier
+        maybe_str!(non_empty))); // Long description
@@ -5,8 +5,10 @@
 mod tag_e;
 pub mod message;
 pub mod parser;
+pub mod tag_table;
 
 use std::fmt::{self, Debug, Display};
+use std::collections::HashMap;
 use quick_error::ResultExt;
 use nom;
 use quick_error::quick_error;
@@ -14,6 +16,7 @@ use bitflags::bitflags;
 
 use crate::maybe_string::MaybeStr;
 pub use self::tag_e::VSL_tag_e as VslRecordTag;
+use self::tag_table::TagTable;
 
 bitflags! {
     pub struct Marker: u8 {
@@ -32,6 +35,19 @@ impl Display for Marker {
 
 pub type VslIdent = u32;
 
+thread_local! {
+    static TAG_NAMES: HashMap<String, VslRecordTag> = TagTable::default().tags()
+        .map(|tag| (format!("{:?}", tag).trim_start_matches("SLT_").to_string(), tag))
+        .collect();
+}
+
+impl VslRecordTag {
+    /// Looks up tag by name as printed by varnishlog, e.g. "ReqURL"
+    pub fn from_name(name: &str) -> Option<VslRecordTag> {
+        TAG_NAMES.with(|names| names.get(name).cloned())
+    }
+}
+
 #[derive(Debug)]
 struct VslRecordHeader {
     tag: u8,
@@ -40,13 +56,31 @@ struct VslRecordHeader {
     ident: VslIdent,
 }
 
+#[derive(Clone, Copy, PartialEq)]
 pub struct VslRecord<'b> {
     pub tag: VslRecordTag,
+    /// Tag number as found in the stream; tag is SLT__Bogus if number is unknown
+    pub raw_tag: u8,
     pub marker: Marker,
     pub ident: VslIdent,
     pub data: &'b[u8],
 }
 
+/// Owned version of VslRecord that can be buffered or sent to other threads
+#[derive(Clone, PartialEq)]
+pub struct VslRecordBuf {
+    pub tag: VslRecordTag,
+    pub raw_tag: u8,
+    pub marker: Marker,
+    pub ident: VslIdent,
+    pub data: Vec<u8>,
+}
+
+/// Types that can be viewed as VslRecord
+pub trait AsVslRecord {
+    fn as_vsl_record(&self) -> VslRecord<'_>;
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum VslRecordParseError {
@@ -57,6 +91,13 @@ quick_error! {
     }
 }
 
+impl VslRecordTag {
+    /// Tag number in default numbering (Varnish 6.6)
+    pub fn number(self) -> u8 {
+        TagTable::default().number(self).unwrap_or(0)
+    }
+}
+
 impl<'b> VslRecord<'b> {
     pub fn parse_data<T, P>(&'b self, parser: P) -> Result<T, VslRecordParseError> where
     P: Fn(&'b [u8]) -> nom::IResult<&'b [u8], T> {
@@ -74,12 +115,67 @@ impl<'b> VslRecord<'b> {
     pub fn is_backend(&self) -> bool {
         self.marker.contains(Marker::VSL_BACKENDMARKER)
     }
+
+    pub fn to_record_buf(&self) -> VslRecordBuf {
+        VslRecordBuf {
+            tag: self.tag,
+            raw_tag: self.raw_tag,
+            marker: self.marker,
+            ident: self.ident,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+impl VslRecordBuf {
+    pub fn as_record(&self) -> VslRecord<'_> {
+        VslRecord {
+            tag: self.tag,
+            raw_tag: self.raw_tag,
+            marker: self.marker,
+            ident: self.ident,
+            data: &self.data,
+        }
+    }
+
+    pub fn is_client(&self) -> bool {
+        self.marker.contains(Marker::VSL_CLIENTMARKER)
+    }
+
+    pub fn is_backend(&self) -> bool {
+        self.marker.contains(Marker::VSL_BACKENDMARKER)
+    }
+}
+
+impl<'b> From<VslRecord<'b>> for VslRecordBuf {
+    fn from(record: VslRecord<'b>) -> VslRecordBuf {
+        record.to_record_buf()
+    }
+}
+
+impl<'b> From<&'b VslRecordBuf> for VslRecord<'b> {
+    fn from(record: &'b VslRecordBuf) -> VslRecord<'b> {
+        record.as_record()
+    }
+}
+
+impl<'b> AsVslRecord for VslRecord<'b> {
+    fn as_vsl_record(&self) -> VslRecord<'_> {
+        *self
+    }
+}
+
+impl AsVslRecord for VslRecordBuf {
+    fn as_vsl_record(&self) -> VslRecord<'_> {
+        self.as_record()
+    }
 }
 
 impl<'b> Debug for VslRecord<'b> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         f.debug_struct("VSL Record")
             .field("tag", &self.tag)
+            .field("raw_tag", &self.raw_tag)
             .field("marker", &self.marker)
             .field("ident", &self.ident)
             .field("data", &MaybeStr::from_bytes(self.data))
@@ -89,7 +185,11 @@ impl<'b> Debug for VslRecord<'b> {
 
 impl<'b> Display for VslRecord<'b> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        let tag = format!("{:?}", self.tag);
+        let tag = if self.tag == VslRecordTag::SLT__Bogus {
+            format!("{:?}({})", self.tag, self.raw_tag)
+        } else {
+            format!("{:?}", self.tag)
+        };
 
         if f.alternate() {
             write!(f, "{} {:5} {:18} {}", self.marker, self.ident, tag, MaybeStr::from_bytes(self.data))
@@ -98,3 +198,50 @@ impl<'b> Display for VslRecord<'b> {
         }
     }
 }
+
+impl Debug for VslRecordBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        Debug::fmt(&self.as_record(), f)
+    }
+}
+
+impl Display for VslRecordBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        Display::fmt(&self.as_record(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vsl_record_buf_round_trip() {
+        let record = VslRecord {
+            tag: VslRecordTag::SLT_ReqURL,
+            raw_tag: 16,
+            marker: Marker::VSL_CLIENTMARKER,
+            ident: 42,
+            data: b"/foo",
+        };
+
+        let record_buf = record.to_record_buf();
+        assert_eq!(record_buf.data, b"/foo".to_vec());
+        assert_eq!(record_buf.as_record(), record);
+        assert_eq!(format!("{:#}", record_buf), format!("{:#}", record));
+    }
+
+    #[test]
+    fn vsl_record_buf_send() {
+        let record_buf = VslRecordBuf {
+            tag: VslRecordTag::SLT_ReqURL,
+            raw_tag: 16,
+            marker: Marker::VSL_CLIENTMARKER,
+            ident: 42,
+            data: b"/foo".to_vec(),
+        };
+
+        let received = std::thread::spawn(move || record_buf).join().unwrap();
+        assert_eq!(received.as_record().data, b"/foo");
+    }
+}
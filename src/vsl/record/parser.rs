@@ -23,8 +23,6 @@
  *   Non-zero if this is a backend transaction
  */
 
-use std::mem;
-
 use nom::{self, le_u32, chain, named};
 
 use super::{
@@ -33,11 +31,12 @@ use super::{
     Marker,
     VslRecordTag,
 };
+use super::tag_table::TagTable;
 
-const VSL_LENOFFSET: u32 = 24;
-const VSL_LENMASK: u32 = 0xffff;
-const VSL_IDENTOFFSET: u8 = 30;
-const VSL_IDENTMASK: u32 = !(0b0000_0011 << VSL_IDENTOFFSET);
+pub(crate) const VSL_LENOFFSET: u32 = 24;
+pub(crate) const VSL_LENMASK: u32 = 0xffff;
+pub(crate) const VSL_IDENTOFFSET: u8 = 30;
+pub(crate) const VSL_IDENTMASK: u32 = !(0b0000_0011 << VSL_IDENTOFFSET);
 
 named!(pub binary_vsl_tag<&[u8], Option<&[u8]> >, opt!(complete!(tag!(b"VSL\0"))));
 
@@ -52,27 +51,98 @@ fn vsl_record_header(input: &[u8]) -> nom::IResult<&[u8], VslRecordHeader, u32>
         })
 }
 
+// Records are parsed using default tag numbering (that knows all the tags); VslReader maps raw
+// tag numbers using TagTable of the Varnish release that wrote the stream
 fn to_vsl_record_tag(num: u8) -> VslRecordTag {
-    let num = num as u32;
+    TagTable::default().tag(num)
+}
+
+/// Longest record data Varnish logs with default vsl_buffer (4k); vsl_reclen parameter can be
+/// raised up to vsl_buffer - 12 so with bigger vsl_buffer records can be longer
+pub const DEFAULT_MAX_RECORD_LEN: usize = 4084;
+
+// Error codes for ErrorKind::Custom
+pub const VSL_RECORD_BAD_LENGTH: u32 = 1;
+pub const VSL_RECORD_IMPLAUSIBLE: u32 = 2;
 
-    // Warning: we need to make sure that num is an existing VslRecordTag variant or program will crash!
-    // TODO: there needs to be a better way than this!
-    if num > VslRecordTag::SLT_VdpAcct as u32 && num < VslRecordTag::SLT__Reserved as u32 {
-        return VslRecordTag::SLT__Bogus
+// Records are checked to be plausible if longest record data length is given
+fn vsl_record(input: &[u8], max_len: Option<usize>) -> nom::IResult<&[u8], VslRecord<'_>, u32> {
+    let header = match vsl_record_header(input) {
+        nom::IResult::Done(_, header) => header,
+        nom::IResult::Incomplete(needed) => return nom::IResult::Incomplete(needed),
+        nom::IResult::Error(err) => return nom::IResult::Error(err),
+    };
+
+    // length includes NUL terminator so it cannot be 0
+    if header.len == 0 {
+        return nom::IResult::Error(nom::Err::Position(nom::ErrorKind::Custom(VSL_RECORD_BAD_LENGTH), input))
     }
 
-    unsafe { mem::transmute(num as u32) }
-}
+    let check = max_len.is_some();
+    if let Some(max_len) = max_len {
+        // length includes NUL terminator
+        if header.len as usize > max_len + 1 || !is_plausible_header(&header) {
+            return nom::IResult::Error(nom::Err::Position(nom::ErrorKind::Custom(VSL_RECORD_IMPLAUSIBLE), input))
+        }
+    }
 
-pub fn vsl_record_v4(input: &[u8]) -> nom::IResult<&[u8], VslRecord<'_>, u32> {
-    chain!(
+    let result = chain!(
         input,
-        header: vsl_record_header ~
-        data: take!(header.len - 1) ~ take!(1) ~ take!((4 - header.len % 4) % 4),
-        || VslRecord {
+        vsl_record_header ~
+        data: take!(header.len - 1) ~ nul: take!(1) ~ take!((4 - header.len % 4) % 4),
+        || (nul, VslRecord {
             tag: to_vsl_record_tag(header.tag),
+            raw_tag: header.tag,
             marker: header.marker,
             ident: header.ident,
             data: data
-        })
+        }));
+
+    match result {
+        nom::IResult::Done(_, (nul, _)) if check && nul != b"\0" =>
+            nom::IResult::Error(nom::Err::Position(nom::ErrorKind::Custom(VSL_RECORD_IMPLAUSIBLE), input)),
+        nom::IResult::Done(i, (_, record)) => nom::IResult::Done(i, record),
+        nom::IResult::Incomplete(needed) => nom::IResult::Incomplete(needed),
+        nom::IResult::Error(err) => nom::IResult::Error(err),
+    }
+}
+
+// Known tag and markers consistent with ident: transaction records (ident > 0) are either
+// client or backend; other records have no marker
+fn is_plausible_header(header: &VslRecordHeader) -> bool {
+    let tag = to_vsl_record_tag(header.tag);
+    let known_tag = tag != VslRecordTag::SLT__Bogus && tag != VslRecordTag::SLT__Reserved;
+
+    let markers_consistent = if header.ident == 0 {
+        header.marker.is_empty()
+    } else {
+        header.marker == Marker::VSL_CLIENTMARKER || header.marker == Marker::VSL_BACKENDMARKER
+    };
+
+    known_tag && markers_consistent
+}
+
+// Batch record wraps records flushed together from vsl_buffer; records follow its header:
+//    [n]               = (SLT__Batch & 0xff) << 24
+//    [n + 1]           = length of the batch records in bytes
+const VSL_BATCH_HEADER: u32 = 255 << VSL_LENOFFSET;
+
+/// Length of records of the batch if input starts with batch record header; consumes the header
+pub fn vsl_batch_header(input: &[u8]) -> nom::IResult<&[u8], Option<u32>, u32> {
+    match chain!(input, r1: le_u32 ~ r2: le_u32, || (r1, r2)) {
+        nom::IResult::Done(i, (VSL_BATCH_HEADER, len)) => nom::IResult::Done(i, Some(len)),
+        nom::IResult::Done(_, _) => nom::IResult::Done(input, None),
+        nom::IResult::Incomplete(needed) => nom::IResult::Incomplete(needed),
+        nom::IResult::Error(err) => nom::IResult::Error(err),
+    }
+}
+
+pub fn vsl_record_v4(input: &[u8]) -> nom::IResult<&[u8], VslRecord<'_>, u32> {
+    vsl_record(input, None)
+}
+
+/// Like vsl_record_v4 but fails on records that do not look like valid VSL records or have data
+/// longer than max_len (vsl_reclen parameter); this is used to find next record after corrupted data
+pub fn vsl_record_v4_checked(input: &[u8], max_len: usize) -> nom::IResult<&[u8], VslRecord<'_>, u32> {
+    vsl_record(input, Some(max_len))
 }
@@ -0,0 +1,192 @@
+// VSL tag numbering of Varnish releases.
+//
+// Tag numbers are given by the order of SLTM entries in include/tbl/vsl_tags.h so the same
+// number may stand for different tags in different Varnish releases. Tables are generated with
+// `./mk_vsl_tag VERSION INCLUDE_DIR` from headers of given release; only the 6.6 table (headers
+// in include/) is generated so far.
+//
+// Between 5.2 and 6.6 tags were only appended so tables of 5.2, 6.1 and 6.2 are the 6.6 table
+// cut after the last tag the release has; this is checked by reading logs-v5.2, logs-v6.1.1
+// and logs-v6.2.0 captures with them. As SLT_Begin has the same number in all of them the 6.6
+// table is detected for all these releases and, being their superset, decodes their logs.
+// Numbers not known to the table are mapped to SLT__Bogus; VslRecord keeps the raw number.
+mod v6_6;
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::iter;
+use quick_error::quick_error;
+
+use super::VslRecordTag;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VarnishVersionParseError {
+        BadFormat(version: String) {
+            display("Expected Varnish version in MAJOR.MINOR format; got: {:?}", version)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarnishVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl VarnishVersion {
+    pub fn new(major: u8, minor: u8) -> VarnishVersion {
+        VarnishVersion { major, minor }
+    }
+}
+
+impl FromStr for VarnishVersion {
+    type Err = VarnishVersionParseError;
+
+    /// Parses "6.6" or "6.6.1"; patch version is ignored
+    fn from_str(version: &str) -> Result<VarnishVersion, VarnishVersionParseError> {
+        let parts = version.split('.').map(|part| part.parse::<u8>().ok()).collect::<Vec<_>>();
+        match parts.as_slice() {
+            [Some(major), Some(minor)] |
+            [Some(major), Some(minor), Some(_)] => Ok(VarnishVersion::new(*major, *minor)),
+            _ => Err(VarnishVersionParseError::BadFormat(version.to_string())),
+        }
+    }
+}
+
+impl Display for VarnishVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Maps VSL tag numbers to tags for given Varnish release
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagTable {
+    version: VarnishVersion,
+    tags: &'static [VslRecordTag],
+}
+
+/// Table with numbering of VslRecordTag variants (Varnish 6.6); it knows all the tags
+impl Default for TagTable {
+    fn default() -> TagTable {
+        TagTable::for_version(VarnishVersion::new(6, 6))
+    }
+}
+
+impl TagTable {
+    /// Table for given Varnish release; releases we have no table for get table of the
+    /// closest newer release as it is a superset
+    pub fn for_version(version: VarnishVersion) -> TagTable {
+        let tags = match (version.major, version.minor) {
+            // up to SLT_HitMiss
+            (0..=5, _) => &v6_6::VSL_TAGS[..=91],
+            // up to SLT_Filters
+            (6, 0..=1) => &v6_6::VSL_TAGS[..=92],
+            // up to SLT_VCL_use
+            (6, 2) => &v6_6::VSL_TAGS[..=94],
+            _ => v6_6::VSL_TAGS,
+        };
+
+        TagTable { version, tags }
+    }
+
+    /// Guesses Varnish release by number of the tag of the first record of a transaction
+    /// which is always SLT_Begin; the newest release of those with given SLT_Begin number is
+    /// returned as its table decodes records of the older ones
+    pub fn detect(first_transaction_tag: u8) -> Option<TagTable> {
+        let table = TagTable::default();
+        if table.number(VslRecordTag::SLT_Begin) == Some(first_transaction_tag) {
+            Some(table)
+        } else {
+            None
+        }
+    }
+
+    pub fn version(&self) -> VarnishVersion {
+        self.version
+    }
+
+    /// Tag of given number; unknown numbers are mapped to SLT__Bogus
+    pub fn tag(&self, num: u8) -> VslRecordTag {
+        match num {
+            254 => VslRecordTag::SLT__Reserved,
+            255 => VslRecordTag::SLT__Batch,
+            num => self.tags.get(num as usize).cloned().unwrap_or(VslRecordTag::SLT__Bogus),
+        }
+    }
+
+    /// Number of given tag; `None` if the tag is not used by this release
+    pub fn number(&self, tag: VslRecordTag) -> Option<u8> {
+        match tag {
+            VslRecordTag::SLT__Reserved => Some(254),
+            VslRecordTag::SLT__Batch => Some(255),
+            tag => self.tags.iter().position(|t| *t == tag).map(|num| num as u8),
+        }
+    }
+
+    /// All tags known to this release
+    pub fn tags(&self) -> impl Iterator<Item = VslRecordTag> + '_ {
+        self.tags.iter().cloned()
+            .chain(iter::once(VslRecordTag::SLT__Reserved))
+            .chain(iter::once(VslRecordTag::SLT__Batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsl::record::VslRecordTag::*;
+
+    #[test]
+    fn parse_version() {
+        assert_eq!("6.6".parse::<VarnishVersion>().unwrap(), VarnishVersion::new(6, 6));
+        assert_eq!("6.1.1".parse::<VarnishVersion>().unwrap(), VarnishVersion::new(6, 1));
+        assert!("6".parse::<VarnishVersion>().is_err());
+        assert!("6.x".parse::<VarnishVersion>().is_err());
+        assert_eq!(VarnishVersion::new(5, 2).to_string(), "5.2");
+    }
+
+    #[test]
+    fn tag_numbers() {
+        let table = TagTable::default();
+        assert_eq!(table.tag(0), SLT__Bogus);
+        assert_eq!(table.tag(76), SLT_Begin);
+        assert_eq!(table.tag(96), SLT_VdpAcct);
+        assert_eq!(table.tag(97), SLT__Bogus);
+        assert_eq!(table.tag(255), SLT__Batch);
+        assert_eq!(table.number(SLT_Begin), Some(76));
+
+        for num in 0..=255u8 {
+            let tag = table.tag(num);
+            if tag != SLT__Bogus {
+                assert_eq!(table.number(tag), Some(num));
+            }
+        }
+    }
+
+    #[test]
+    fn older_versions() {
+        let table = TagTable::for_version(VarnishVersion::new(5, 2));
+        assert_eq!(table.tag(91), SLT_HitMiss);
+        assert_eq!(table.tag(92), SLT__Bogus);
+        assert_eq!(table.number(SLT_Filters), None);
+
+        let table = TagTable::for_version(VarnishVersion::new(6, 1));
+        assert_eq!(table.tag(92), SLT_Filters);
+        assert_eq!(table.tag(93), SLT__Bogus);
+
+        let table = TagTable::for_version(VarnishVersion::new(6, 2));
+        assert_eq!(table.tag(94), SLT_VCL_use);
+        assert_eq!(table.tag(95), SLT__Bogus);
+
+        let table = TagTable::for_version(VarnishVersion::new(7, 0));
+        assert_eq!(table.tag(96), SLT_VdpAcct);
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(TagTable::detect(76).map(|table| table.version()), Some(VarnishVersion::new(6, 6)));
+        assert_eq!(TagTable::detect(16), None);
+    }
+}
@@ -0,0 +1,104 @@
+// automatically generated by mk_vsl_tag from include/tbl/vsl_tags.h of Varnish 6.6
+
+use crate::vsl::record::VslRecordTag::{self, *};
+
+/// Tags indexed by number; SLT__Reserved (254) and SLT__Batch (255) are not included
+pub const VSL_TAGS: &[VslRecordTag] = &[
+    SLT__Bogus,
+    SLT_Debug,
+    SLT_Error,
+    SLT_CLI,
+    SLT_SessOpen,
+    SLT_SessClose,
+    SLT_BackendOpen,
+    SLT_BackendReuse,
+    SLT_BackendClose,
+    SLT_HttpGarbage,
+    SLT_Proxy,
+    SLT_ProxyGarbage,
+    SLT_Backend,
+    SLT_Length,
+    SLT_FetchError,
+    SLT_ReqMethod,
+    SLT_ReqURL,
+    SLT_ReqProtocol,
+    SLT_ReqStatus,
+    SLT_ReqReason,
+    SLT_ReqHeader,
+    SLT_ReqUnset,
+    SLT_ReqLost,
+    SLT_RespMethod,
+    SLT_RespURL,
+    SLT_RespProtocol,
+    SLT_RespStatus,
+    SLT_RespReason,
+    SLT_RespHeader,
+    SLT_RespUnset,
+    SLT_RespLost,
+    SLT_BereqMethod,
+    SLT_BereqURL,
+    SLT_BereqProtocol,
+    SLT_BereqStatus,
+    SLT_BereqReason,
+    SLT_BereqHeader,
+    SLT_BereqUnset,
+    SLT_BereqLost,
+    SLT_BerespMethod,
+    SLT_BerespURL,
+    SLT_BerespProtocol,
+    SLT_BerespStatus,
+    SLT_BerespReason,
+    SLT_BerespHeader,
+    SLT_BerespUnset,
+    SLT_BerespLost,
+    SLT_ObjMethod,
+    SLT_ObjURL,
+    SLT_ObjProtocol,
+    SLT_ObjStatus,
+    SLT_ObjReason,
+    SLT_ObjHeader,
+    SLT_ObjUnset,
+    SLT_ObjLost,
+    SLT_BogoHeader,
+    SLT_LostHeader,
+    SLT_TTL,
+    SLT_Fetch_Body,
+    SLT_VCL_acl,
+    SLT_VCL_call,
+    SLT_VCL_trace,
+    SLT_VCL_return,
+    SLT_ReqStart,
+    SLT_Hit,
+    SLT_HitPass,
+    SLT_ExpBan,
+    SLT_ExpKill,
+    SLT_WorkThread,
+    SLT_ESI_xmlerror,
+    SLT_Hash,
+    SLT_Backend_health,
+    SLT_VCL_Log,
+    SLT_VCL_Error,
+    SLT_Gzip,
+    SLT_Link,
+    SLT_Begin,
+    SLT_End,
+    SLT_VSL,
+    SLT_Storage,
+    SLT_Timestamp,
+    SLT_ReqAcct,
+    SLT_PipeAcct,
+    SLT_BereqAcct,
+    SLT_VfpAcct,
+    SLT_Witness,
+    SLT_BackendStart,
+    SLT_H2RxHdr,
+    SLT_H2RxBody,
+    SLT_H2TxHdr,
+    SLT_H2TxBody,
+    SLT_HitMiss,
+    SLT_Filters,
+    SLT_SessError,
+    SLT_VCL_use,
+    SLT_Notice,
+    SLT_VdpAcct,
+];
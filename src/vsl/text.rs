@@ -0,0 +1,289 @@
+// Reads VSL records from varnishlog text output.
+//
+// Both raw (`varnishlog -g raw`) and grouped (default `-g vxid`, `-g request` and
+// `-g session`) formats are supported:
+//
+//      32770 Begin          c req 32769 rxreq
+//      32770 ReqURL         c /foo
+//
+// *   << Request  >> 32770
+// -   Begin          req 32769 rxreq
+// -   ReqURL         /foo
+// **  << BeReq    >> 32771
+// --  Begin          bereq 32770 fetch
+//
+// In grouped output records have no client/backend marker so it is taken from the
+// transaction type in the group header. Records with tags unknown to us are skipped.
+use std::io::{self, BufRead};
+use std::cmp::max;
+use quick_error::quick_error;
+use log::warn;
+
+use super::record::{VslRecordBuf, VslRecordTag, VslIdent, Marker};
+
+// Width of tag column as printed by varnishlog
+const TAG_WIDTH: usize = 14;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VslTextReaderError {
+        Io(err: io::Error) {
+            display("Failed to read varnishlog text stream: {}", err)
+            from()
+        }
+        Syntax(line: usize, msg: String) {
+            display("Failed to parse varnishlog text on line {}: {}", line, msg)
+        }
+    }
+}
+
+// Splits off field of given width that is followed by single space; fields may be longer
+// than the width and padding may be missing if trailing whitespace was lost
+fn split_field(line: &str, width: usize) -> (&str, &str) {
+    let field_len = line.find(' ').unwrap_or(line.len());
+    let (field, mut rest) = line.split_at(field_len);
+
+    let padding = max(width, field_len) - field_len + 1;
+    for _ in 0..padding {
+        match rest.strip_prefix(' ') {
+            Some(stripped) => rest = stripped,
+            None => break,
+        }
+    }
+    (field, rest)
+}
+
+pub struct VslTextReader<R: BufRead> {
+    input: R,
+    line: String,
+    line_no: usize,
+    group: Option<(VslIdent, Marker)>,
+}
+
+impl<R: BufRead> VslTextReader<R> {
+    pub fn new(input: R) -> VslTextReader<R> {
+        VslTextReader {
+            input,
+            line: String::new(),
+            line_no: 0,
+            group: None,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    fn syntax_error<T>(&self, msg: &str) -> Result<T, VslTextReaderError> {
+        Err(VslTextReaderError::Syntax(self.line_no, msg.to_string()))
+    }
+
+    fn tag(&self, name: &str) -> Option<VslRecordTag> {
+        let tag = VslRecordTag::from_name(name);
+        if tag.is_none() {
+            warn!("Skipping record with unknown tag {:?} on line {}", name, self.line_no);
+        }
+        tag
+    }
+
+    fn parse_group_header(&mut self, line: &str) -> Result<(), VslTextReaderError> {
+        let (kind, ident) = match (line.find("<<"), line.find(">>")) {
+            (Some(start), Some(end)) if start < end => (line[start + 2..end].trim(), line[end + 2..].trim()),
+            _ => return self.syntax_error("expected transaction group header"),
+        };
+
+        let ident = match ident.parse() {
+            Ok(ident) => ident,
+            Err(_) => return self.syntax_error("bad transaction vxid in group header"),
+        };
+
+        let marker = match kind {
+            "Session" | "Request" => Marker::VSL_CLIENTMARKER,
+            "BeReq" => Marker::VSL_BACKENDMARKER,
+            _ => Marker::empty(),
+        };
+
+        self.group = Some((ident, marker));
+        Ok(())
+    }
+
+    fn parse_grouped_record(&self, line: &str) -> Result<Option<VslRecordBuf>, VslTextReaderError> {
+        let (ident, marker) = match self.group {
+            Some(group) => group,
+            None => return self.syntax_error("record outside of transaction group"),
+        };
+
+        // level indicator, e.g. "-", "--" or "-4-"
+        let (_, rest) = split_field(line, 3);
+        let (tag, data) = split_field(rest.trim_start(), TAG_WIDTH);
+
+        Ok(self.tag(tag).map(|tag| VslRecordBuf {
+            tag,
+            raw_tag: tag.number(),
+            marker,
+            ident,
+            data: data.as_bytes().to_vec(),
+        }))
+    }
+
+    fn parse_raw_record(&self, line: &str) -> Result<Option<VslRecordBuf>, VslTextReaderError> {
+        let (ident, rest) = split_field(line.trim_start(), 0);
+        let ident = match ident.parse() {
+            Ok(ident) => ident,
+            Err(_) => return self.syntax_error("expected record vxid"),
+        };
+
+        let (tag, rest) = split_field(rest.trim_start(), TAG_WIDTH);
+        let (marker, data) = split_field(rest, 1);
+        let marker = match marker {
+            "c" => Marker::VSL_CLIENTMARKER,
+            "b" => Marker::VSL_BACKENDMARKER,
+            "-" => Marker::empty(),
+            _ => return self.syntax_error("expected record type of 'c', 'b' or '-'"),
+        };
+
+        Ok(self.tag(tag).map(|tag| VslRecordBuf {
+            tag,
+            raw_tag: tag.number(),
+            marker,
+            ident,
+            data: data.as_bytes().to_vec(),
+        }))
+    }
+
+    /// Reads next record; returns `None` at the end of the stream
+    pub fn read_record(&mut self) -> Result<Option<VslRecordBuf>, VslTextReaderError> {
+        loop {
+            self.line.clear();
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Ok(None)
+            }
+            self.line_no += 1;
+
+            let line = std::mem::take(&mut self.line);
+            let trimmed = line.trim_end_matches(&['\n', '\r'][..]);
+
+            let record = if trimmed.trim().is_empty() {
+                None
+            } else if trimmed.starts_with('*') {
+                self.parse_group_header(trimmed)?;
+                None
+            } else if trimmed.starts_with('-') {
+                self.parse_grouped_record(trimmed)?
+            } else {
+                self.parse_raw_record(trimmed)?
+            };
+
+            self.line = line;
+            if record.is_some() {
+                return Ok(record)
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for VslTextReader<R> {
+    type Item = Result<VslRecordBuf, VslTextReaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::fs::File;
+    use crate::vsl::reader::VslReader;
+    use crate::access_log::test_helpers::*;
+
+    fn parse(text: &str) -> Vec<VslRecordBuf> {
+        VslTextReader::new(Cursor::new(text.as_bytes())).collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    #[test]
+    fn parse_raw() {
+        let records = parse(concat!(
+            "         0 CLI            - Rd ping\n",
+            "     32770 Begin          c req 32769 rxreq\n",
+            "     32770 ReqHeader      c Host:  foo \n",
+            "     32771 BereqMethod    b GET\n",
+            "     32770 End            c \n",
+            "     32770 VCL_return     c deliver\n",
+        ));
+
+        assert_eq!(records, vec![
+            VslRecordBuf { tag: SLT_CLI, raw_tag: SLT_CLI.number(), marker: Marker::empty(), ident: 0, data: b"Rd ping".to_vec() },
+            vsl(SLT_Begin, 32770, "req 32769 rxreq").to_record_buf(),
+            vsl(SLT_ReqHeader, 32770, "Host:  foo ").to_record_buf(),
+            VslRecordBuf { tag: SLT_BereqMethod, raw_tag: SLT_BereqMethod.number(), marker: Marker::VSL_BACKENDMARKER, ident: 32771, data: b"GET".to_vec() },
+            vsl(SLT_End, 32770, "").to_record_buf(),
+            vsl(SLT_VCL_return, 32770, "deliver").to_record_buf(),
+        ]);
+    }
+
+    #[test]
+    fn parse_grouped() {
+        let records = parse(concat!(
+            "*   << Request  >> 32770     \n",
+            "-   Begin          req 32769 rxreq\n",
+            "-   ReqURL         /foo\n",
+            "**  << BeReq    >> 32771     \n",
+            "--  Begin          bereq 32770 fetch\n",
+            "--  End\n",
+            "\n",
+            "*   << Session  >> 32769     \r\n",
+            "-   Begin          sess 0 HTTP/1\r\n",
+        ));
+
+        assert_eq!(records, vec![
+            vsl(SLT_Begin, 32770, "req 32769 rxreq").to_record_buf(),
+            vsl(SLT_ReqURL, 32770, "/foo").to_record_buf(),
+            VslRecordBuf { tag: SLT_Begin, raw_tag: SLT_Begin.number(), marker: Marker::VSL_BACKENDMARKER, ident: 32771, data: b"bereq 32770 fetch".to_vec() },
+            VslRecordBuf { tag: SLT_End, raw_tag: SLT_End.number(), marker: Marker::VSL_BACKENDMARKER, ident: 32771, data: b"".to_vec() },
+            vsl(SLT_Begin, 32769, "sess 0 HTTP/1").to_record_buf(),
+        ]);
+    }
+
+    #[test]
+    fn parse_unknown_tag() {
+        let records = parse(concat!(
+            "     32770 FooBar         c baz\n",
+            "     32770 End            c \n",
+        ));
+
+        assert_eq!(records, vec![vsl(SLT_End, 32770, "").to_record_buf()]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut reader = VslTextReader::new(Cursor::new(b"-   Begin          req 32769 rxreq\n".as_ref()));
+        match reader.read_record() {
+            Err(VslTextReaderError::Syntax(1, _)) => (),
+            result => panic!("expected Syntax error; got: {:?}", result),
+        }
+
+        let mut reader = VslTextReader::new(Cursor::new(b"\n     32770 Begin          x req 32769 rxreq\n".as_ref()));
+        match reader.read_record() {
+            Err(VslTextReaderError::Syntax(2, _)) => (),
+            result => panic!("expected Syntax error; got: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_formatted_log_file() {
+        let mut text = String::new();
+        let mut expected = Vec::new();
+
+        let mut reader = VslReader::new(File::open("logs/varnish20160804-3752-1lr56fj56c2d5925f217f012.vsl").unwrap());
+        while let Some(record) = reader.read_record().unwrap() {
+            let marker = if record.is_client() { "c" } else if record.is_backend() { "b" } else { "-" };
+            let tag = format!("{:?}", record.tag);
+            text.push_str(&format!("{:10} {:14} {} {}\n", record.ident, &tag[4..], marker, String::from_utf8_lossy(record.data)));
+            expected.push(record.to_record_buf());
+        }
+
+        assert!(parse(&text) == expected);
+    }
+}
@@ -0,0 +1,669 @@
+// Reads VSL records directly from Varnish shared memory log (VSM) of running varnishd.
+//
+// From Varnish source code (include/vapi/vsl_int.h):
+/*
+ * Shared memory log format
+ *
+ * The segment consists of a header followed by a ring buffer of 32bit
+ * unsigned integers containing log records:
+ *
+ * struct VSL_head {
+ * #define VSL_HEAD_MARKER		"VSLHEAD1"	/* Incr. as version# */
+ *	char			marker[VSL_MARKER_SIZE];
+ *	ssize_t			segsize;
+ *	unsigned		segment_n;
+ *	ssize_t			offset[VSL_SEGMENTS];
+ *	uint32_t		log[];
+ * };
+ *
+ * The log is divided into VSL_SEGMENTS segments of segsize words. offset[]
+ * holds offset of the first record of each segment and segment_n counts
+ * segments written so far. VSL_ENDMARKER follows the last record written and
+ * VSL_WRAPMARKER tells that writer continues from the beginning of the log.
+ */
+//
+// The segment is found in `_.vsm_child/_.index` of varnishd working directory (`-n` name) that
+// lists VSM segments as "+ <file> <offset> <length> <class> <ident>" lines (Varnish 6.0 and newer;
+// "-" lines remove segments). Only 64 bit layout of VSL_head is supported.
+//
+// VsmReader follows the ring buffer like varnishlog does: it keeps track of the segment it is
+// reading and when the writer gets too close (it would overwrite data not yet read) it reports
+// an overrun and continues from the segment the writer is at. Complete records are copied out
+// of shared memory so VsmReader implements Read producing binary VSL stream for VslReader.
+use std::io::{self, Read};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::thread;
+use std::ptr;
+use std::fmt::{self, Display};
+use quick_error::quick_error;
+use log::{info, warn};
+use memmap2::Mmap;
+
+use super::record::parser::{VSL_LENOFFSET, VSL_LENMASK};
+
+// Default location of varnishd working directories
+const VARNISH_STATE_DIR: &str = "/var/lib/varnish";
+const VSM_CHILD_DIR: &str = "_.vsm_child";
+const VSM_INDEX: &str = "_.index";
+
+const VSL_CLASS: &str = "Log";
+const VSL_HEAD_MARKER: &[u8] = b"VSLHEAD1";
+const VSL_SEGMENTS: u32 = 8;
+
+// Offsets into VSL_head
+const VSL_HEAD_SEGSIZE: usize = 8;
+const VSL_HEAD_SEGMENT_N: usize = 16;
+const VSL_HEAD_OFFSET: usize = 24;
+const VSL_HEAD_LOG: usize = VSL_HEAD_OFFSET + 8 * VSL_SEGMENTS as usize;
+
+const VSL_ENDMARKER: u32 = (254 << VSL_LENOFFSET) | 0x45_4545;
+const VSL_WRAPMARKER: u32 = (254 << VSL_LENOFFSET) | 0x57_5757;
+
+// How long to wait for new records to be written
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How often to check if varnishd did not replace the segment (e.g. after restart) when idle
+const VALIDITY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VsmError {
+        Io(path: PathBuf, err: io::Error) {
+            display("Failed to read VSM file {}: {}", path.display(), err)
+        }
+        NoLogSegment(index: PathBuf) {
+            display("No VSL segment listed in VSM index {}; is varnishd (6.0 or newer) running?", index.display())
+        }
+        BadSegment(path: PathBuf, msg: &'static str) {
+            display("Bad VSL segment in {}: {}", path.display(), msg)
+        }
+    }
+}
+
+impl From<VsmError> for io::Error {
+    fn from(err: VsmError) -> io::Error {
+        match err {
+            VsmError::Io(_, err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+        }
+    }
+}
+
+/// Working directory of varnishd instance of given name (as given with `-n`)
+pub fn vsm_workdir(name: &str) -> PathBuf {
+    let name = Path::new(name);
+    if name.is_absolute() {
+        name.to_path_buf()
+    } else {
+        Path::new(VARNISH_STATE_DIR).join(name)
+    }
+}
+
+// VSL segment entry of the VSM index: file and location of the segment within it
+#[derive(Debug, Clone, PartialEq)]
+struct LogSegment {
+    file: PathBuf,
+    offset: usize,
+    len: usize,
+}
+
+fn find_log_segment(workdir: &Path) -> Result<LogSegment, VsmError> {
+    let dir = workdir.join(VSM_CHILD_DIR);
+    let index = dir.join(VSM_INDEX);
+    let content = fs::read_to_string(&index).map_err(|err| VsmError::Io(index.clone(), err))?;
+
+    let mut segment = None;
+    for line in content.lines() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields.as_slice() {
+            ["+", file, offset, len, class, ..] if *class == VSL_CLASS => {
+                if let (Ok(offset), Ok(len)) = (offset.parse(), len.parse()) {
+                    segment = Some(LogSegment { file: dir.join(file), offset, len });
+                }
+            }
+            ["-", file, offset, ..] if segment.as_ref().is_some_and(|segment| {
+                segment.file == dir.join(file) && offset.parse() == Ok(segment.offset)
+            }) => segment = None,
+            _ => (),
+        }
+    }
+
+    segment.ok_or(VsmError::NoLogSegment(index))
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VsmReaderStats {
+    /// Number of records copied out of the log
+    pub records: u64,
+    /// Number of times the writer overwrote records before they were read
+    pub overruns: u64,
+    /// Number of times the reader wrapped around the end of the log
+    pub wraps: u64,
+}
+
+impl Display for VsmReaderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "records: {} overruns: {} wraps: {}", self.records, self.overruns, self.wraps)
+    }
+}
+
+pub struct VsmReader {
+    workdir: Option<PathBuf>,
+    segment: LogSegment,
+    map: Mmap,
+    // size of the ring buffer and its segments in words
+    log_words: usize,
+    segsize: usize,
+    // next record to read (word index) and number of the segment it is in
+    next: usize,
+    seq: u32,
+    dump: bool,
+    record: Vec<u8>,
+    pos: usize,
+    last_validity_check: Instant,
+    stop: Option<Arc<AtomicBool>>,
+    stats: VsmReaderStats,
+}
+
+impl VsmReader {
+    /// Open VSL segment of varnishd with given working directory
+    pub fn open<P: AsRef<Path>>(workdir: P) -> Result<VsmReader, VsmError> {
+        let workdir = workdir.as_ref();
+        let mut reader = VsmReader::open_segment(find_log_segment(workdir)?)?;
+        reader.workdir = Some(workdir.to_path_buf());
+        Ok(reader)
+    }
+
+    fn open_segment(segment: LogSegment) -> Result<VsmReader, VsmError> {
+        let file = File::open(&segment.file).map_err(|err| VsmError::Io(segment.file.clone(), err))?;
+        // Safety: varnishd only writes to the segment; the log and head fields it keeps updating are
+        // read with read_volatile (head marker is written before the segment is listed in the index)
+        let map = unsafe { Mmap::map(&file) }.map_err(|err| VsmError::Io(segment.file.clone(), err))?;
+
+        if segment.offset % 8 != 0 || segment.len < VSL_HEAD_LOG || segment.offset + segment.len > map.len() {
+            return Err(VsmError::BadSegment(segment.file, "segment location is not valid"))
+        }
+        if &map[segment.offset..segment.offset + VSL_HEAD_MARKER.len()] != VSL_HEAD_MARKER {
+            return Err(VsmError::BadSegment(segment.file, "VSL head marker not found"))
+        }
+
+        let log_words = (segment.len - VSL_HEAD_LOG) / 4;
+        let mut reader = VsmReader {
+            workdir: None,
+            segment,
+            map,
+            log_words,
+            segsize: 0,
+            next: 0,
+            seq: 0,
+            dump: false,
+            record: Vec::new(),
+            pos: 0,
+            last_validity_check: Instant::now(),
+            stop: None,
+            stats: VsmReaderStats::default(),
+        };
+
+        let segsize = reader.head_i64(VSL_HEAD_SEGSIZE);
+        if segsize <= 0 || segsize as usize * VSL_SEGMENTS as usize > log_words {
+            return Err(VsmError::BadSegment(reader.segment.file, "segment size is not valid"))
+        }
+        reader.segsize = segsize as usize;
+        reader.seek_newest();
+        Ok(reader)
+    }
+
+    /// Read records already in the log starting from the oldest one and stop at the end of the
+    /// log instead of waiting for new records (like `varnishlog -d`)
+    pub fn enable_dump(&mut self) {
+        self.dump = true;
+        self.seek_oldest();
+    }
+
+    /// End the stream before the next record once the flag is set (e.g. by signal handler); record
+    /// being read is finished first
+    pub fn set_stop_flag(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop.as_ref().is_some_and(|stop| stop.load(Ordering::Relaxed))
+    }
+
+    pub fn stats(&self) -> &VsmReaderStats {
+        &self.stats
+    }
+
+    fn head_i64(&self, offset: usize) -> i64 {
+        // Safety: offset is within VSL_head which is 8 byte aligned
+        unsafe { ptr::read_volatile(self.map.as_ptr().add(self.segment.offset + offset) as *const i64) }
+    }
+
+    fn segment_n(&self) -> u32 {
+        // Safety: as above
+        unsafe { ptr::read_volatile(self.map.as_ptr().add(self.segment.offset + VSL_HEAD_SEGMENT_N) as *const u32) }
+    }
+
+    fn segment_offset(&self, seq: u32) -> i64 {
+        self.head_i64(VSL_HEAD_OFFSET + 8 * (seq % VSL_SEGMENTS) as usize)
+    }
+
+    fn word(&self, index: usize) -> u32 {
+        assert!(index < self.log_words);
+        // Safety: index is within the log which is 4 byte aligned
+        unsafe { ptr::read_volatile((self.map.as_ptr().add(self.segment.offset + VSL_HEAD_LOG) as *const u32).add(index)) }
+    }
+
+    fn seek(&mut self, seq: u32) {
+        fence(Ordering::Acquire);
+        self.seq = seq;
+        self.next = match self.segment_offset(seq) {
+            offset if offset >= 0 && (offset as usize) < self.log_words => offset as usize,
+            _ => 0,
+        };
+    }
+
+    // Start of the segment writer is currently writing to
+    fn seek_newest(&mut self) {
+        self.seek(self.segment_n());
+    }
+
+    // Oldest segment that is safe to read
+    fn seek_oldest(&mut self) {
+        let segment_n = self.segment_n();
+        let mut seq = segment_n.saturating_sub(VSL_SEGMENTS - 3);
+        while seq != segment_n && self.segment_offset(seq) < 0 {
+            seq += 1;
+        }
+        self.seek(seq);
+    }
+
+    // Writer is about to overwrite the segment we are reading
+    fn overrun(&self) -> bool {
+        fence(Ordering::Acquire);
+        self.segment_n().wrapping_sub(self.seq) >= VSL_SEGMENTS - 2
+    }
+
+    fn skip_overrun(&mut self) {
+        warn!("VSL log overrun: records were overwritten before they could be read; continuing with newest records");
+        self.stats.overruns += 1;
+        self.seek_newest();
+    }
+
+    // Copies next record to the record buffer; returns false if there are no new records
+    fn next_record(&mut self) -> bool {
+        loop {
+            if self.overrun() {
+                self.skip_overrun();
+            }
+
+            let header = self.word(self.next);
+            match header {
+                VSL_ENDMARKER => return false,
+                VSL_WRAPMARKER => {
+                    self.next = 0;
+                    while self.seq % VSL_SEGMENTS != 0 {
+                        self.seq = self.seq.wrapping_add(1);
+                    }
+                    self.stats.wraps += 1;
+                    continue
+                }
+                _ => (),
+            }
+
+            // header, data with NUL terminator and padding (batch record has no data)
+            let words = 2 + ((header & VSL_LENMASK) as usize).div_ceil(4);
+            if self.next + words >= self.log_words {
+                // only possible if the record was overwritten while we were reading it
+                self.skip_overrun();
+                continue
+            }
+
+            fence(Ordering::Acquire);
+            self.record.clear();
+            for index in self.next..self.next + words {
+                let word = self.word(index);
+                self.record.extend_from_slice(&word.to_ne_bytes());
+            }
+
+            if self.overrun() {
+                self.skip_overrun();
+                continue
+            }
+
+            self.next += words;
+            while self.next / self.segsize > (self.seq % VSL_SEGMENTS) as usize {
+                self.seq = self.seq.wrapping_add(1);
+            }
+            self.pos = 0;
+            self.stats.records += 1;
+            return true
+        }
+    }
+
+    // Reopens the segment if varnishd replaced it (e.g. child process was restarted)
+    fn check_validity(&mut self) -> Result<(), VsmError> {
+        if self.last_validity_check.elapsed() < VALIDITY_CHECK_INTERVAL {
+            return Ok(())
+        }
+        self.last_validity_check = Instant::now();
+
+        let workdir = match self.workdir {
+            Some(ref workdir) => workdir.clone(),
+            None => return Ok(()),
+        };
+
+        let segment = find_log_segment(&workdir)?;
+        if segment != self.segment {
+            info!("VSL segment was replaced by {}; reopening", segment.file.display());
+            let stats = self.stats.clone();
+            let stop = self.stop.take();
+            let dump = self.dump;
+            *self = VsmReader::open_segment(segment)?;
+            self.workdir = Some(workdir);
+            self.stats = stats;
+            self.stop = stop;
+            self.dump = dump;
+        }
+        Ok(())
+    }
+}
+
+/// Yields binary VSL stream of records as they are written to the log; blocks until new records
+/// are available unless in dump mode
+impl Read for VsmReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.record.len() {
+            if self.is_stopped() {
+                return Ok(0)
+            }
+            if self.next_record() {
+                break
+            }
+            if self.dump {
+                return Ok(0)
+            }
+            self.check_validity()?;
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let mut bytes = 0;
+        loop {
+            let chunk = std::cmp::min(buf.len() - bytes, self.record.len() - self.pos);
+            buf[bytes..bytes + chunk].copy_from_slice(&self.record[self.pos..self.pos + chunk]);
+            self.pos += chunk;
+            bytes += chunk;
+
+            // copy more records if they are already available
+            if bytes == buf.len() || self.is_stopped() || !self.next_record() {
+                return Ok(bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use assert_matches::assert_matches;
+    use std::fs::OpenOptions;
+    use crate::vsl::reader::VslReader;
+    use crate::vsl::record::VslRecordTag;
+
+    // Writes to synthetic VSL segment the way varnishd does (bin/varnishd/cache/cache_shmlog.c)
+    struct SegmentWriter {
+        file: File,
+        segsize: usize,
+        ptr: usize,
+        segment_n: u32,
+        vxid: u32,
+    }
+
+    impl SegmentWriter {
+        fn create(workdir: &Path, segsize: usize) -> SegmentWriter {
+            let dir = workdir.join(VSM_CHILD_DIR);
+            fs::create_dir_all(&dir).unwrap();
+
+            // VSC segment before the log one to check that offsets are used
+            let log_offset = 4096;
+            let log_len = VSL_HEAD_LOG + segsize * VSL_SEGMENTS as usize * 4;
+            fs::write(dir.join(VSM_INDEX), format!(concat!(
+                "# 1234 1690000000\n",
+                "+ _.VSC_cluster.1 0 4096 VSC_cluster \n",
+                "+ _.Log.1 0 {} Log \n",
+                "- _.VSC_cluster.1 0 4096 VSC_cluster \n",
+                "+ _.Log.2 {} {} Log \n",
+            ), log_len, log_offset, log_len)).unwrap();
+
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(dir.join("_.Log.2")).unwrap();
+            file.set_len((log_offset + log_len) as u64).unwrap();
+
+            let writer = SegmentWriter { file, segsize, ptr: 0, segment_n: 0, vxid: 0 };
+            writer.write_head(VSL_HEAD_SEGSIZE, &(segsize as i64).to_ne_bytes());
+            writer.write_head(VSL_HEAD_SEGMENT_N, &0u32.to_ne_bytes());
+            for seq in 0..VSL_SEGMENTS {
+                writer.write_offset(seq, if seq == 0 { 0 } else { -1 });
+            }
+            writer.write_word(0, VSL_ENDMARKER);
+            writer.write_head(0, VSL_HEAD_MARKER);
+            writer
+        }
+
+        fn write_head(&self, offset: usize, bytes: &[u8]) {
+            self.file.write_at(bytes, (4096 + offset) as u64).unwrap();
+        }
+
+        fn write_offset(&self, seq: u32, offset: i64) {
+            self.write_head(VSL_HEAD_OFFSET + 8 * (seq % VSL_SEGMENTS) as usize, &offset.to_ne_bytes());
+        }
+
+        fn write_word(&self, index: usize, word: u32) {
+            self.write_head(VSL_HEAD_LOG + index * 4, &word.to_ne_bytes());
+        }
+
+        fn log_words(&self) -> usize {
+            self.segsize * VSL_SEGMENTS as usize
+        }
+
+        fn wrap(&mut self) {
+            self.segment_n += VSL_SEGMENTS - self.segment_n % VSL_SEGMENTS;
+            self.write_offset(0, 0);
+            self.write_word(0, VSL_ENDMARKER);
+            if self.ptr != 0 {
+                self.write_word(self.ptr, VSL_WRAPMARKER);
+                self.ptr = 0;
+            }
+            self.write_head(VSL_HEAD_SEGMENT_N, &self.segment_n.to_ne_bytes());
+        }
+
+        // Writes SLT_Begin record with next vxid as client transaction
+        fn write_record(&mut self) -> u32 {
+            self.vxid += 1;
+            let data = format!("req {} rxreq\0", self.vxid);
+            let len = data.len() as u32;
+            let words = 2 + data.len().div_ceil(4);
+
+            if self.ptr + words >= self.log_words() {
+                self.wrap();
+            }
+
+            let p = self.ptr;
+            self.ptr += words;
+            self.write_word(self.ptr, VSL_ENDMARKER);
+            while self.ptr / self.segsize > (self.segment_n % VSL_SEGMENTS) as usize {
+                self.segment_n += 1;
+                self.write_offset(self.segment_n, self.ptr as i64);
+            }
+
+            let mut padded = data.into_bytes();
+            padded.resize((words - 2) * 4, 0);
+            self.write_head(VSL_HEAD_LOG + (p + 2) * 4, &padded);
+            self.write_word(p + 1, 0x4000_0000 | self.vxid);
+            self.write_word(p, (76 << VSL_LENOFFSET) | len);
+            self.write_head(VSL_HEAD_SEGMENT_N, &self.segment_n.to_ne_bytes());
+            self.vxid
+        }
+    }
+
+    fn workdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("varnishslog-vsm-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record_vxid(record: &[u8]) -> u32 {
+        u32::from_ne_bytes([record[4], record[5], record[6], record[7]]) & 0x3fff_ffff
+    }
+
+    fn read_vxids(reader: VsmReader) -> Vec<u32> {
+        let mut reader = VslReader::new(reader);
+        let mut vxids = Vec::new();
+        while let Some(record) = reader.read_record().unwrap() {
+            assert_eq!(record.tag, VslRecordTag::SLT_Begin);
+            assert_eq!(record.data, format!("req {} rxreq", record.ident).as_bytes());
+            vxids.push(record.ident);
+        }
+        vxids
+    }
+
+    #[test]
+    fn find_segment() {
+        let workdir = workdir("index");
+        SegmentWriter::create(&workdir, 64);
+
+        assert_eq!(find_log_segment(&workdir).unwrap(), LogSegment {
+            file: workdir.join("_.vsm_child/_.Log.2"),
+            offset: 4096,
+            len: VSL_HEAD_LOG + 64 * 8 * 4,
+        });
+        assert_matches!(find_log_segment(&workdir.join("foo")), Err(VsmError::Io(..)));
+        assert_eq!(vsm_workdir("varnishd"), Path::new("/var/lib/varnish/varnishd"));
+        assert_eq!(vsm_workdir("/tmp/varnishd"), Path::new("/tmp/varnishd"));
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn dump_records() {
+        let workdir = workdir("dump");
+        let mut writer = SegmentWriter::create(&workdir, 64);
+        for _ in 0..10 {
+            writer.write_record();
+        }
+
+        let mut reader = VsmReader::open(&workdir).unwrap();
+        reader.enable_dump();
+        assert_eq!(read_vxids(reader), (1..=10).collect::<Vec<_>>());
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn dump_wrapped_log() {
+        let workdir = workdir("wrap");
+        let mut writer = SegmentWriter::create(&workdir, 16);
+        // 6 words per record; log is 128 words so it wraps few times
+        for _ in 0..100 {
+            writer.write_record();
+        }
+
+        let mut reader = VsmReader::open(&workdir).unwrap();
+        reader.enable_dump();
+        let vxids = read_vxids(reader);
+
+        // oldest safe segments up to the last record with no gaps
+        assert!(vxids.len() > 5);
+        assert_eq!(*vxids.last().unwrap(), 100);
+        assert!(vxids.windows(2).all(|pair| pair[1] == pair[0] + 1));
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn follow_records_and_overrun() {
+        let workdir = workdir("follow");
+        let mut writer = SegmentWriter::create(&workdir, 16);
+        writer.write_record();
+
+        // starts at the segment writer is at
+        let mut reader = VsmReader::open(&workdir).unwrap();
+        assert!(reader.next_record());
+        assert!(!reader.next_record());
+
+        writer.write_record();
+        writer.write_record();
+        assert!(reader.next_record());
+        assert!(reader.next_record());
+        assert!(!reader.next_record());
+        assert_eq!(reader.stats().overruns, 0);
+
+        // writer laps the reader
+        for _ in 0..100 {
+            writer.write_record();
+        }
+        assert!(reader.next_record());
+        assert_eq!(reader.stats().overruns, 1);
+
+        let mut vxids = vec![record_vxid(&reader.record)];
+        while reader.next_record() {
+            vxids.push(record_vxid(&reader.record));
+        }
+        assert_eq!(*vxids.last().unwrap(), 103);
+        assert!(vxids.windows(2).all(|pair| pair[1] == pair[0] + 1));
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn stop_with_records_available() {
+        let workdir = workdir("stop");
+        let mut writer = SegmentWriter::create(&workdir, 64);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut reader = VsmReader::open(&workdir).unwrap();
+        reader.set_stop_flag(stop.clone());
+        for _ in 0..10 {
+            writer.write_record();
+        }
+
+        // record being read is finished but no more are taken
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 8);
+        stop.store(true, Ordering::Relaxed);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"req 1 rxreq\0");
+        assert_eq!(record_vxid(&buf), 1);
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn stop_after_segment_replaced() {
+        let workdir = workdir("restart");
+        SegmentWriter::create(&workdir, 16);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut reader = VsmReader::open(&workdir).unwrap();
+        reader.set_stop_flag(stop.clone());
+
+        // varnishd child restart allocates new log segment
+        let dir = workdir.join(VSM_CHILD_DIR);
+        fs::rename(dir.join("_.Log.2"), dir.join("_.Log.3")).unwrap();
+        let index = fs::read_to_string(dir.join(VSM_INDEX)).unwrap();
+        fs::write(dir.join(VSM_INDEX), index.replace("_.Log.2", "_.Log.3")).unwrap();
+
+        reader.last_validity_check -= VALIDITY_CHECK_INTERVAL;
+        reader.check_validity().unwrap();
+        assert_eq!(reader.segment.file, dir.join("_.Log.3"));
+
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(reader.read(&mut [0; 64]).unwrap(), 0);
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+// Writes VSL records in binary log format as written by `varnishlog -w`.
+//
+// This is inverse of the vsl_record_v4 parser so that files produced can be read back
+// with `varnishlog -r` or this crate. Record padding is written as zeros so files read are
+// reproduced byte for byte only if Varnish left their padding clean.
+use std::io::{self, Write};
+use quick_error::quick_error;
+
+use super::record::{AsVslRecord, VslRecordTag};
+use super::record::parser::{VSL_LENOFFSET, VSL_LENMASK, VSL_IDENTOFFSET, VSL_IDENTMASK};
+
+pub const VSL_FILE_HEADER: &[u8] = b"VSL\0";
+
+const PADDING: [u8; 4] = [0; 4];
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VslWriterError {
+        Io(err: io::Error) {
+            display("Failed to write VSL stream: {}", err)
+            from()
+        }
+        RecordTooLong(tag: VslRecordTag, len: usize) {
+            display("VSL record {:?} data is too long to be encoded: {} bytes", tag, len)
+        }
+    }
+}
+
+pub struct VslWriter<W: Write> {
+    output: W,
+}
+
+impl<W: Write> VslWriter<W> {
+    /// Writes VSL file header to the output
+    pub fn new(mut output: W) -> Result<VslWriter<W>, VslWriterError> {
+        output.write_all(VSL_FILE_HEADER)?;
+        Ok(VslWriter::without_header(output))
+    }
+
+    /// For appending records to existing VSL stream
+    pub fn without_header(output: W) -> VslWriter<W> {
+        VslWriter {
+            output,
+        }
+    }
+
+    pub fn write_record<V: AsVslRecord + ?Sized>(&mut self, record: &V) -> Result<(), VslWriterError> {
+        let record = record.as_vsl_record();
+
+        // length includes NUL terminator
+        let len = record.data.len() + 1;
+        if len > VSL_LENMASK as usize {
+            return Err(VslWriterError::RecordTooLong(record.tag, record.data.len()))
+        }
+
+        // raw tag number keeps tag numbering of the Varnish release the record came from
+        let r1 = (u32::from(record.raw_tag) << VSL_LENOFFSET) | len as u32;
+        let r2 = (u32::from(record.marker.bits()) << VSL_IDENTOFFSET) | (record.ident & VSL_IDENTMASK);
+
+        self.output.write_all(&r1.to_le_bytes())?;
+        self.output.write_all(&r2.to_le_bytes())?;
+        self.output.write_all(record.data)?;
+        self.output.write_all(&[0])?;
+        self.output.write_all(&PADDING[0..(4 - len % 4) % 4])?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), VslWriterError> {
+        self.output.flush().map_err(From::from)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use crate::vsl::reader::VslReader;
+    use crate::vsl::record::{VslRecord, Marker};
+
+    #[test]
+    fn write_record() {
+        let mut writer = VslWriter::new(Vec::new()).unwrap();
+        writer.write_record(&VslRecord {
+            tag: VslRecordTag::SLT_Begin,
+            raw_tag: 76,
+            marker: Marker::VSL_CLIENTMARKER,
+            ident: 3,
+            data: b"sess 0 HTTP/1",
+        }).unwrap();
+
+        assert_eq!(writer.into_inner(), b"VSL\0\x0e\x00\x00\x4c\x03\x00\x00\x40sess 0 HTTP/1\x00\x00\x00".to_vec());
+    }
+
+    #[test]
+    fn write_record_too_long() {
+        let data = vec![b'x'; VSL_LENMASK as usize];
+        let mut writer = VslWriter::without_header(Vec::new());
+
+        match writer.write_record(&VslRecord {
+            tag: VslRecordTag::SLT_Debug,
+            raw_tag: 1,
+            marker: Marker::VSL_CLIENTMARKER,
+            ident: 3,
+            data: &data,
+        }) {
+            Err(VslWriterError::RecordTooLong(VslRecordTag::SLT_Debug, _)) => (),
+            result => panic!("expected RecordTooLong error; got: {:?}", result),
+        }
+    }
+
+    // Writes records of the log file back; returns written stream and the file with record
+    // padding cleared as Varnish does not clear it so it may contain left over bytes while writer
+    // pads with zeros
+    fn round_trip(path: &Path) -> (Vec<u8>, Vec<u8>) {
+        let mut cleared = fs::read(path).unwrap();
+        let mut reader = VslReader::new(File::open(path).unwrap());
+        let mut writer = VslWriter::new(Vec::new()).unwrap();
+
+        let mut offset = VSL_FILE_HEADER.len();
+        while let Some(record) = reader.read_record().unwrap() {
+            let len = record.data.len() + 1;
+            let padding_start = offset + 8 + len;
+            offset = padding_start + (4 - len % 4) % 4;
+            for byte in &mut cleared[padding_start..offset] {
+                *byte = 0;
+            }
+
+            writer.write_record(&record).unwrap();
+        }
+        (writer.into_inner(), cleared)
+    }
+
+    fn log_files() -> impl Iterator<Item = PathBuf> {
+        fs::read_dir("logs").unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map(|ext| ext == "vsl").unwrap_or(false))
+    }
+
+    #[test]
+    fn round_trip_log_files() {
+        // only files Varnish left clean padding in can be written back byte for byte
+        let mut exact = 0;
+        for path in log_files() {
+            let (written, cleared) = round_trip(&path);
+            if cleared == fs::read(&path).unwrap() {
+                assert!(written == cleared, "round trip of {:?} is not byte exact", path);
+                exact += 1;
+            }
+        }
+        assert!(exact > 0);
+    }
+
+    #[test]
+    fn round_trip_log_files_with_padding_cleared() {
+        for path in log_files() {
+            let (written, cleared) = round_trip(&path);
+            assert!(written == cleared, "round trip of {:?} differs in more than record padding", path);
+        }
+    }
+}